@@ -0,0 +1,235 @@
+//! A Fiat–Shamir transcript over a Poseidon2 duplex sponge.
+//!
+//! [`Poseidon2Transcript`] allocates nothing and only uses the width-24 permutation, so host and
+//! guest derive the same challenges from the same sequence of operations.
+
+use crate::poseidon2::{
+    byte_length_element, pack_bytes, permute, Poseidon2BabyBearLong, Poseidon2BabyBearValidWidth,
+    BYTES_PER_FIELD_ELEMENT,
+};
+use p3_baby_bear::BabyBear;
+use p3_field::FieldAlgebra;
+
+/// The width of the permutation of a [`Poseidon2Transcript`].
+const WIDTH: usize = 24;
+
+/// The number of capacity lanes of a [`Poseidon2Transcript`].
+pub const TRANSCRIPT_CAPACITY: usize = 8;
+
+/// The number of lanes absorbed into and squeezed from per permutation.
+const RATE: usize = WIDTH - TRANSCRIPT_CAPACITY;
+
+/// The value of the first capacity lane of a new transcript.
+///
+/// It follows the seed tags of `SpongeRng`, so a transcript never shares a state with a
+/// generator.
+pub const TRANSCRIPT_DOMAIN_TAG: u32 = 3;
+
+/// A Fiat–Shamir transcript: a duplex sponge over the width-24 Poseidon2 permutation.
+///
+/// Absorbed elements are added to the rate lanes, permuting whenever all of them are filled.
+/// The first challenge after an absorb closes the absorbed elements with `10*` padding, like
+/// [`Poseidon2Sponge`](crate::poseidon2::Poseidon2Sponge), and permutes; challenges are then
+/// read from the rate lanes, permuting whenever all of them are read. Hence:
+///
+/// - challenges depend on every absorbed element and on the order of absorbs and challenges;
+/// - absorbs and challenges are split arbitrarily: absorbing `[a]` then `[b]` is absorbing
+///   `[a, b]`, and drawing one challenge twice is drawing two challenges once;
+/// - a challenge with nothing absorbed before it, the first one included, is still preceded
+///   by the padding permutation.
+///
+/// Bytes are absorbed as their packing by
+/// [`bytes_to_field_elements`](crate::poseidon2::bytes_to_field_elements), which is not told
+/// apart from absorbing the packed elements: the protocol fixes what each absorb holds.
+///
+/// ```
+/// use openvm_sig_agg::{fiat_shamir::Poseidon2Transcript, poseidon2_config::poseidon2_instance};
+/// use p3_baby_bear::BabyBear;
+///
+/// let mut prover = Poseidon2Transcript::new(poseidon2_instance());
+/// let mut verifier = prover.clone();
+///
+/// prover.absorb_felts(&[BabyBear::new(1), BabyBear::new(2)]);
+/// prover.absorb_bytes(b"commitment");
+/// verifier.absorb_felts(&[BabyBear::new(1)]);
+/// verifier.absorb_felts(&[BabyBear::new(2)]);
+/// verifier.absorb_bytes(b"commitment");
+///
+/// assert_eq!(prover.challenge_felts::<4>(), verifier.challenge_felts::<4>());
+/// ```
+#[derive(Debug, Clone)]
+pub struct Poseidon2Transcript<I = Poseidon2BabyBearLong> {
+    poseidon_instance: I,
+    state: [BabyBear; WIDTH],
+    /// The number of rate lanes absorbed into since the last permutation.
+    absorbed: usize,
+    /// The next rate lane to read a challenge from, or `None` after an absorb.
+    squeezed: Option<usize>,
+}
+
+impl<I> Poseidon2Transcript<I>
+where
+    I: Poseidon2BabyBearValidWidth<WIDTH>,
+{
+    /// Creates an empty transcript.
+    pub fn new(poseidon_instance: I) -> Self {
+        let mut state = [BabyBear::ZERO; WIDTH];
+        state[RATE] = BabyBear::new(TRANSCRIPT_DOMAIN_TAG);
+        Self { poseidon_instance, state, absorbed: 0, squeezed: None }
+    }
+
+    /// Absorbs field elements.
+    pub fn absorb_felts(&mut self, elements: &[BabyBear]) {
+        elements.iter().for_each(|&element| self.absorb(element));
+    }
+
+    /// Absorbs a byte string, packed like
+    /// [`bytes_to_field_elements`](crate::poseidon2::bytes_to_field_elements).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is not shorter than the field order.
+    pub fn absorb_bytes(&mut self, bytes: &[u8]) {
+        self.absorb(byte_length_element(bytes.len()));
+        for chunk in bytes.chunks(BYTES_PER_FIELD_ELEMENT) {
+            self.absorb(pack_bytes(chunk));
+        }
+    }
+
+    /// Draws `N` challenges.
+    pub fn challenge_felts<const N: usize>(&mut self) -> [BabyBear; N] {
+        core::array::from_fn(|_| self.challenge())
+    }
+
+    fn absorb(&mut self, element: BabyBear) {
+        // After challenges, the absorb starts over at the first rate lane
+        if self.squeezed.take().is_some() {
+            self.absorbed = 0;
+        }
+        self.state[self.absorbed] += element;
+        self.absorbed += 1;
+        if self.absorbed == RATE {
+            permute(&self.poseidon_instance, &mut self.state);
+            self.absorbed = 0;
+        }
+    }
+
+    fn challenge(&mut self) -> BabyBear {
+        let lane = match self.squeezed {
+            // The `1` closes the absorbed, possibly empty, chunk
+            None => {
+                self.state[self.absorbed] += BabyBear::ONE;
+                permute(&self.poseidon_instance, &mut self.state);
+                0
+            }
+            Some(RATE) => {
+                permute(&self.poseidon_instance, &mut self.state);
+                0
+            }
+            Some(lane) => lane,
+        };
+        self.squeezed = Some(lane + 1);
+        self.state[lane]
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{poseidon2::bytes_to_field_elements, poseidon2_config::poseidon2_instance};
+
+    fn transcript() -> Poseidon2Transcript {
+        Poseidon2Transcript::new(poseidon2_instance())
+    }
+
+    #[test]
+    fn test_scripted_transcript_follows_the_duplex() {
+        let mut transcript = transcript();
+        transcript.absorb_felts(&(1..=20).map(BabyBear::new).collect::<Vec<_>>());
+        let first = transcript.challenge_felts::<3>();
+        transcript.absorb_bytes(b"abcd");
+        let second = transcript.challenge_felts::<20>();
+        let third = transcript.challenge_felts::<2>();
+
+        // The same sequence on the permutation directly
+        let instance = poseidon2_instance();
+        let mut state = [BabyBear::ZERO; 24];
+        state[16] = BabyBear::new(TRANSCRIPT_DOMAIN_TAG);
+        state.iter_mut().zip(1..=16).for_each(|(lane, i)| *lane += BabyBear::new(i));
+        instance.permute_mut(&mut state);
+        state.iter_mut().zip(17..=20).for_each(|(lane, i)| *lane += BabyBear::new(i));
+        state[4] += BabyBear::ONE;
+        instance.permute_mut(&mut state);
+        assert_eq!(first, state[..3]);
+
+        // "abcd" packs to its length, "abc" and "d"
+        state[0] += BabyBear::new(4);
+        state[1] += BabyBear::new(0x0063_6261);
+        state[2] += BabyBear::new(0x64);
+        state[3] += BabyBear::ONE;
+        instance.permute_mut(&mut state);
+        assert_eq!(second[..16], state[..16]);
+        instance.permute_mut(&mut state);
+        assert_eq!(second[16..], state[..4]);
+        assert_eq!(third, state[4..6]);
+    }
+
+    #[test]
+    fn test_splitting_operations_keeps_challenges() {
+        let elements: Vec<BabyBear> = (0..40).map(BabyBear::new).collect();
+        let mut whole = transcript();
+        whole.absorb_felts(&elements);
+        let expected = whole.challenge_felts::<40>();
+
+        for split in [1, 3, 16, 17, 39] {
+            let mut parts = transcript();
+            elements.chunks(split).for_each(|chunk| parts.absorb_felts(chunk));
+            let mut challenges = Vec::new();
+            while challenges.len() < 40 {
+                challenges.extend(parts.challenge_felts::<3>());
+                challenges.push(parts.challenge_felts::<1>()[0]);
+            }
+            assert_eq!(challenges[..40], expected, "{split}");
+        }
+
+        // Bytes are absorbed as their packing
+        let mut bytes = transcript();
+        bytes.absorb_bytes(b"transcript");
+        let mut packed = transcript();
+        packed.absorb_felts(&bytes_to_field_elements(b"transcript"));
+        assert_eq!(bytes.challenge_felts::<8>(), packed.challenge_felts::<8>());
+    }
+
+    #[test]
+    fn test_reordering_operations_changes_challenges() {
+        let [a, b] = [BabyBear::new(1), BabyBear::new(2)];
+        let challenges = |script: &dyn Fn(&mut Poseidon2Transcript)| {
+            let mut transcript = transcript();
+            script(&mut transcript);
+            transcript.challenge_felts::<4>()
+        };
+
+        let reference = challenges(&|t| t.absorb_felts(&[a, b]));
+        assert_ne!(reference, challenges(&|t| t.absorb_felts(&[b, a])));
+        assert_ne!(reference, challenges(&|t| t.absorb_felts(&[a, b, BabyBear::ZERO])));
+        assert_ne!(reference, challenges(&|t| t.absorb_felts(&[])));
+        assert_ne!(
+            reference,
+            challenges(&|t| {
+                t.absorb_felts(&[a]);
+                let _ = t.challenge_felts::<1>();
+                t.absorb_felts(&[b]);
+            })
+        );
+        assert_ne!(
+            challenges(&|t| {
+                t.absorb_bytes(b"ab");
+                t.absorb_bytes(b"c");
+            }),
+            challenges(&|t| {
+                t.absorb_bytes(b"a");
+                t.absorb_bytes(b"bc");
+            })
+        );
+    }
+}
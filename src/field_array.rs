@@ -0,0 +1,99 @@
+//! Fixed-length arrays of field elements with a canonical borsh encoding.
+//!
+//! Hash outputs are `[BabyBear; N]`, which has no borsh encoding of its own. [`FieldArray`]
+//! wraps them and encodes every element, in index order, as the little-endian `u32` of its
+//! canonical value. Decoding rejects values that are not below the prime, so every field
+//! element has exactly one encoding.
+
+use borsh::{
+    io::{Error, ErrorKind, Read, Result, Write},
+    BorshDeserialize, BorshSerialize,
+};
+use p3_baby_bear::BabyBear;
+use p3_field::PrimeField32;
+
+/// `N` field elements with a canonical borsh encoding of `4 * N` bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FieldArray<const N: usize>(pub [BabyBear; N]);
+
+impl<const N: usize> From<[BabyBear; N]> for FieldArray<N> {
+    fn from(elements: [BabyBear; N]) -> Self {
+        Self(elements)
+    }
+}
+
+impl<const N: usize> From<FieldArray<N>> for [BabyBear; N] {
+    fn from(array: FieldArray<N>) -> Self {
+        array.0
+    }
+}
+
+impl<const N: usize> BorshSerialize for FieldArray<N> {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<()> {
+        self.0.iter().try_for_each(|element| element.as_canonical_u32().serialize(writer))
+    }
+}
+
+impl<const N: usize> BorshDeserialize for FieldArray<N> {
+    fn deserialize_reader<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut elements = [BabyBear::new(0); N];
+        for element in &mut elements {
+            let value = u32::deserialize_reader(reader)?;
+            if value >= BabyBear::ORDER_U32 {
+                return Err(Error::new(ErrorKind::InvalidData, "non-canonical field element"));
+            }
+            *element = BabyBear::new(value);
+        }
+        Ok(Self(elements))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tweak::{ChainTweak, TweakEncoding};
+    use p3_field::FieldAlgebra;
+
+    #[test]
+    fn test_borsh_roundtrip() {
+        let array =
+            FieldArray([BabyBear::ZERO, BabyBear::ONE, BabyBear::NEG_ONE, BabyBear::new(7)]);
+        let bytes = borsh::to_vec(&array).unwrap();
+        assert_eq!(bytes.len(), 16);
+        assert_eq!(borsh::from_slice::<FieldArray<4>>(&bytes).unwrap(), array);
+    }
+
+    #[test]
+    fn test_elements_are_in_index_order() {
+        // The limbs of a tweak keep their order, least significant first, and every element is
+        // its canonical little-endian value
+        let limbs = ChainTweak::new_unchecked(1, 2, 3).to_field_elements();
+        let bytes = borsh::to_vec(&FieldArray(limbs)).unwrap();
+
+        assert_eq!(bytes[..4], 301990110u32.to_le_bytes());
+        for (chunk, limb) in bytes.chunks_exact(4).zip(limbs) {
+            assert_eq!(chunk, limb.as_canonical_u32().to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn test_non_canonical_elements_are_rejected() {
+        let mut bytes = borsh::to_vec(&FieldArray([BabyBear::ONE; 2])).unwrap();
+        bytes[4..].copy_from_slice(&BabyBear::ORDER_U32.to_le_bytes());
+
+        let err = borsh::from_slice::<FieldArray<2>>(&bytes).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        bytes[4..].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(borsh::from_slice::<FieldArray<2>>(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_wrong_lengths_are_rejected() {
+        let bytes = borsh::to_vec(&FieldArray([BabyBear::ONE; 3])).unwrap();
+
+        assert!(borsh::from_slice::<FieldArray<3>>(&bytes[..11]).is_err());
+        assert!(borsh::from_slice::<FieldArray<2>>(&bytes).is_err());
+        assert!(borsh::from_slice::<FieldArray<4>>(&bytes).is_err());
+    }
+}
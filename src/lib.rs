@@ -1,9 +1,20 @@
-#![allow(incomplete_features)]
-#![feature(inherent_associated_types)]
-#![feature(generic_const_exprs)]
-#![feature(generic_arg_infer)]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+pub mod fiat_shamir;
+#[cfg(feature = "borsh")]
+pub mod field_array;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod poseidon2;
+#[cfg(feature = "std")]
 pub mod poseidon2_config;
+#[cfg(feature = "rng")]
+pub mod sponge_rng;
+#[cfg(feature = "transcript-debug")]
+pub mod transcript;
 pub mod tweak;
+#[cfg(feature = "std")]
 pub mod tweak_hash;
@@ -0,0 +1,102 @@
+//! Counters of the Poseidon2 permutations performed by the current thread.
+//!
+//! Every permutation of the hashing functions of this crate is counted by width, so the cost of
+//! a key generation, signature or verification can be read off with [`snapshot`] after
+//! [`reset`]. Without the `metrics` feature nothing is counted and this module does not exist.
+
+use std::cell::Cell;
+
+/// The number of permutations performed by the current thread, by width.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct PermCounts {
+    /// Width-16 permutations.
+    pub perm16: u64,
+    /// Width-24 permutations.
+    pub perm24: u64,
+}
+
+thread_local! {
+    static COUNTS: Cell<PermCounts> = const { Cell::new(PermCounts { perm16: 0, perm24: 0 }) };
+}
+
+/// Returns the permutations performed by the current thread since the last [`reset`].
+pub fn snapshot() -> PermCounts {
+    COUNTS.with(Cell::get)
+}
+
+/// Resets the counters of the current thread.
+pub fn reset() {
+    COUNTS.with(|counts| counts.set(PermCounts::default()));
+}
+
+/// Counts `count` permutations of width `WIDTH`.
+pub(crate) fn record<const WIDTH: usize>(count: u64) {
+    COUNTS.with(|counts| {
+        let mut updated = counts.get();
+        match WIDTH {
+            16 => updated.perm16 += count,
+            24 => updated.perm24 += count,
+            _ => {}
+        }
+        counts.set(updated);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        poseidon2::{poseidon2_compress, poseidon2_sponge},
+        poseidon2_config::{poseidon2_instance, poseidon2_instance_short},
+        tweak::{ChainTweak, PoseidonTweak},
+        tweak_hash::PoseidonTweakHash,
+    };
+    use p3_baby_bear::BabyBear;
+    use p3_field::FieldAlgebra;
+    use std::thread;
+
+    type DefaultHash = PoseidonTweakHash<3, 2, 2, 4, 4, 3, 8, 2>;
+
+    #[test]
+    fn test_tweak_hashes_count_one_permutation() {
+        let tweak = PoseidonTweak::Chain(ChainTweak::new_unchecked(1, 2, 3));
+
+        // A single block fits the width-16 permutation
+        reset();
+        DefaultHash::new([BabyBear::ONE; 4], tweak, vec![[BabyBear::ONE; 4]]).apply();
+        assert_eq!(snapshot(), PermCounts { perm16: 1, perm24: 0 });
+
+        // Two blocks need the width-24 permutation
+        reset();
+        DefaultHash::new([BabyBear::ONE; 4], tweak, vec![[BabyBear::ONE; 4]; 2]).apply();
+        assert_eq!(snapshot(), PermCounts { perm16: 0, perm24: 1 });
+    }
+
+    #[test]
+    fn test_sponge_counts_every_permutation() {
+        let instance = poseidon2_instance();
+        let capacity = [BabyBear::ONE; 9];
+
+        // 20 elements and the padding fill two chunks of 15, then 20 outputs need two blocks
+        reset();
+        let _: [BabyBear; 20] = poseidon2_sponge(&instance, &capacity, &[BabyBear::ONE; 20]);
+        assert_eq!(snapshot(), PermCounts { perm16: 0, perm24: 3 });
+    }
+
+    #[test]
+    fn test_counters_are_per_thread() {
+        reset();
+        let _: [BabyBear; 8] = poseidon2_compress(&poseidon2_instance_short(), &[BabyBear::ONE; 8]);
+
+        let other = thread::spawn(|| {
+            let _: [BabyBear; 8] =
+                poseidon2_compress(&poseidon2_instance_short(), &[BabyBear::ONE; 8]);
+            snapshot()
+        });
+        assert_eq!(other.join().unwrap(), PermCounts { perm16: 1, perm24: 0 });
+        assert_eq!(snapshot(), PermCounts { perm16: 1, perm24: 0 });
+
+        reset();
+        assert_eq!(snapshot(), PermCounts::default());
+    }
+}
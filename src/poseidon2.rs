@@ -1,29 +1,194 @@
-use num_bigint::BigUint;
-use p3_baby_bear::{BabyBear, BabyBearParameters, Poseidon2BabyBear};
-use p3_field::FieldAlgebra;
-use p3_monty_31::MontyParameters;
+use crate::tweak::{limbs_from_packed, TweakError, TWEAK_LEN};
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::fmt;
+use p3_baby_bear::{BabyBear, Poseidon2BabyBear};
+use p3_field::{Field, FieldAlgebra, PackedValue, PrimeField32};
+#[cfg(feature = "koala-bear")]
+use p3_koala_bear::{KoalaBear, Poseidon2KoalaBear};
+#[cfg(feature = "alloc")]
+use p3_poseidon2::ExternalLayerConstants;
 use p3_symmetric::Permutation;
 
-#[derive(Debug)]
-pub struct Assert<const COND: bool>;
-
-pub trait IsTrue {}
-impl IsTrue for Assert<true> {}
-
 pub type Poseidon2BabyBearLong = Poseidon2BabyBear<24>;
 pub type Poseidon2BabyBearShort = Poseidon2BabyBear<16>;
 
+/// A prime field with Poseidon2 permutations of widths 16 and 24.
+///
+/// [`BabyBear`] is the field of the signature scheme. With the `koala-bear` feature, KoalaBear
+/// can be used with the same hashing functions, e.g. to benchmark the scheme over it.
+pub trait PoseidonField: PrimeField32 {
+    /// The width-16 Poseidon2 permutation.
+    type Poseidon2Short: Poseidon2ValidWidth<16, Field = Self>;
+    /// The width-24 Poseidon2 permutation.
+    type Poseidon2Long: Poseidon2ValidWidth<24, Field = Self>;
+
+    /// Builds the width-16 permutation from its round constants.
+    #[cfg(feature = "alloc")]
+    fn new_poseidon2_short(
+        external_constants: ExternalLayerConstants<Self, 16>,
+        internal_constants: Vec<Self>,
+    ) -> Self::Poseidon2Short;
+
+    /// Builds the width-24 permutation from its round constants.
+    #[cfg(feature = "alloc")]
+    fn new_poseidon2_long(
+        external_constants: ExternalLayerConstants<Self, 24>,
+        internal_constants: Vec<Self>,
+    ) -> Self::Poseidon2Long;
+}
+
 /// Trait to constrain valid WIDTH values
-pub trait Poseidon2BabyBearValidWidth {
-    const WIDTH: usize;
+///
+/// Only the concrete Poseidon2 instances of a [`PoseidonField`] implement this trait, so every
+/// function generic over it is tied to a supported `(instance, WIDTH)` pair without requiring
+/// const arithmetic on associated constants.
+pub trait Poseidon2ValidWidth<const WIDTH: usize>: Permutation<[Self::Field; WIDTH]> {
+    /// The field the permutation operates on.
+    type Field: PoseidonField;
+}
+
+/// The valid Baby Bear instances, for the functions that only hash over [`BabyBear`].
+pub trait Poseidon2BabyBearValidWidth<const WIDTH: usize>:
+    Poseidon2ValidWidth<WIDTH, Field = BabyBear>
+{
+}
+
+impl<I: Poseidon2ValidWidth<WIDTH, Field = BabyBear>, const WIDTH: usize>
+    Poseidon2BabyBearValidWidth<WIDTH> for I
+{
+}
+
+impl PoseidonField for BabyBear {
+    type Poseidon2Short = Poseidon2BabyBearShort;
+    type Poseidon2Long = Poseidon2BabyBearLong;
+
+    #[cfg(feature = "alloc")]
+    fn new_poseidon2_short(
+        external_constants: ExternalLayerConstants<Self, 16>,
+        internal_constants: Vec<Self>,
+    ) -> Self::Poseidon2Short {
+        Poseidon2BabyBear::new(external_constants, internal_constants)
+    }
+
+    #[cfg(feature = "alloc")]
+    fn new_poseidon2_long(
+        external_constants: ExternalLayerConstants<Self, 24>,
+        internal_constants: Vec<Self>,
+    ) -> Self::Poseidon2Long {
+        Poseidon2BabyBear::new(external_constants, internal_constants)
+    }
+}
+
+impl Poseidon2ValidWidth<16> for Poseidon2BabyBearShort {
+    type Field = BabyBear;
+}
+
+impl Poseidon2ValidWidth<24> for Poseidon2BabyBearLong {
+    type Field = BabyBear;
+}
+
+#[cfg(feature = "koala-bear")]
+impl PoseidonField for KoalaBear {
+    type Poseidon2Short = Poseidon2KoalaBear<16>;
+    type Poseidon2Long = Poseidon2KoalaBear<24>;
+
+    #[cfg(feature = "alloc")]
+    fn new_poseidon2_short(
+        external_constants: ExternalLayerConstants<Self, 16>,
+        internal_constants: Vec<Self>,
+    ) -> Self::Poseidon2Short {
+        Poseidon2KoalaBear::new(external_constants, internal_constants)
+    }
+
+    #[cfg(feature = "alloc")]
+    fn new_poseidon2_long(
+        external_constants: ExternalLayerConstants<Self, 24>,
+        internal_constants: Vec<Self>,
+    ) -> Self::Poseidon2Long {
+        Poseidon2KoalaBear::new(external_constants, internal_constants)
+    }
+}
+
+#[cfg(feature = "koala-bear")]
+impl Poseidon2ValidWidth<16> for Poseidon2KoalaBear<16> {
+    type Field = KoalaBear;
+}
+
+#[cfg(feature = "koala-bear")]
+impl Poseidon2ValidWidth<24> for Poseidon2KoalaBear<24> {
+    type Field = KoalaBear;
 }
 
-impl Poseidon2BabyBearValidWidth for Poseidon2BabyBearShort {
-    const WIDTH: usize = 16;
+/// Errors returned by the Poseidon2 hashing functions on inputs of invalid lengths.
+///
+/// Input lengths can come from untrusted data, e.g. a malformed signature, so verification code
+/// uses the `try_` functions and rejects such inputs instead of panicking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Poseidon2Error {
+    /// The input of `len` elements does not fit in the permutation state of `width` elements.
+    InputTooLong { len: usize, width: usize },
+    /// The `out_len` output elements are more than the `in_len` input elements.
+    OutputExceedsInput { out_len: usize, in_len: usize },
+    /// The domain parameter at `index` does not fit in 32 bits.
+    DomainParameterTooLarge { index: usize, value: usize },
+    /// A batch of `leaves` inputs came with `tweaks` tweaks and `outputs` outputs.
+    BatchLengthMismatch { leaves: usize, tweaks: usize, outputs: usize },
+    /// A leaf of a batch has `found` elements where the first leaf has `expected`.
+    LeafLengthMismatch { expected: usize, found: usize },
+    /// A flat slice of `len` elements is not a whole number of states of width `width`.
+    RaggedStates { len: usize, width: usize },
+    /// The tweak of the hash does not fit in its field-element encoding.
+    Tweak(TweakError),
 }
 
-impl Poseidon2BabyBearValidWidth for Poseidon2BabyBearLong {
-    const WIDTH: usize = 24;
+impl fmt::Display for Poseidon2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InputTooLong { len, width } => {
+                write!(f, "input of {len} elements does not fit in a state of width {width}")
+            }
+            Self::OutputExceedsInput { out_len, in_len } => {
+                write!(f, "cannot compress {in_len} input elements into {out_len} output elements")
+            }
+            Self::DomainParameterTooLarge { index, value } => {
+                write!(f, "domain parameter {index} is {value}, which does not fit in 32 bits")
+            }
+            Self::BatchLengthMismatch { leaves, tweaks, outputs } => {
+                write!(f, "batch of {leaves} leaves has {tweaks} tweaks and {outputs} outputs")
+            }
+            Self::LeafLengthMismatch { expected, found } => {
+                write!(f, "leaf of {found} elements in a batch of leaves of {expected} elements")
+            }
+            Self::RaggedStates { len, width } => {
+                write!(f, "{len} elements do not split into states of width {width}")
+            }
+            Self::Tweak(err) => write!(f, "cannot encode the tweak: {err}"),
+        }
+    }
+}
+
+impl core::error::Error for Poseidon2Error {}
+
+impl From<TweakError> for Poseidon2Error {
+    fn from(err: TweakError) -> Self {
+        Self::Tweak(err)
+    }
+}
+
+/// Applies the permutation `instance` to `state`.
+///
+/// Every permutation of this crate goes through this function, which counts it with the
+/// `metrics` feature and is a plain `permute_mut` call without it.
+#[inline(always)]
+pub(crate) fn permute<const WIDTH: usize, T, P>(instance: &P, state: &mut [T; WIDTH])
+where
+    T: Clone,
+    P: Permutation<[T; WIDTH]>,
+{
+    #[cfg(feature = "metrics")]
+    crate::metrics::record::<WIDTH>(1);
+    instance.permute_mut(state);
 }
 
 /// Applies the Poseidon2 permutation to a padded input.
@@ -35,19 +200,41 @@ impl Poseidon2BabyBearValidWidth for Poseidon2BabyBearLong {
 /// # Paper Reference:
 /// This function computes `PoseidonPerm(x)`.
 /// It ensures that `x` is first expanded to a valid `WIDTH` before applying Poseidon2.
-pub fn poseidon2_padded_permute<I>(instance: &I, x: &[BabyBear]) -> [BabyBear; I::WIDTH]
+///
+/// # Panics
+///
+/// Panics if `x` has more than `WIDTH` elements, see [`try_poseidon2_padded_permute`].
+pub fn poseidon2_padded_permute<const WIDTH: usize, I>(
+    instance: &I,
+    x: &[I::Field],
+) -> [I::Field; WIDTH]
 where
-    I: Poseidon2BabyBearValidWidth + Permutation<[BabyBear; I::WIDTH]>,
+    I: Poseidon2ValidWidth<WIDTH>,
 {
-    assert!(x.len() <= I::WIDTH, "Input length must be less than WIDTH");
+    try_poseidon2_padded_permute(instance, x).unwrap_or_else(|err| panic!("{err}"))
+}
+
+/// Applies the Poseidon2 permutation to a padded input, like [`poseidon2_padded_permute`].
+///
+/// Returns [`Poseidon2Error::InputTooLong`] if `x` has more than `WIDTH` elements.
+pub fn try_poseidon2_padded_permute<const WIDTH: usize, I>(
+    instance: &I,
+    x: &[I::Field],
+) -> Result<[I::Field; WIDTH], Poseidon2Error>
+where
+    I: Poseidon2ValidWidth<WIDTH>,
+{
+    if x.len() > WIDTH {
+        return Err(Poseidon2Error::InputTooLong { len: x.len(), width: WIDTH });
+    }
 
     // Pad input with zeroes if necessary
-    let mut padded_x = [BabyBear::ZERO; I::WIDTH];
+    let mut padded_x = [I::Field::ZERO; WIDTH];
     padded_x[..x.len()].copy_from_slice(x);
 
     // Apply Poseidon permutation
-    instance.permute_mut(&mut padded_x);
-    padded_x
+    permute(instance, &mut padded_x);
+    Ok(padded_x)
 }
 
 /// Implements the Poseidon2 **Compression Mode** hashing function.
@@ -59,104 +246,1931 @@ where
 /// # Paper Reference:
 /// Compression mode is the **more efficient** option, as it directly maps `t` inputs to `u` outputs
 /// without requiring iterative absorption, unlike sponge mode.
-pub fn poseidon2_compress<const OUT_LEN: usize, I>(
+///
+/// # Panics
+///
+/// Panics if `x` has more than `WIDTH` or fewer than `OUT_LEN` elements, see
+/// [`try_poseidon2_compress`]. An `OUT_LEN` above `WIDTH` is rejected at compile time:
+///
+/// ```compile_fail
+/// # use openvm_sig_agg::{poseidon2::poseidon2_compress, poseidon2_config::poseidon2_instance};
+/// # use p3_baby_bear::BabyBear;
+/// # use p3_field::FieldAlgebra;
+/// let out = poseidon2_compress::<25, 24, _>(&poseidon2_instance(), &[BabyBear::ONE; 24]);
+/// ```
+pub fn poseidon2_compress<const OUT_LEN: usize, const WIDTH: usize, I>(
+    poseidon_instance: &I,
+    x: &[I::Field],
+) -> [I::Field; OUT_LEN]
+where
+    I: Poseidon2ValidWidth<WIDTH>,
+{
+    try_poseidon2_compress(poseidon_instance, x).unwrap_or_else(|err| panic!("{err}"))
+}
+
+/// Implements the Poseidon2 **Compression Mode** hashing function, like [`poseidon2_compress`].
+///
+/// Returns [`Poseidon2Error::OutputExceedsInput`] if `x` has fewer than `OUT_LEN` elements and
+/// [`Poseidon2Error::InputTooLong`] if it has more than `WIDTH`. `OUT_LEN` must not exceed
+/// `WIDTH`, which is checked at compile time.
+pub fn try_poseidon2_compress<const OUT_LEN: usize, const WIDTH: usize, I>(
     poseidon_instance: &I,
-    x: &[BabyBear],
-) -> [BabyBear; OUT_LEN]
+    x: &[I::Field],
+) -> Result<[I::Field; OUT_LEN], Poseidon2Error>
 where
-    I: Poseidon2BabyBearValidWidth + Permutation<[BabyBear; I::WIDTH]>,
+    I: Poseidon2ValidWidth<WIDTH>,
 {
-    assert!(x.len() >= OUT_LEN, "Input length must be greater than or equal to OUT_LEN");
+    const { assert!(OUT_LEN <= WIDTH, "OUT_LEN must not exceed WIDTH") };
+
+    if x.len() < OUT_LEN {
+        return Err(Poseidon2Error::OutputExceedsInput { out_len: OUT_LEN, in_len: x.len() });
+    }
 
     // Apply Poseidon2 permutation
-    let permuted_x = poseidon2_padded_permute(poseidon_instance, x);
+    let permuted_x: [_; WIDTH] = try_poseidon2_padded_permute(poseidon_instance, x)?;
 
     // Compute element-wise addition and truncate to OUT_LEN
     //
     // This leads to PoseidonCompress(x) = Truncate(PoseidonPermute(x) + x)
-    core::array::from_fn(|i| permuted_x[i] + x[i])
+    Ok(core::array::from_fn(|i| permuted_x[i] + x[i]))
+}
+
+/// Implements the Poseidon2 **Compression Mode** with the input length bound into the state.
+///
+/// [`poseidon2_compress`] zero-pads its input, so `x` and `x` followed by zeros up to `WIDTH`
+/// elements compress to the same output. This variant reserves the last lane of the state for
+/// `x.len()`: the state is `x`, zeros, then the length, and the output is the truncation of the
+/// permuted state plus the state. Inputs of different lengths therefore never share a state.
+/// Use it wherever the input length is not fixed by the caller.
+///
+/// # Panics
+///
+/// Panics if `x` has `WIDTH` or more or fewer than `OUT_LEN` elements, see
+/// [`try_poseidon2_compress_len_bound`].
+pub fn poseidon2_compress_len_bound<const OUT_LEN: usize, const WIDTH: usize, I>(
+    poseidon_instance: &I,
+    x: &[I::Field],
+) -> [I::Field; OUT_LEN]
+where
+    I: Poseidon2ValidWidth<WIDTH>,
+{
+    try_poseidon2_compress_len_bound(poseidon_instance, x).unwrap_or_else(|err| panic!("{err}"))
+}
+
+/// Implements the Poseidon2 **Compression Mode** with the input length bound into the state,
+/// like [`poseidon2_compress_len_bound`].
+///
+/// Returns [`Poseidon2Error::OutputExceedsInput`] if `x` has fewer than `OUT_LEN` elements and
+/// [`Poseidon2Error::InputTooLong`] if it does not fit in the `WIDTH - 1` lanes before the
+/// length lane.
+pub fn try_poseidon2_compress_len_bound<const OUT_LEN: usize, const WIDTH: usize, I>(
+    poseidon_instance: &I,
+    x: &[I::Field],
+) -> Result<[I::Field; OUT_LEN], Poseidon2Error>
+where
+    I: Poseidon2ValidWidth<WIDTH>,
+{
+    if x.len() < OUT_LEN {
+        return Err(Poseidon2Error::OutputExceedsInput { out_len: OUT_LEN, in_len: x.len() });
+    }
+    if x.len() >= WIDTH {
+        return Err(Poseidon2Error::InputTooLong { len: x.len(), width: WIDTH - 1 });
+    }
+
+    Ok(compress_with_tag(poseidon_instance, x, x.len()))
+}
+
+/// Compresses `x`, shorter than `WIDTH`, with `tag` in the last lane of the state.
+///
+/// The output is the truncation of the permuted state plus the state, so `x` may be shorter
+/// than `OUT_LEN`.
+fn compress_with_tag<const OUT_LEN: usize, const WIDTH: usize, I>(
+    poseidon_instance: &I,
+    x: &[I::Field],
+    tag: usize,
+) -> [I::Field; OUT_LEN]
+where
+    I: Poseidon2ValidWidth<WIDTH>,
+{
+    const { assert!(OUT_LEN <= WIDTH, "OUT_LEN must not exceed WIDTH") };
+
+    let mut state = [I::Field::ZERO; WIDTH];
+    state[..x.len()].copy_from_slice(x);
+    state[WIDTH - 1] = I::Field::from_canonical_usize(tag);
+
+    let mut permuted = state;
+    permute(poseidon_instance, &mut permuted);
+    core::array::from_fn(|i| permuted[i] + state[i])
+}
+
+/// Writes `input` into the first lanes of a zero state and returns the state and input length.
+///
+/// Returns [`Poseidon2Error::InputTooLong`], with `width` set to `lanes` and `len` to
+/// `lanes + 1`, if `input` has more than `lanes` elements. `input` is read up to its first
+/// extra element only, so it may be infinite.
+fn fill_state<const WIDTH: usize, F: PrimeField32>(
+    input: impl IntoIterator<Item = F>,
+    lanes: usize,
+) -> Result<([F; WIDTH], usize), Poseidon2Error> {
+    let mut state = [F::ZERO; WIDTH];
+    let mut len = 0;
+    for element in input {
+        if len == lanes {
+            return Err(Poseidon2Error::InputTooLong { len: lanes + 1, width: lanes });
+        }
+        state[len] = element;
+        len += 1;
+    }
+    Ok((state, len))
+}
+
+/// Permutes `state` and adds the first `OUT_LEN` lanes of `state` back.
+fn feed_forward<const OUT_LEN: usize, const WIDTH: usize, I>(
+    poseidon_instance: &I,
+    mut state: [I::Field; WIDTH],
+) -> [I::Field; OUT_LEN]
+where
+    I: Poseidon2ValidWidth<WIDTH>,
+{
+    let input: [_; OUT_LEN] = core::array::from_fn(|i| state[i]);
+    permute(poseidon_instance, &mut state);
+    core::array::from_fn(|i| state[i] + input[i])
+}
+
+/// Implements the Poseidon2 **Compression Mode** over the elements of an iterator, like
+/// [`poseidon2_compress`].
+///
+/// The elements are written into the state directly, so callers compressing a concatenation
+/// chain its parts instead of collecting them into a `Vec` first.
+///
+/// # Panics
+///
+/// Panics if `input` has more than `WIDTH` or fewer than `OUT_LEN` elements, see
+/// [`try_poseidon2_compress_iter`].
+pub fn poseidon2_compress_iter<const OUT_LEN: usize, const WIDTH: usize, I>(
+    poseidon_instance: &I,
+    input: impl IntoIterator<Item = I::Field>,
+) -> [I::Field; OUT_LEN]
+where
+    I: Poseidon2ValidWidth<WIDTH>,
+{
+    try_poseidon2_compress_iter(poseidon_instance, input).unwrap_or_else(|err| panic!("{err}"))
+}
+
+/// Implements the Poseidon2 **Compression Mode** over the elements of an iterator, like
+/// [`try_poseidon2_compress`].
+///
+/// Returns the errors of [`try_poseidon2_compress`] for the same input, except that an input
+/// longer than `WIDTH` is read up to its first extra element only: the `len` of
+/// [`Poseidon2Error::InputTooLong`] is then `WIDTH + 1`, whatever the length of `input`.
+pub fn try_poseidon2_compress_iter<const OUT_LEN: usize, const WIDTH: usize, I>(
+    poseidon_instance: &I,
+    input: impl IntoIterator<Item = I::Field>,
+) -> Result<[I::Field; OUT_LEN], Poseidon2Error>
+where
+    I: Poseidon2ValidWidth<WIDTH>,
+{
+    const { assert!(OUT_LEN <= WIDTH, "OUT_LEN must not exceed WIDTH") };
+
+    let (state, len) = fill_state(input, WIDTH)?;
+    if len < OUT_LEN {
+        return Err(Poseidon2Error::OutputExceedsInput { out_len: OUT_LEN, in_len: len });
+    }
+    Ok(feed_forward(poseidon_instance, state))
+}
+
+/// Implements the Poseidon2 **Compression Mode** with the input length bound into the state
+/// over the elements of an iterator, like [`try_poseidon2_compress_len_bound`].
+///
+/// Returns the errors of [`try_poseidon2_compress_len_bound`] for the same input, except that
+/// an input longer than `WIDTH - 1` is read up to its first extra element only: the `len` of
+/// [`Poseidon2Error::InputTooLong`] is then `WIDTH`, whatever the length of `input`.
+pub fn try_poseidon2_compress_len_bound_iter<const OUT_LEN: usize, const WIDTH: usize, I>(
+    poseidon_instance: &I,
+    input: impl IntoIterator<Item = I::Field>,
+) -> Result<[I::Field; OUT_LEN], Poseidon2Error>
+where
+    I: Poseidon2ValidWidth<WIDTH>,
+{
+    const { assert!(OUT_LEN <= WIDTH, "OUT_LEN must not exceed WIDTH") };
+
+    let (mut state, len) = fill_state(input, WIDTH - 1)?;
+    if len < OUT_LEN {
+        return Err(Poseidon2Error::OutputExceedsInput { out_len: OUT_LEN, in_len: len });
+    }
+    state[WIDTH - 1] = I::Field::from_canonical_usize(len);
+    Ok(feed_forward(poseidon_instance, state))
+}
+
+/// The regimes of [`poseidon2_hash`], each with its own domain separation.
+///
+/// No regime is `0`, so the tag of a compression regime, `mode << 8 | len`, never equals the
+/// bare length lane of [`poseidon2_compress_len_bound`].
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HashMode {
+    /// Width-16 compression.
+    Short = 1,
+    /// Width-24 compression.
+    Long = 2,
+    /// Width-24 sponge.
+    Sponge = 3,
+}
+
+/// The capacity of the sponge regime of [`poseidon2_hash`].
+#[cfg(feature = "std")]
+const HASH_SPONGE_CAPACITY: usize = 9;
+
+/// Hashes an input of any length into `OUT_LEN` elements, picking the cheapest Poseidon2 mode.
+///
+/// - Inputs of at most 15 elements are compressed by the width-16 permutation, and inputs of at
+///   most 23 elements by the width-24 one. The last lane of the state holds the input length
+///   and the regime, as in [`poseidon2_compress_len_bound`], so trailing zeros are not lost.
+/// - Longer inputs go through [`poseidon2_sponge`] over the width-24 permutation, whose
+///   capacity is the [`cached_domain_separator`](crate::poseidon2_config::cached_domain_separator)
+///   of the regime and the output length.
+///
+/// The regime is part of every state, so inputs hashed in different regimes never share a
+/// permutation input. The compression regimes stop one element short of the width because of
+/// the length lane.
+#[cfg(feature = "std")]
+pub fn poseidon2_hash<const OUT_LEN: usize>(input: &[BabyBear]) -> [BabyBear; OUT_LEN] {
+    use crate::poseidon2_config::{
+        cached_domain_separator, cached_poseidon2_instance, cached_poseidon2_instance_short,
+    };
+
+    const { assert!(OUT_LEN <= 16, "OUT_LEN must fit in the width-16 permutation") };
+
+    let tag = |mode: HashMode| ((mode as usize) << 8) | input.len();
+    if input.len() < 16 {
+        compress_with_tag::<OUT_LEN, 16, _>(
+            cached_poseidon2_instance_short(),
+            input,
+            tag(HashMode::Short),
+        )
+    } else if input.len() < 24 {
+        compress_with_tag::<OUT_LEN, 24, _>(cached_poseidon2_instance(), input, tag(HashMode::Long))
+    } else {
+        let params = [HashMode::Sponge as usize, 24, HASH_SPONGE_CAPACITY, OUT_LEN];
+        let capacity =
+            cached_domain_separator::<HASH_SPONGE_CAPACITY, 24, Poseidon2BabyBearLong>(&params)
+                .expect("the sponge parameters fit in 32 bits");
+        poseidon2_sponge::<OUT_LEN, HASH_SPONGE_CAPACITY, 24, _>(
+            cached_poseidon2_instance(),
+            capacity,
+            input,
+        )
+    }
+}
+
+/// Applies the Poseidon2 permutation to every state of `states`, in place.
+///
+/// The result is identical to permuting each state on its own. Over Baby Bear,
+/// [`poseidon2_permute_packed`] permutes the states several at a time with SIMD instructions.
+pub fn poseidon2_permute_many<const WIDTH: usize, I>(
+    poseidon_instance: &I,
+    states: &mut [[I::Field; WIDTH]],
+) where
+    I: Poseidon2ValidWidth<WIDTH>,
+{
+    for state in states {
+        permute(poseidon_instance, state);
+    }
+}
+
+/// Applies the Poseidon2 permutation to every state of a flat slice, in place.
+///
+/// `states` holds the states one after the other, `WIDTH` elements each, like
+/// [`poseidon2_permute_many`] over the same memory. Returns [`Poseidon2Error::RaggedStates`],
+/// leaving `states` untouched, if its length is not a multiple of `WIDTH`.
+pub fn poseidon2_permute_many_flat<const WIDTH: usize, I>(
+    poseidon_instance: &I,
+    states: &mut [I::Field],
+) -> Result<(), Poseidon2Error>
+where
+    I: Poseidon2ValidWidth<WIDTH>,
+{
+    if states.len() % WIDTH != 0 {
+        return Err(Poseidon2Error::RaggedStates { len: states.len(), width: WIDTH });
+    }
+    for chunk in states.chunks_exact_mut(WIDTH) {
+        let state: &mut [_; WIDTH] = chunk.try_into().expect("chunks have WIDTH elements");
+        permute(poseidon_instance, state);
+    }
+    Ok(())
+}
+
+/// The packed Baby Bear field of the target: AVX2, AVX-512 or NEON lanes, or a single scalar
+/// lane on targets without SIMD support.
+pub type PackedBabyBear = <BabyBear as Field>::Packing;
+
+/// The number of states permuted at once by [`poseidon2_permute_packed`].
+pub const PACKED_LANES: usize = <PackedBabyBear as PackedValue>::WIDTH;
+
+/// Applies the Poseidon2 permutation to every state of `states`.
+///
+/// The states are permuted [`PACKED_LANES`] at a time: each batch is transposed into
+/// [`PackedBabyBear`] lanes, permuted once and transposed back. The states left over after the
+/// last full batch are permuted one by one. The result is identical to permuting each state.
+pub fn poseidon2_permute_packed<const WIDTH: usize, I>(
+    poseidon_instance: &I,
+    states: &mut [[BabyBear; WIDTH]],
+) where
+    I: Poseidon2BabyBearValidWidth<WIDTH> + Permutation<[PackedBabyBear; WIDTH]>,
+{
+    let mut batches = states.chunks_exact_mut(PACKED_LANES);
+    for batch in batches.by_ref() {
+        let mut packed: [PackedBabyBear; WIDTH] =
+            core::array::from_fn(|i| PackedBabyBear::from_fn(|lane| batch[lane][i]));
+        #[cfg(feature = "metrics")]
+        crate::metrics::record::<WIDTH>(PACKED_LANES as u64);
+        poseidon_instance.permute_mut(&mut packed);
+        for (i, lanes) in packed.iter().enumerate() {
+            for (state, &value) in batch.iter_mut().zip(lanes.as_slice()) {
+                state[i] = value;
+            }
+        }
+    }
+
+    for state in batches.into_remainder() {
+        permute(poseidon_instance, state);
+    }
+}
+
+/// Compresses at most [`PACKED_LANES`] inputs with a single packed permutation.
+fn compress_packed_batch<const OUT_LEN: usize, const WIDTH: usize, I>(
+    poseidon_instance: &I,
+    inputs: &[&[BabyBear]],
+    out: &mut [[BabyBear; OUT_LEN]],
+) where
+    I: Poseidon2BabyBearValidWidth<WIDTH> + Permutation<[PackedBabyBear; WIDTH]>,
+{
+    const { assert!(OUT_LEN <= WIDTH, "OUT_LEN must not exceed WIDTH") };
+
+    let mut states = [[BabyBear::ZERO; WIDTH]; PACKED_LANES];
+    for (state, x) in states.iter_mut().zip(inputs) {
+        let in_len = x.len();
+        assert!(
+            in_len >= OUT_LEN,
+            "{}",
+            Poseidon2Error::OutputExceedsInput { out_len: OUT_LEN, in_len }
+        );
+        assert!(in_len <= WIDTH, "{}", Poseidon2Error::InputTooLong { len: in_len, width: WIDTH });
+        state[..x.len()].copy_from_slice(x);
+    }
+
+    let states = &mut states[..inputs.len()];
+    poseidon2_permute_packed(poseidon_instance, states);
+
+    // Truncate(PoseidonPermute(x) + x), as in `poseidon2_compress`
+    for ((out, state), x) in out.iter_mut().zip(states.iter()).zip(inputs) {
+        *out = core::array::from_fn(|i| state[i] + x[i]);
+    }
+}
+
+/// Compresses every input of `inputs` into the matching entry of `out`.
+///
+/// The output is identical to calling [`poseidon2_compress`] on each input. Inputs may have
+/// different lengths, as long as each one is accepted by [`poseidon2_compress`]. The inputs are
+/// permuted [`PACKED_LANES`] at a time with [`poseidon2_permute_packed`].
+///
+/// # Panics
+///
+/// Panics if `inputs` and `out` have different lengths, or if an input is rejected by
+/// [`try_poseidon2_compress`].
+pub fn poseidon2_compress_batch<const OUT_LEN: usize, const WIDTH: usize, I>(
+    poseidon_instance: &I,
+    inputs: &[&[BabyBear]],
+    out: &mut [[BabyBear; OUT_LEN]],
+) where
+    I: Poseidon2BabyBearValidWidth<WIDTH> + Permutation<[PackedBabyBear; WIDTH]>,
+{
+    assert_eq!(inputs.len(), out.len(), "one output is needed per input");
+
+    for (inputs, out) in inputs.chunks(PACKED_LANES).zip(out.chunks_mut(PACKED_LANES)) {
+        compress_packed_batch(poseidon_instance, inputs, out);
+    }
+}
+
+/// Compresses every input of `inputs` into the matching entry of `out` on the rayon thread pool.
+///
+/// This is [`poseidon2_compress_batch`] with the inputs split across threads, which all share
+/// `poseidon_instance`. The output does not depend on the number of threads.
+///
+/// # Panics
+///
+/// Panics if `inputs` and `out` have different lengths, or if an input is rejected by
+/// [`try_poseidon2_compress`].
+#[cfg(feature = "rayon")]
+pub fn par_poseidon2_compress_batch<const OUT_LEN: usize, const WIDTH: usize, I>(
+    poseidon_instance: &I,
+    inputs: &[&[BabyBear]],
+    out: &mut [[BabyBear; OUT_LEN]],
+) where
+    I: Poseidon2BabyBearValidWidth<WIDTH> + Permutation<[PackedBabyBear; WIDTH]> + Sync,
+{
+    use rayon::prelude::*;
+
+    assert_eq!(inputs.len(), out.len(), "one output is needed per input");
+
+    inputs.par_chunks(PACKED_LANES).zip(out.par_chunks_mut(PACKED_LANES)).for_each(
+        |(inputs, out)| {
+            compress_packed_batch(poseidon_instance, inputs, out);
+        },
+    );
+}
+
+/// Compresses the two children `left` and `right` of a Merkle node into their parent.
+///
+/// The width-24 permutation is applied to `parameter || tweak_fe || left || right`, zero-padded,
+/// as with [`poseidon2_compress`] on the concatenation, but the input is assembled on the stack.
+/// Inputs that do not fit the permutation are rejected at compile time:
+///
+/// ```compile_fail
+/// # use openvm_sig_agg::{poseidon2::poseidon2_compress_pair, poseidon2_config::poseidon2_instance};
+/// # use p3_baby_bear::BabyBear;
+/// # use p3_field::FieldAlgebra;
+/// # let instance = poseidon2_instance();
+/// // 5 + 3 + 2 * 9 = 26 elements
+/// let children = [BabyBear::ONE; 9];
+/// poseidon2_compress_pair(&instance, &[BabyBear::ONE; 5], &[BabyBear::ONE; 3], &children, &children);
+/// ```
+pub fn poseidon2_compress_pair<
+    const HASH_LEN: usize,
+    const PARAMETER_LEN: usize,
+    const TWEAK_LEN: usize,
+>(
+    poseidon_instance: &Poseidon2BabyBearLong,
+    parameter: &[BabyBear; PARAMETER_LEN],
+    tweak_fe: &[BabyBear; TWEAK_LEN],
+    left: &[BabyBear; HASH_LEN],
+    right: &[BabyBear; HASH_LEN],
+) -> [BabyBear; HASH_LEN] {
+    const {
+        assert!(
+            PARAMETER_LEN + TWEAK_LEN + 2 * HASH_LEN <= 24,
+            "parameter, tweak and children must fit in the width-24 permutation"
+        );
+    };
+
+    let mut input = [BabyBear::ZERO; 24];
+    let (parameter_lanes, rest) = input.split_at_mut(PARAMETER_LEN);
+    let (tweak_lanes, rest) = rest.split_at_mut(TWEAK_LEN);
+    let (left_lanes, rest) = rest.split_at_mut(HASH_LEN);
+    parameter_lanes.copy_from_slice(parameter);
+    tweak_lanes.copy_from_slice(tweak_fe);
+    left_lanes.copy_from_slice(left);
+    rest[..HASH_LEN].copy_from_slice(right);
+
+    // The zero lanes after the children are the padding of `poseidon2_compress`
+    poseidon2_compress::<HASH_LEN, 24, _>(poseidon_instance, &input)
 }
 
 /// Implements the Poseidon2 **Sponge Mode** hashing function.
 ///
 /// # Overview:
-/// - **Absorption:** The input `x` is divided into chunks of `rate`, and each chunk is absorbed.
-/// - **Permutation:** After every absorption step, Poseidon2 permutation is applied.
-/// - **Squeezing:** The state is iteratively extracted to produce `OUT_LEN` elements.
+/// - **Padding:** The input `x` is padded with `10*`: a `1` is appended, followed by zeros up to
+///   a multiple of `rate = WIDTH - CAPACITY`. The `1` is appended even if `x` already fills its
+///   last chunk, so inputs of different lengths never pad to the same chunks.
+/// - **State:** The `WIDTH` lanes are split into the `rate` rate lanes followed by the
+///   `CAPACITY` capacity lanes, which start out as `capacity_value`.
+/// - **Absorption:** The padded input is divided into chunks of `rate`, and each chunk is added
+///   into the rate lanes. The capacity lanes are never written to directly.
+/// - **Permutation:** After every absorption step, Poseidon2 permutation is applied to the whole
+///   state.
+/// - **Squeezing:** The rate lanes are iteratively extracted to produce `OUT_LEN` elements.
 ///
 /// # Paper Reference:
 /// This mode is **more flexible** than compression mode but has **higher computational cost**.
 /// - It is used when `x.len()` exceeds `t = {4, 8, 12, 16, 20, 24}`.
 /// - Requires padding to align input to a multiple of `rate`.
-pub fn poseidon2_sponge<const OUT_LEN: usize, const CAPACITY: usize, I>(
+///
+/// `CAPACITY < WIDTH` is checked at compile time, without nightly features:
+///
+/// ```compile_fail
+/// # use openvm_sig_agg::{poseidon2::poseidon2_sponge, poseidon2_config::poseidon2_instance_short};
+/// # use p3_baby_bear::BabyBear;
+/// # use p3_field::FieldAlgebra;
+/// # let instance = poseidon2_instance_short();
+/// let out: [BabyBear; 8] = poseidon2_sponge(&instance, &[BabyBear::ONE; 16], &[BabyBear::ONE]);
+/// ```
+pub fn poseidon2_sponge<const OUT_LEN: usize, const CAPACITY: usize, const WIDTH: usize, I>(
+    poseidon_instance: &I,
+    capacity_value: &[I::Field; CAPACITY],
+    x: &[I::Field],
+) -> [I::Field; OUT_LEN]
+where
+    I: Poseidon2ValidWidth<WIDTH>,
+{
+    sponge(poseidon_instance, capacity_value, x, SpongePadding::TenStar, AbsorbMode::Add)
+}
+
+/// Implements the Poseidon2 **Sponge Mode** with overwrite-mode absorption.
+///
+/// This is [`poseidon2_sponge`] with every chunk, the padded last one included, written over
+/// the rate lanes instead of added to them, see [`AbsorbMode::Overwrite`]. The outputs of the
+/// two functions are unrelated.
+pub fn poseidon2_sponge_overwrite<
+    const OUT_LEN: usize,
+    const CAPACITY: usize,
+    const WIDTH: usize,
+    I,
+>(
     poseidon_instance: &I,
-    capacity_value: &[BabyBear; CAPACITY],
-    x: &[BabyBear],
-) -> [BabyBear; OUT_LEN]
+    capacity_value: &[I::Field; CAPACITY],
+    x: &[I::Field],
+) -> [I::Field; OUT_LEN]
 where
-    I: Poseidon2BabyBearValidWidth + Permutation<[BabyBear; I::WIDTH - CAPACITY]>,
-    Assert<{ I::WIDTH > CAPACITY }>: IsTrue,
-    [(); I::WIDTH - CAPACITY]:,
+    I: Poseidon2ValidWidth<WIDTH>,
+{
+    sponge(poseidon_instance, capacity_value, x, SpongePadding::TenStar, AbsorbMode::Overwrite)
+}
+
+/// Implements the Poseidon2 **Sponge Mode** with zero padding only.
+///
+/// This is [`poseidon2_sponge`] without the `10*` padding: the input is only padded with zeros
+/// to a multiple of the rate, so `[a]` and `[a, 0]` hash to the same output. It is only sound
+/// when every input of a given `capacity_value` has the same length, e.g. when the capacity
+/// value is derived from the input lengths.
+pub fn poseidon2_sponge_unpadded<
+    const OUT_LEN: usize,
+    const CAPACITY: usize,
+    const WIDTH: usize,
+    I,
+>(
+    poseidon_instance: &I,
+    capacity_value: &[I::Field; CAPACITY],
+    x: &[I::Field],
+) -> [I::Field; OUT_LEN]
+where
+    I: Poseidon2ValidWidth<WIDTH>,
+{
+    sponge(poseidon_instance, capacity_value, x, SpongePadding::Zero, AbsorbMode::Add)
+}
+
+/// Implements the Poseidon2 **Sponge Mode** with an output of any length.
+///
+/// The input is absorbed once, padded like in [`poseidon2_sponge`], and `out` is filled with as
+/// many `rate`-sized blocks as needed, permuting between blocks. The first `n` output elements
+/// only depend on the input, not on `out.len()`, so a longer output extends a shorter one and
+/// `out.len() == OUT_LEN` gives the output of [`poseidon2_sponge`].
+pub fn poseidon2_xof<const CAPACITY: usize, const WIDTH: usize, I>(
+    poseidon_instance: &I,
+    capacity_value: &[I::Field; CAPACITY],
+    input: &[I::Field],
+    out: &mut [I::Field],
+) where
+    I: Poseidon2ValidWidth<WIDTH>,
 {
+    sponge_into(
+        poseidon_instance,
+        capacity_value,
+        input,
+        SpongePadding::TenStar,
+        AbsorbMode::Add,
+        out,
+    );
+}
+
+/// How a sponge absorbs its input chunks into the rate lanes.
+///
+/// Both modes keep the capacity lanes out of reach of the input, so both have the generic
+/// sponge security of half the capacity. They differ in what the next permutation sees:
+///
+/// - [`Add`](Self::Add) mixes each chunk into the output of the previous permutation. It is the
+///   mode of [`poseidon2_sponge`] and of the circuits verifying it.
+/// - [`Overwrite`](Self::Overwrite) discards the rate lanes of the previous permutation, as in
+///   the SAFE API. It saves the additions in circuits, but only the capacity lanes carry the
+///   absorbed input forward, so a short capacity weakens it first. Its padding must overwrite
+///   every rate lane, zeros included, or stale lanes would enter the last permutation.
+///
+/// The modes hash the same input to unrelated outputs: the mode is part of the specification
+/// of a hash and must match the implementation it interoperates with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AbsorbMode {
+    /// `state[i] += chunk[i]`.
+    #[default]
+    Add,
+    /// `state[i] = chunk[i]`.
+    Overwrite,
+}
+
+impl AbsorbMode {
+    /// Absorbs `element` into the rate lane `lane`.
+    #[inline(always)]
+    fn absorb<F: Field>(self, lane: &mut F, element: F) {
+        match self {
+            Self::Add => *lane += element,
+            Self::Overwrite => *lane = element,
+        }
+    }
+}
+
+/// How [`sponge`] pads its input to a multiple of the rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SpongePadding {
+    /// Append a `1`, then zeros, even to inputs that fill their last chunk.
+    TenStar,
+    /// Append zeros to inputs that do not fill their last chunk.
+    Zero,
+}
+
+/// Absorbs `x` padded with `padding` into a sponge with `mode` and squeezes `OUT_LEN` elements
+/// out of it.
+fn sponge<const OUT_LEN: usize, const CAPACITY: usize, const WIDTH: usize, I>(
+    poseidon_instance: &I,
+    capacity_value: &[I::Field; CAPACITY],
+    x: &[I::Field],
+    padding: SpongePadding,
+    mode: AbsorbMode,
+) -> [I::Field; OUT_LEN]
+where
+    I: Poseidon2ValidWidth<WIDTH>,
+{
+    let mut out = [I::Field::ZERO; OUT_LEN];
+    sponge_into(poseidon_instance, capacity_value, x, padding, mode, &mut out);
+    out
+}
+
+/// Absorbs `x` padded with `padding` into a sponge with `mode` and squeezes `out.len()`
+/// elements out of it.
+///
+/// Nothing is allocated: full chunks are absorbed from `x` directly, and the padded last chunk
+/// is built on the stack.
+fn sponge_into<const CAPACITY: usize, const WIDTH: usize, I>(
+    poseidon_instance: &I,
+    capacity_value: &[I::Field; CAPACITY],
+    x: &[I::Field],
+    padding: SpongePadding,
+    mode: AbsorbMode,
+    out: &mut [I::Field],
+) where
+    I: Poseidon2ValidWidth<WIDTH>,
+{
+    // The capacity must leave room for at least one rate element
+    const { assert!(CAPACITY < WIDTH, "CAPACITY must be less than WIDTH") };
+
     // Compute the rate (available absorption space)
-    let rate = I::WIDTH - CAPACITY;
+    let rate = WIDTH - CAPACITY;
 
-    // Pad input to a multiple of `rate`
-    let extra_elements = (rate - (x.len() % rate)) % rate;
-    let mut input_vector = x.to_vec().clone();
-    input_vector.resize(x.len() + extra_elements, BabyBear::ZERO);
+    // Initialize state: zero rate lanes followed by the `capacity_value` capacity lanes
+    let mut state = [I::Field::ZERO; WIDTH];
+    state[rate..].copy_from_slice(capacity_value);
 
-    // Initialize state with `capacity_value`
-    let mut state =
-        core::array::from_fn(|i| capacity_value.get(i).copied().unwrap_or(BabyBear::ZERO));
+    // Absorption phase: chunks only go into the rate lanes, the whole state is permuted
+    let mut absorb = |chunk: &[I::Field]| {
+        state[..rate].iter_mut().zip(chunk).for_each(|(s, &c)| mode.absorb(s, c));
+        permute(poseidon_instance, &mut state);
+    };
+    let mut chunks = x.chunks_exact(rate);
+    chunks.by_ref().for_each(&mut absorb);
 
-    // Absorption phase
-    for chunk in input_vector.chunks_exact(rate) {
-        state.iter_mut().zip(chunk).for_each(|(s, &c)| *s += c);
-        poseidon_instance.permute_mut(&mut state);
+    // Pad the remaining elements into a last chunk
+    let remainder = chunks.remainder();
+    let mut last = [I::Field::ZERO; WIDTH];
+    last[..remainder.len()].copy_from_slice(remainder);
+    match padding {
+        SpongePadding::TenStar => {
+            last[remainder.len()] = I::Field::ONE;
+            absorb(&last[..rate]);
+        }
+        SpongePadding::Zero if !remainder.is_empty() => absorb(&last[..rate]),
+        SpongePadding::Zero => {}
     }
 
-    // // Step 5: Squeeze phase - extract output
-    // let mut out = [BabyBear::ZERO; OUT_LEN];
-    // let mut out_index = 0;
+    squeeze(poseidon_instance, &mut state, rate, out);
+}
 
-    // while out_index < OUT_LEN {
-    //     let len = (OUT_LEN - out_index).min(rate);
-    //     out[out_index..out_index + len].copy_from_slice(&state[..len]);
-    //     out_index += len;
-    //     poseidon_instance.permute_mut(&mut state);
-    // }
+/// Fills `out` from the first `rate` lanes of an absorbed sponge `state`.
+fn squeeze<const WIDTH: usize, I>(
+    poseidon_instance: &I,
+    state: &mut [I::Field; WIDTH],
+    rate: usize,
+    out: &mut [I::Field],
+) where
+    I: Poseidon2ValidWidth<WIDTH>,
+{
+    // Copy the rate lanes, permuting only while more output is needed
+    let mut blocks = out.chunks_mut(rate).peekable();
+    while let Some(block) = blocks.next() {
+        block.copy_from_slice(&state[..block.len()]);
+        if blocks.peek().is_some() {
+            permute(poseidon_instance, state);
+        }
+    }
+}
 
-    // out
+/// An incremental Poseidon2 sponge.
+///
+/// The input is absorbed piece by piece with [`absorb`](Self::absorb), so callers hashing a
+/// concatenation, e.g. `parameter || tweak || message`, do not need to build it first. The
+/// output of [`squeeze`](Self::squeeze) is the one of [`poseidon2_sponge`] for the concatenation
+/// of every absorbed slice, however it is split:
+///
+/// ```
+/// use openvm_sig_agg::{
+///     poseidon2::{poseidon2_sponge, Poseidon2Sponge},
+///     poseidon2_config::poseidon2_instance,
+/// };
+/// use p3_baby_bear::BabyBear;
+/// use p3_field::FieldAlgebra;
+///
+/// let instance = poseidon2_instance();
+/// let capacity = [BabyBear::ONE; 9];
+///
+/// let mut sponge = Poseidon2Sponge::new(&instance, &capacity);
+/// sponge.absorb(&[BabyBear::ONE; 4]);
+/// sponge.absorb(&[BabyBear::TWO; 20]);
+/// let out: [BabyBear; 8] = sponge.squeeze();
+///
+/// let mut x = vec![BabyBear::ONE; 4];
+/// x.extend([BabyBear::TWO; 20]);
+/// assert_eq!(out, poseidon2_sponge::<8, 9, 24, _>(&instance, &capacity, &x));
+/// ```
+///
+/// Squeezing consumes the sponge, so nothing can be absorbed after the output is taken:
+///
+/// ```compile_fail
+/// # use openvm_sig_agg::{poseidon2::Poseidon2Sponge, poseidon2_config::poseidon2_instance};
+/// # use p3_baby_bear::BabyBear;
+/// # use p3_field::FieldAlgebra;
+/// # let instance = poseidon2_instance();
+/// let mut sponge = Poseidon2Sponge::new(&instance, &[BabyBear::ONE; 9]);
+/// let out: [BabyBear; 8] = sponge.squeeze();
+/// sponge.absorb(&out);
+/// ```
+///
+/// The [absorb mode](AbsorbMode) is given when the sponge is created, so it cannot change after
+/// something has been absorbed:
+///
+/// ```compile_fail
+/// # use openvm_sig_agg::{
+/// #     poseidon2::{AbsorbMode, Poseidon2Sponge},
+/// #     poseidon2_config::poseidon2_instance,
+/// # };
+/// # use p3_baby_bear::BabyBear;
+/// # use p3_field::FieldAlgebra;
+/// # let instance = poseidon2_instance();
+/// let mut sponge = Poseidon2Sponge::new(&instance, &[BabyBear::ONE; 9]);
+/// sponge.absorb(&[BabyBear::ONE; 4]);
+/// let sponge = sponge.with_absorb_mode(AbsorbMode::Overwrite);
+/// ```
+#[derive(Debug)]
+pub struct Poseidon2Sponge<'a, I, const CAPACITY: usize, const WIDTH: usize>
+where
+    I: Poseidon2ValidWidth<WIDTH>,
+{
+    poseidon_instance: &'a I,
+    state: [I::Field; WIDTH],
+    /// The number of elements absorbed into the rate lanes since the last permutation.
+    absorbed: usize,
+    mode: AbsorbMode,
+}
+
+impl<I, const CAPACITY: usize, const WIDTH: usize> Clone for Poseidon2Sponge<'_, I, CAPACITY, WIDTH>
+where
+    I: Poseidon2ValidWidth<WIDTH>,
+{
+    fn clone(&self) -> Self {
+        Self {
+            poseidon_instance: self.poseidon_instance,
+            state: self.state,
+            absorbed: self.absorbed,
+            mode: self.mode,
+        }
+    }
+}
 
-    // Squeeze phase - extract output
-    let mut out = vec![];
-    while out.len() < OUT_LEN {
-        out.extend_from_slice(&state[..rate]);
-        poseidon_instance.permute_mut(&mut state);
+impl<'a, I, const CAPACITY: usize, const WIDTH: usize> Poseidon2Sponge<'a, I, CAPACITY, WIDTH>
+where
+    I: Poseidon2ValidWidth<WIDTH>,
+{
+    /// The number of lanes the input is absorbed into.
+    const RATE: usize = {
+        // The capacity must leave room for at least one rate element
+        assert!(CAPACITY < WIDTH, "CAPACITY must be less than WIDTH");
+        WIDTH - CAPACITY
+    };
+
+    /// Creates an empty sponge whose capacity lanes hold `capacity_value`, absorbing with
+    /// [`AbsorbMode::Add`].
+    pub fn new(poseidon_instance: &'a I, capacity_value: &[I::Field; CAPACITY]) -> Self {
+        Self::with_absorb_mode(poseidon_instance, capacity_value, AbsorbMode::Add)
+    }
+
+    /// Creates an empty sponge whose capacity lanes hold `capacity_value`, absorbing with `mode`.
+    ///
+    /// With [`AbsorbMode::Overwrite`], the output is the one of [`poseidon2_sponge_overwrite`].
+    /// The mode is fixed for the lifetime of the sponge, so it cannot change once something has
+    /// been absorbed.
+    pub fn with_absorb_mode(
+        poseidon_instance: &'a I,
+        capacity_value: &[I::Field; CAPACITY],
+        mode: AbsorbMode,
+    ) -> Self {
+        let mut state = [I::Field::ZERO; WIDTH];
+        state[Self::RATE..].copy_from_slice(capacity_value);
+        Self { poseidon_instance, state, absorbed: 0, mode }
+    }
+
+    /// Absorbs `x` after the elements absorbed so far.
+    ///
+    /// The rate lanes are permuted as soon as they are full, so absorbing in pieces costs the
+    /// same permutations as absorbing the concatenation at once.
+    pub fn absorb(&mut self, x: &[I::Field]) {
+        for &element in x {
+            self.mode.absorb(&mut self.state[self.absorbed], element);
+            self.absorbed += 1;
+            if self.absorbed == Self::RATE {
+                permute(self.poseidon_instance, &mut self.state);
+                self.absorbed = 0;
+            }
+        }
     }
-    out.as_slice()[..OUT_LEN].try_into().expect("Length mismatch")
+
+    /// Pads the absorbed input with `10*` like [`poseidon2_sponge`] and squeezes `OUT_LEN`
+    /// elements out of the sponge.
+    pub fn squeeze<const OUT_LEN: usize>(mut self) -> [I::Field; OUT_LEN] {
+        // The `1` closes the last, possibly empty, chunk; the remaining lanes get zeros
+        let mode = self.mode;
+        self.state[self.absorbed..Self::RATE]
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, lane)| mode.absorb(lane, I::Field::from_bool(i == 0)));
+        permute(self.poseidon_instance, &mut self.state);
+
+        let mut out = [I::Field::ZERO; OUT_LEN];
+        squeeze(self.poseidon_instance, &mut self.state, Self::RATE, &mut out);
+        out
+    }
+}
+
+/// The number of bytes packed into each field element by [`bytes_to_field_elements`].
+pub const BYTES_PER_FIELD_ELEMENT: usize = 3;
+
+/// The field element holding the length of a byte string.
+pub(crate) fn byte_length_element<F: PrimeField32>(len: usize) -> F {
+    let len = u32::try_from(len)
+        .ok()
+        .filter(|&len| len < F::ORDER_U32)
+        .expect("byte strings must be shorter than the field order");
+    F::from_canonical_u32(len)
+}
+
+/// Packs at most [`BYTES_PER_FIELD_ELEMENT`] bytes into a field element, little-endian.
+pub(crate) fn pack_bytes<F: PrimeField32>(chunk: &[u8]) -> F {
+    F::from_canonical_u32(chunk.iter().rev().fold(0, |acc, &byte| (acc << 8) | u32::from(byte)))
 }
 
-pub fn poseidon_safe_domain_separator<const OUT_LEN: usize, I>(
+/// Packs `bytes` into field elements.
+///
+/// The first element is the number of bytes. It is followed by the bytes in chunks of
+/// [`BYTES_PER_FIELD_ELEMENT`], each read as a little-endian integer, the last chunk being
+/// possibly shorter. Chunks are below `2^24`, so they are canonical field elements, and the
+/// length tells how many bytes the last chunk holds: the packing is injective, e.g. `[]`,
+/// `[0x00]` and `[0x00, 0x00]` pack to `[0]`, `[1, 0]` and `[2, 0]`.
+///
+/// # Panics
+///
+/// Panics if `bytes` is not shorter than the field order.
+#[cfg(feature = "alloc")]
+pub fn bytes_to_field_elements(bytes: &[u8]) -> Vec<BabyBear> {
+    core::iter::once(byte_length_element(bytes.len()))
+        .chain(bytes.chunks(BYTES_PER_FIELD_ELEMENT).map(pack_bytes))
+        .collect()
+}
+
+/// Hashes the byte string `bytes` into `OUT_LEN` field elements.
+///
+/// This is [`poseidon2_sponge`] over [`bytes_to_field_elements`] of `bytes`, with `domain` as
+/// the capacity value, but the packed elements are absorbed one by one instead of being
+/// collected first.
+///
+/// # Panics
+///
+/// Panics if `bytes` is not shorter than the field order.
+pub fn poseidon2_hash_bytes<const OUT_LEN: usize, const CAPACITY: usize, const WIDTH: usize, I>(
     poseidon_instance: &I,
-    params: &[usize],
-) -> [BabyBear; OUT_LEN]
+    domain: &[I::Field; CAPACITY],
+    bytes: &[u8],
+) -> [I::Field; OUT_LEN]
 where
-    I: Poseidon2BabyBearValidWidth + Permutation<[BabyBear; I::WIDTH]>,
+    I: Poseidon2ValidWidth<WIDTH>,
 {
-    // Turn params into a big integer
-    let domain_uint = params.iter().fold(BigUint::ZERO, |acc, &item| {
-        acc * BigUint::from(((1 as u64) << 32) as u64) + (item as u32)
-    });
+    let mut sponge = Poseidon2Sponge::<_, CAPACITY, WIDTH>::new(poseidon_instance, domain);
+    sponge.absorb(&[byte_length_element(bytes.len())]);
+    for chunk in bytes.chunks(BYTES_PER_FIELD_ELEMENT) {
+        sponge.absorb(&[pack_bytes(chunk)]);
+    }
+    sponge.squeeze()
+}
+
+/// The separator byte of [`poseidon2_prf`] inputs.
+///
+/// It follows the [`TweakDomain`](crate::tweak::TweakDomain) values, so a PRF index never
+/// encodes like a tweak.
+pub const PRF_SEPARATOR: u8 = 0x03;
+
+/// Encodes the index of a [`poseidon2_prf`] output into [`TWEAK_LEN`] field elements.
+///
+/// The index is packed into the integer `epoch << 24 | chain_index << 8 | PRF_SEPARATOR` and
+/// split into base-p limbs, least significant first, like a tweak. E.g. epoch `7` and chain
+/// index `3` encode to `[0x0700_0303, 0, 0]`.
+pub fn poseidon2_prf_index(epoch: u32, chain_index: u16) -> [BabyBear; TWEAK_LEN] {
+    let packed =
+        (u128::from(epoch) << 24) | (u128::from(chain_index) << 8) | u128::from(PRF_SEPARATOR);
+    limbs_from_packed(packed)
+}
+
+/// Derives `OUT_LEN` pseudorandom field elements, e.g. the start of a hash chain, from the
+/// secret `key`, an epoch and a chain index.
+///
+/// The output is [`poseidon2_compress`] over the width-16 permutation of
+/// `key || poseidon2_prf_index(epoch, chain_index)`. Keys and outputs that do not fit the
+/// permutation are rejected at compile time.
+#[cfg(feature = "std")]
+pub fn poseidon2_prf<const OUT_LEN: usize, const KEY_LEN: usize>(
+    key: &[BabyBear; KEY_LEN],
+    epoch: u32,
+    chain_index: u16,
+) -> [BabyBear; OUT_LEN] {
+    const {
+        assert!(KEY_LEN + TWEAK_LEN <= 16, "the key and index must fit in the permutation");
+        assert!(OUT_LEN <= KEY_LEN + TWEAK_LEN, "the output must not exceed the input");
+    };
+
+    let mut input = [BabyBear::ZERO; 16];
+    input[..KEY_LEN].copy_from_slice(key);
+    input[KEY_LEN..KEY_LEN + TWEAK_LEN].copy_from_slice(&poseidon2_prf_index(epoch, chain_index));
+
+    let instance = crate::poseidon2_config::cached_poseidon2_instance_short();
+    poseidon2_compress::<OUT_LEN, 16, _>(instance, &input[..KEY_LEN + TWEAK_LEN])
+}
+
+/// Number of parameters hashed by [`poseidon_safe_domain_separator`].
+pub const DOMAIN_PARAMETERS_LENGTH: usize = 4;
+
+/// Packs the domain parameters into a `u128`, 32 bits each with the first one most significant,
+/// and splits it into `WIDTH` base-p limbs, least significant first.
+fn domain_separator_input<const WIDTH: usize>(
+    params: &[usize; DOMAIN_PARAMETERS_LENGTH],
+) -> Result<[BabyBear; WIDTH], Poseidon2Error> {
+    let mut domain = 0u128;
+    for (index, &value) in params.iter().enumerate() {
+        let param = u32::try_from(value)
+            .map_err(|_| Poseidon2Error::DomainParameterTooLarge { index, value })?;
+        domain = (domain << 32) | u128::from(param);
+    }
+
+    let prime = u128::from(BabyBear::ORDER_U32);
+    let mut input = [BabyBear::ZERO; WIDTH];
+    for limb in &mut input {
+        *limb = BabyBear::new((domain % prime) as u32);
+        domain /= prime;
+    }
+    Ok(input)
+}
+
+/// Hashes the domain parameters `params` into `OUT_LEN` field elements.
+///
+/// The parameters are packed into a 128-bit integer, 32 bits each, written in base p into the
+/// permutation state and compressed. Returns an error if a parameter does not fit in 32 bits.
+/// `OUT_LEN` must not exceed `WIDTH`, like for [`poseidon2_compress`].
+pub fn poseidon_safe_domain_separator<const OUT_LEN: usize, const WIDTH: usize, I>(
+    poseidon_instance: &I,
+    params: &[usize; DOMAIN_PARAMETERS_LENGTH],
+) -> Result<[BabyBear; OUT_LEN], Poseidon2Error>
+where
+    I: Poseidon2BabyBearValidWidth<WIDTH>,
+{
+    let input = domain_separator_input::<WIDTH>(params)?;
+    try_poseidon2_compress::<OUT_LEN, WIDTH, I>(poseidon_instance, &input)
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::poseidon2_config::{poseidon2_instance, poseidon2_instance_short};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn elements<const N: usize>(offset: u32) -> [BabyBear; N] {
+        core::array::from_fn(|i| BabyBear::new(offset + i as u32))
+    }
+
+    #[test]
+    fn test_try_padded_permute_rejects_long_inputs() {
+        let instance = poseidon2_instance_short();
+
+        let x = [BabyBear::ONE; 16];
+        assert_eq!(try_poseidon2_padded_permute(&instance, &x), Ok(instance.permute(x)));
+
+        let x = [BabyBear::ONE; 17];
+        assert_eq!(
+            try_poseidon2_padded_permute::<16, _>(&instance, &x),
+            Err(Poseidon2Error::InputTooLong { len: 17, width: 16 })
+        );
+    }
+
+    #[test]
+    fn test_try_compress_rejects_invalid_lengths() {
+        let instance = poseidon2_instance_short();
+
+        let x = [BabyBear::ONE; 8];
+        assert_eq!(
+            try_poseidon2_compress::<8, 16, _>(&instance, &x),
+            Ok(poseidon2_compress::<8, 16, _>(&instance, &x))
+        );
+        assert_eq!(
+            try_poseidon2_compress::<9, 16, _>(&instance, &x),
+            Err(Poseidon2Error::OutputExceedsInput { out_len: 9, in_len: 8 })
+        );
+
+        let x = [BabyBear::ONE; 17];
+        assert_eq!(
+            try_poseidon2_compress::<8, 16, _>(&instance, &x),
+            Err(Poseidon2Error::InputTooLong { len: 17, width: 16 })
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "input of 17 elements does not fit in a state of width 16")]
+    fn test_compress_panics_on_long_inputs() {
+        poseidon2_compress::<8, 16, _>(&poseidon2_instance_short(), &[BabyBear::ONE; 17]);
+    }
+
+    #[test]
+    fn test_compress_iter_matches_slice_compress() {
+        let instance = poseidon2_instance_short();
+        let x: Vec<BabyBear> = (0..20).map(|i| BabyBear::new(7 * i + 1)).collect();
+
+        for len in 0..=x.len() {
+            // Split in two parts to compress a chained iterator
+            let (head, tail) = x[..len].split_at(len / 2);
+            let input = head.iter().chain(tail).copied();
+            // Too long inputs are read up to their first extra element
+            let truncated = |width: usize| &x[..len.min(width + 1)];
+            assert_eq!(
+                try_poseidon2_compress_iter::<8, 16, _>(&instance, input.clone()),
+                try_poseidon2_compress::<8, 16, _>(&instance, truncated(16)),
+                "{len}"
+            );
+            assert_eq!(
+                try_poseidon2_compress_len_bound_iter::<8, 16, _>(&instance, input),
+                try_poseidon2_compress_len_bound::<8, 16, _>(&instance, truncated(15)),
+                "{len}"
+            );
+        }
+
+        // An infinite input is rejected after its first extra element
+        let ones = core::iter::repeat(BabyBear::ONE);
+        assert_eq!(
+            try_poseidon2_compress_iter::<8, 16, _>(&instance, ones.clone()),
+            Err(Poseidon2Error::InputTooLong { len: 17, width: 16 })
+        );
+        assert_eq!(
+            try_poseidon2_compress_len_bound_iter::<8, 16, _>(&instance, ones),
+            Err(Poseidon2Error::InputTooLong { len: 16, width: 15 })
+        );
+        assert_eq!(
+            poseidon2_compress_iter::<4, 16, _>(&instance, x[..16].iter().copied()),
+            poseidon2_compress::<4, 16, _>(&instance, &x[..16])
+        );
+    }
+
+    /// Inputs of every length accepted by a compression to 8 elements of width 16.
+    fn heterogeneous_inputs() -> Vec<Vec<BabyBear>> {
+        (0..64).map(|i| (0..8 + i % 9).map(|j| BabyBear::new(1000 * i + j)).collect()).collect()
+    }
+
+    #[test]
+    fn test_compress_batch_matches_compress() {
+        let instance = poseidon2_instance_short();
+        let inputs = heterogeneous_inputs();
+        let inputs: Vec<&[BabyBear]> = inputs.iter().map(Vec::as_slice).collect();
+
+        let mut out = vec![[BabyBear::ZERO; 8]; inputs.len()];
+        poseidon2_compress_batch::<8, 16, _>(&instance, &inputs, &mut out);
+        for (x, out) in inputs.iter().zip(&out) {
+            assert_eq!(*out, poseidon2_compress::<8, 16, _>(&instance, x));
+        }
+
+        // An empty batch writes nothing
+        let mut empty: [[BabyBear; 8]; 0] = [];
+        poseidon2_compress_batch::<8, 16, _>(&instance, &[], &mut empty);
+    }
+
+    #[test]
+    #[should_panic(expected = "one output is needed per input")]
+    fn test_compress_batch_checks_output_length() {
+        let x = [BabyBear::ONE; 16];
+        let mut out = [[BabyBear::ZERO; 8]; 2];
+        poseidon2_compress_batch::<8, 16, _>(&poseidon2_instance_short(), &[&x], &mut out);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_par_compress_batch_does_not_depend_on_threads() {
+        let instance = poseidon2_instance_short();
+        let inputs = heterogeneous_inputs();
+        let inputs: Vec<&[BabyBear]> = inputs.iter().map(Vec::as_slice).collect();
+
+        let mut expected = vec![[BabyBear::ZERO; 8]; inputs.len()];
+        poseidon2_compress_batch::<8, 16, _>(&instance, &inputs, &mut expected);
+
+        for threads in [1, 2, 3, 8] {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            let mut out = vec![[BabyBear::ZERO; 8]; inputs.len()];
+            pool.install(|| par_poseidon2_compress_batch::<8, 16, _>(&instance, &inputs, &mut out));
+            assert_eq!(out, expected, "{threads} threads");
+        }
+
+        let mut empty: [[BabyBear; 8]; 0] = [];
+        par_poseidon2_compress_batch::<8, 16, _>(&instance, &[], &mut empty);
+    }
+
+    #[test]
+    fn test_sponge_single_chunk_known_answer() {
+        let instance = poseidon2_instance();
+        let capacity: [BabyBear; 9] = elements(100);
+        let x: [BabyBear; 3] = elements(1);
+
+        // Rate lanes 0..15 hold the input padded with `1` and zeros, capacity lanes 15..24 the
+        // capacity value
+        let mut state = [BabyBear::ZERO; 24];
+        state[..3].copy_from_slice(&x);
+        state[3] = BabyBear::ONE;
+        state[15..].copy_from_slice(&capacity);
+        let expected = instance.permute(state);
+
+        let out: [BabyBear; 8] = poseidon2_sponge(&instance, &capacity, &x);
+        assert_eq!(out, expected[..8]);
+
+        // Without the `10*` padding, the rate lanes only hold the zero-padded input
+        state[3] = BabyBear::ZERO;
+        let out: [BabyBear; 8] = poseidon2_sponge_unpadded(&instance, &capacity, &x);
+        assert_eq!(out, instance.permute(state)[..8]);
+    }
+
+    #[test]
+    fn test_sponge_two_chunks_known_answer() {
+        let instance = poseidon2_instance();
+        let capacity: [BabyBear; 9] = elements(100);
+        let x: [BabyBear; 20] = elements(1);
+
+        // First chunk: 15 elements, second chunk: 5 elements, the `1` and 9 zeros of padding
+        let mut state = [BabyBear::ZERO; 24];
+        state[..15].copy_from_slice(&x[..15]);
+        state[15..].copy_from_slice(&capacity);
+        state = instance.permute(state);
+        for (lane, &element) in state.iter_mut().zip(&x[15..]) {
+            *lane += element;
+        }
+        state[5] += BabyBear::ONE;
+        state = instance.permute(state);
+
+        // Squeezing 20 elements takes the 15 rate lanes of two consecutive states
+        let mut expected = state[..15].to_vec();
+        expected.extend_from_slice(&instance.permute(state)[..5]);
+
+        let out: [BabyBear; 20] = poseidon2_sponge(&instance, &capacity, &x);
+        assert_eq!(out.to_vec(), expected);
+    }
+
+    #[test]
+    fn test_sponge_pads_block_aligned_inputs() {
+        let instance = poseidon2_instance();
+        let capacity: [BabyBear; 9] = elements(100);
+        let x: [BabyBear; 15] = elements(1);
+
+        // A full chunk is followed by a chunk holding only the padding
+        let mut padded = x.to_vec();
+        padded.push(BabyBear::ONE);
+        padded.resize(30, BabyBear::ZERO);
+
+        let out: [BabyBear; 8] = poseidon2_sponge(&instance, &capacity, &x);
+        let expected: [BabyBear; 8] = poseidon2_sponge_unpadded(&instance, &capacity, &padded);
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_sponge_padding_separates_trailing_zeros() {
+        let instance = poseidon2_instance();
+        let capacity: [BabyBear; 9] = elements(100);
+        let a = [BabyBear::new(7)];
+        let a0 = [BabyBear::new(7), BabyBear::ZERO];
+
+        // Zero padding turns both inputs into the same chunk
+        let unpadded: [[BabyBear; 8]; 2] = [
+            poseidon2_sponge_unpadded(&instance, &capacity, &a),
+            poseidon2_sponge_unpadded(&instance, &capacity, &a0),
+        ];
+        assert_eq!(unpadded[0], unpadded[1]);
+
+        let padded: [[BabyBear; 8]; 2] = [
+            poseidon2_sponge(&instance, &capacity, &a),
+            poseidon2_sponge(&instance, &capacity, &a0),
+        ];
+        assert_ne!(padded[0], padded[1]);
+
+        // The empty input is padded as well
+        let empty: [BabyBear; 8] = poseidon2_sponge(&instance, &capacity, &[]);
+        let zero: [BabyBear; 8] = poseidon2_sponge(&instance, &capacity, &[BabyBear::ZERO]);
+        assert_ne!(empty, zero);
+    }
+
+    #[test]
+    fn test_sponge_matches_allocating_implementation() {
+        // The sponge as it was written with a padded `Vec` input and a `Vec` output
+        fn allocating<const OUT_LEN: usize>(
+            instance: &Poseidon2BabyBearLong,
+            capacity_value: &[BabyBear; 9],
+            x: &[BabyBear],
+            ten_star: bool,
+        ) -> [BabyBear; OUT_LEN] {
+            let rate = 24 - 9;
+            let mut input_vector = x.to_vec();
+            if ten_star {
+                input_vector.push(BabyBear::ONE);
+            }
+            input_vector.resize(input_vector.len().next_multiple_of(rate), BabyBear::ZERO);
+
+            let mut state = [BabyBear::ZERO; 24];
+            state[rate..].copy_from_slice(capacity_value);
+            for chunk in input_vector.chunks_exact(rate) {
+                state[..rate].iter_mut().zip(chunk).for_each(|(s, &c)| *s += c);
+                instance.permute_mut(&mut state);
+            }
+
+            let mut out = vec![];
+            while out.len() < OUT_LEN {
+                out.extend_from_slice(&state[..rate]);
+                instance.permute_mut(&mut state);
+            }
+            out.as_slice()[..OUT_LEN].try_into().expect("Length mismatch")
+        }
+
+        let instance = poseidon2_instance();
+        let capacity: [BabyBear; 9] = elements(100);
+        let x: [BabyBear; 47] = elements(1);
+        for len in [0, 1, 14, 15, 16, 29, 30, 31, 47] {
+            let x = &x[..len];
+
+            let out: [BabyBear; 8] = poseidon2_sponge(&instance, &capacity, x);
+            assert_eq!(out, allocating::<8>(&instance, &capacity, x, true), "{len}");
+            let out: [BabyBear; 31] = poseidon2_sponge(&instance, &capacity, x);
+            assert_eq!(out, allocating::<31>(&instance, &capacity, x, true), "{len}");
+
+            let out: [BabyBear; 15] = poseidon2_sponge_unpadded(&instance, &capacity, x);
+            assert_eq!(out, allocating::<15>(&instance, &capacity, x, false), "{len}");
+            let out: [BabyBear; 30] = poseidon2_sponge_unpadded(&instance, &capacity, x);
+            assert_eq!(out, allocating::<30>(&instance, &capacity, x, false), "{len}");
+        }
+
+        // Squeezing no output does not index past the state
+        let _: [BabyBear; 0] = poseidon2_sponge(&instance, &capacity, &x);
+    }
 
-    // create the Poseidon input by interpreting the number in base-p
-    let mut input = vec![BabyBear::ZERO; I::WIDTH];
-    input.iter_mut().fold(domain_uint, |acc, item| {
-        let tmp = acc.clone() % BigUint::from(BabyBearParameters::PRIME);
-        *item = BabyBear::new(*tmp.to_u32_digits().first().unwrap_or(&0));
-        (acc - tmp) / (BigUint::from(BabyBearParameters::PRIME))
-    });
-    // now run Poseidon
-    poseidon2_compress::<OUT_LEN, I>(poseidon_instance, &input)
+    #[test]
+    fn test_incremental_sponge_matches_one_shot() {
+        let instance = poseidon2_instance();
+        let capacity: [BabyBear; 9] = elements(100);
+        let x: [BabyBear; 47] = elements(1);
+
+        // Split points of the input, covering empty pieces and pieces across chunk boundaries
+        let chunkings: [&[usize]; 8] = [
+            &[],
+            &[0],
+            &[0, 0, 47],
+            &[1, 14, 15],
+            &[15, 30, 45],
+            &[7, 23, 38, 46],
+            &[14, 16, 29, 31],
+            &[3, 3, 3, 47, 47],
+        ];
+        for len in [0, 1, 15, 16, 30, 47] {
+            let x = &x[..len];
+            let expected: [BabyBear; 31] = poseidon2_sponge(&instance, &capacity, x);
+
+            for splits in chunkings {
+                let mut sponge = Poseidon2Sponge::new(&instance, &capacity);
+                let mut start = 0;
+                for &split in splits.iter().chain(&[len]) {
+                    let end = split.clamp(start, len);
+                    sponge.absorb(&x[start..end]);
+                    start = end;
+                }
+                assert_eq!(sponge.squeeze::<31>(), expected, "{len} {splits:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_incremental_sponge_edge_cases() {
+        let instance = poseidon2_instance();
+        let capacity: [BabyBear; 9] = elements(100);
+
+        // Squeezing before any absorb is hashing the empty input
+        let empty: [BabyBear; 8] = Poseidon2Sponge::new(&instance, &capacity).squeeze();
+        assert_eq!(empty, poseidon2_sponge::<8, 9, 24, _>(&instance, &capacity, &[]));
+
+        // Absorbing nothing leaves the sponge unchanged
+        let mut sponge = Poseidon2Sponge::new(&instance, &capacity);
+        sponge.absorb(&[]);
+        assert_eq!(sponge.squeeze::<8>(), empty);
+
+        // A cloned sponge continues independently from the shared prefix
+        let mut prefix = Poseidon2Sponge::new(&instance, &capacity);
+        prefix.absorb(&elements::<20>(1));
+        let mut a = prefix.clone();
+        let mut b = prefix;
+        a.absorb(&[BabyBear::ONE]);
+        b.absorb(&[BabyBear::TWO]);
+        assert_ne!(a.squeeze::<8>(), b.squeeze::<8>());
+    }
+
+    #[test]
+    fn test_absorb_modes_follow_the_permutation() {
+        let instance = poseidon2_instance();
+        let capacity: [BabyBear; 9] = elements(100);
+        let x: [BabyBear; 20] = elements(1);
+
+        // Both chunks, the second one padded with `10*`, on the permutation directly
+        let absorb_directly = |mode: AbsorbMode| {
+            let mut last = [BabyBear::ZERO; 15];
+            last[..5].copy_from_slice(&x[15..]);
+            last[5] = BabyBear::ONE;
+
+            let mut state = [BabyBear::ZERO; 24];
+            state[15..].copy_from_slice(&capacity);
+            for chunk in [&x[..15], &last[..]] {
+                state[..15].iter_mut().zip(chunk).for_each(|(s, &c)| mode.absorb(s, c));
+                instance.permute_mut(&mut state);
+            }
+            <[BabyBear; 8]>::try_from(&state[..8]).unwrap()
+        };
+        let add = absorb_directly(AbsorbMode::Add);
+        let overwrite = absorb_directly(AbsorbMode::Overwrite);
+        assert_ne!(add, overwrite);
+
+        assert_eq!(poseidon2_sponge::<8, 9, 24, _>(&instance, &capacity, &x), add);
+        assert_eq!(poseidon2_sponge_overwrite::<8, 9, 24, _>(&instance, &capacity, &x), overwrite);
+
+        for (mode, expected) in [(AbsorbMode::Add, add), (AbsorbMode::Overwrite, overwrite)] {
+            let mut sponge = Poseidon2Sponge::with_absorb_mode(&instance, &capacity, mode);
+            sponge.absorb(&x[..7]);
+            sponge.absorb(&x[7..]);
+            assert_eq!(sponge.squeeze::<8>(), expected, "{mode:?}");
+        }
+    }
+
+    #[test]
+    fn test_overwrite_sponge_matches_incremental_sponge() {
+        let instance = poseidon2_instance();
+        let capacity: [BabyBear; 9] = elements(100);
+        let x: [BabyBear; 47] = elements(1);
+
+        // Lengths around chunk boundaries, where the padding overwrites a whole chunk
+        for len in [0, 1, 14, 15, 16, 29, 30, 31, 47] {
+            let x = &x[..len];
+            let mut sponge =
+                Poseidon2Sponge::with_absorb_mode(&instance, &capacity, AbsorbMode::Overwrite);
+            sponge.absorb(x);
+            assert_eq!(
+                sponge.squeeze::<20>(),
+                poseidon2_sponge_overwrite::<20, 9, 24, _>(&instance, &capacity, x),
+                "{len}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_sponge_keeps_capacity_out_of_the_rate() {
+        let instance = poseidon2_instance();
+        let x: [BabyBear; 15] = elements(1);
+
+        // With the capacity in the rate lanes, a capacity value could be cancelled by the input
+        let capacity: [BabyBear; 9] = elements(1);
+        let zero_capacity = [BabyBear::ZERO; 9];
+        let shifted: [BabyBear; 15] =
+            core::array::from_fn(|i| x[i] + *capacity.get(i).unwrap_or(&BabyBear::ZERO));
+
+        let a: [BabyBear; 8] = poseidon2_sponge(&instance, &capacity, &x);
+        let b: [BabyBear; 8] = poseidon2_sponge(&instance, &zero_capacity, &shifted);
+        assert_ne!(a, b);
+
+        let c: [BabyBear; 8] = poseidon2_sponge(&instance, &zero_capacity, &x);
+        assert_ne!(a, c);
+    }
+
+    /// The BigUint implementation the domain separator input used to be computed with.
+    fn domain_separator_input_biguint<const WIDTH: usize>(params: &[usize]) -> [BabyBear; WIDTH] {
+        use num_bigint::BigUint;
+
+        let domain_uint = params
+            .iter()
+            .fold(BigUint::ZERO, |acc, &item| acc * BigUint::from(1u64 << 32) + (item as u32));
+
+        let mut input = [BabyBear::ZERO; WIDTH];
+        input.iter_mut().fold(domain_uint, |acc, item| {
+            let tmp = acc.clone() % BigUint::from(BabyBear::ORDER_U32);
+            *item = BabyBear::new(*tmp.to_u32_digits().first().unwrap_or(&0));
+            (acc - tmp) / BigUint::from(BabyBear::ORDER_U32)
+        });
+        input
+    }
+
+    #[test]
+    fn test_domain_separator_input_fixtures() {
+        // Limbs of the packed parameters, least significant first, computed from the BigUint
+        // implementation
+        let fixtures: [([usize; 4], [u32; 5]); 7] = [
+            ([0, 0, 0, 0], [0, 0, 0, 0, 0]),
+            ([1, 2, 3, 4], [1454323646, 742074922, 1427480083, 9, 0]),
+            ([4, 3, 8, 2], [895977860, 1447165476, 1683388468, 38, 0]),
+            ([8, 2, 4, 7], [1935121307, 469464009, 1353510998, 77, 0]),
+            ([2, 80, 26, 8], [725372552, 1251505134, 841694591, 19, 0]),
+            (
+                [123456789, 2013265920, 7, 0xdeadbeef],
+                [656444240, 861477127, 1287796109, 1198646539, 0],
+            ),
+            ([u32::MAX as usize; 4], [663890613, 1010629583, 1185769012, 1434678067, 20]),
+        ];
+
+        for (params, limbs) in fixtures {
+            let input = domain_separator_input::<16>(&params).unwrap();
+            assert_eq!(input[..5], limbs.map(BabyBear::new), "{params:?}");
+            assert_eq!(input[5..], [BabyBear::ZERO; 11], "{params:?}");
+            assert_eq!(input, domain_separator_input_biguint::<16>(&params), "{params:?}");
+        }
+    }
+
+    #[test]
+    fn test_domain_separator_matches_biguint_implementation() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let instance = poseidon2_instance();
+
+        for _ in 0..256 {
+            let params: [usize; 4] = core::array::from_fn(|_| rng.gen::<u32>() as usize);
+            let input = domain_separator_input_biguint::<24>(&params);
+
+            assert_eq!(domain_separator_input::<24>(&params), Ok(input));
+            assert_eq!(
+                poseidon_safe_domain_separator::<8, 24, _>(&instance, &params),
+                Ok(poseidon2_compress::<8, 24, _>(&instance, &input))
+            );
+        }
+    }
+
+    #[test]
+    fn test_domain_separator_rejects_wide_parameters() {
+        let instance = poseidon2_instance_short();
+        let params = [1, 1 << 32, 3, 4];
+
+        assert_eq!(
+            poseidon_safe_domain_separator::<8, 16, _>(&instance, &params),
+            Err(Poseidon2Error::DomainParameterTooLarge { index: 1, value: 1 << 32 })
+        );
+    }
+
+    #[test]
+    fn test_compress_pair_matches_generic_compress() {
+        let instance = poseidon2_instance();
+        let parameter: [BabyBear; 5] = elements(1);
+        let tweak_fe: [BabyBear; 3] = elements(10);
+        let left: [BabyBear; 8] = elements(20);
+        let right: [BabyBear; 8] = elements(30);
+
+        let input: Vec<BabyBear> = [&parameter[..], &tweak_fe[..], &left[..], &right[..]].concat();
+        assert_eq!(
+            poseidon2_compress_pair(&instance, &parameter, &tweak_fe, &left, &right),
+            poseidon2_compress::<8, 24, _>(&instance, &input)
+        );
+
+        // Inputs shorter than the state are zero-padded like in the generic path
+        let parameter: [BabyBear; 4] = elements(1);
+        let (left, right): ([BabyBear; 4], [BabyBear; 4]) = (elements(20), elements(30));
+        let input: Vec<BabyBear> = [&parameter[..], &tweak_fe[..], &left[..], &right[..]].concat();
+        assert_eq!(
+            poseidon2_compress_pair(&instance, &parameter, &tweak_fe, &left, &right),
+            poseidon2_compress::<4, 24, _>(&instance, &input)
+        );
+    }
+
+    #[test]
+    fn test_compress_pair_depends_on_child_order() {
+        let instance = poseidon2_instance();
+        let parameter: [BabyBear; 5] = elements(1);
+        let tweak_fe: [BabyBear; 3] = elements(10);
+        let left: [BabyBear; 8] = elements(20);
+        let right: [BabyBear; 8] = elements(30);
+
+        assert_ne!(
+            poseidon2_compress_pair(&instance, &parameter, &tweak_fe, &left, &right),
+            poseidon2_compress_pair(&instance, &parameter, &tweak_fe, &right, &left)
+        );
+    }
+
+    #[test]
+    fn test_permute_many_matches_per_state_permutation() {
+        let short = poseidon2_instance_short();
+        let long = poseidon2_instance();
+
+        for count in [0, 1, 2, 7] {
+            let mut states: Vec<[BabyBear; 16]> = (0..count).map(|i| elements(100 * i)).collect();
+            let expected: Vec<_> = states.iter().map(|&state| short.permute(state)).collect();
+            poseidon2_permute_many(&short, &mut states);
+            assert_eq!(states, expected, "{count} states");
+
+            let mut states: Vec<[BabyBear; 24]> = (0..count).map(|i| elements(100 * i)).collect();
+            let expected: Vec<_> = states.iter().map(|&state| long.permute(state)).collect();
+            let mut flat: Vec<BabyBear> = states.concat();
+            poseidon2_permute_many(&long, &mut states);
+            assert_eq!(states, expected, "{count} states");
+            assert_eq!(poseidon2_permute_many_flat::<24, _>(&long, &mut flat), Ok(()));
+            assert_eq!(flat, expected.concat(), "{count} flat states");
+        }
+    }
+
+    #[test]
+    fn test_permute_many_flat_rejects_ragged_states() {
+        let long = poseidon2_instance();
+
+        for len in [1, 23, 25, 47] {
+            let mut flat: Vec<BabyBear> = (0..len).map(BabyBear::new).collect();
+            let original = flat.clone();
+            assert_eq!(
+                poseidon2_permute_many_flat::<24, _>(&long, &mut flat),
+                Err(Poseidon2Error::RaggedStates { len: len as usize, width: 24 })
+            );
+            assert_eq!(flat, original, "{len}");
+        }
+
+        // 72 elements are three states of width 24, but not whole states of width 16
+        let mut flat = [BabyBear::ONE; 72];
+        assert_eq!(poseidon2_permute_many_flat::<24, _>(&long, &mut flat), Ok(()));
+        assert_eq!(
+            poseidon2_permute_many_flat::<16, _>(&poseidon2_instance_short(), &mut flat),
+            Err(Poseidon2Error::RaggedStates { len: 72, width: 16 })
+        );
+    }
+
+    #[test]
+    fn test_permute_packed_matches_scalar_permutation() {
+        // Without SIMD support the packing has a single lane, so the same sizes also exercise
+        // the scalar fallback
+        let short = poseidon2_instance_short();
+        let long = poseidon2_instance();
+
+        for batch in [1, PACKED_LANES - 1, PACKED_LANES, PACKED_LANES + 3, 3 * PACKED_LANES + 1] {
+            let mut states: Vec<[BabyBear; 16]> =
+                (0..batch as u32).map(|i| elements(100 * i)).collect();
+            let expected: Vec<_> = states.iter().map(|&state| short.permute(state)).collect();
+            poseidon2_permute_packed(&short, &mut states);
+            assert_eq!(states, expected, "{batch} states");
+
+            let mut states: Vec<[BabyBear; 24]> =
+                (0..batch as u32).map(|i| elements(100 * i)).collect();
+            let expected: Vec<_> = states.iter().map(|&state| long.permute(state)).collect();
+            poseidon2_permute_packed(&long, &mut states);
+            assert_eq!(states, expected, "{batch} states");
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "does not fit in a state of width 16")]
+    fn test_compress_batch_rejects_long_inputs() {
+        let inputs = heterogeneous_inputs();
+        let mut inputs: Vec<&[BabyBear]> = inputs.iter().map(Vec::as_slice).collect();
+        let long = [BabyBear::ONE; 17];
+        inputs.push(&long);
+
+        let mut out = vec![[BabyBear::ZERO; 8]; inputs.len()];
+        poseidon2_compress_batch::<8, 16, _>(&poseidon2_instance_short(), &inputs, &mut out);
+    }
+
+    #[cfg(feature = "koala-bear")]
+    #[test]
+    fn test_koala_bear_instantiation() {
+        use p3_koala_bear::KoalaBear;
+        use p3_poseidon2::ExternalLayerConstants;
+
+        fn constants<const WIDTH: usize>(
+            partial_rounds: u32,
+        ) -> (ExternalLayerConstants<KoalaBear, WIDTH>, Vec<KoalaBear>) {
+            let round = |r: u32| {
+                core::array::from_fn(|i| KoalaBear::from_canonical_u32(r * 100 + i as u32))
+            };
+            let external = ExternalLayerConstants::new(
+                (0..4).map(round).collect(),
+                (4..8).map(round).collect(),
+            );
+            let internal =
+                (0..partial_rounds).map(|r| KoalaBear::from_canonical_u32(7 * r + 1)).collect();
+            (external, internal)
+        }
+
+        let (external, internal) = constants::<16>(20);
+        let short = KoalaBear::new_poseidon2_short(external, internal);
+        let (external, internal) = constants::<24>(23);
+        let long = KoalaBear::new_poseidon2_long(external, internal);
+
+        // Permute a known state
+        let state: [KoalaBear; 16] =
+            core::array::from_fn(|i| KoalaBear::from_canonical_u32(i as u32));
+        let permuted: [KoalaBear; 16] = poseidon2_padded_permute(&short, &state);
+        assert_eq!(permuted, short.permute(state));
+        assert_ne!(permuted, state);
+
+        // Compress a known input
+        let input = &state[..10];
+        let compressed: [KoalaBear; 8] = poseidon2_compress(&short, input);
+        let padded: [KoalaBear; 16] = poseidon2_padded_permute(&short, input);
+        assert_eq!(compressed, core::array::from_fn(|i| padded[i] + input[i]));
+
+        // Sponge over the width-24 permutation
+        let capacity = [KoalaBear::ONE; 9];
+        let a: [KoalaBear; 8] = poseidon2_sponge(&long, &capacity, &state);
+        let b: [KoalaBear; 8] = poseidon2_sponge(&long, &capacity, &state[..15]);
+        assert_ne!(a, b);
+
+        // The incremental sponge and the byte hash work over KoalaBear too
+        let mut sponge = Poseidon2Sponge::new(&long, &capacity);
+        sponge.absorb(&state[..5]);
+        sponge.absorb(&state[5..]);
+        assert_eq!(sponge.squeeze::<8>(), a);
+
+        let packed: Vec<KoalaBear> = [3, 0x63_62_61].map(KoalaBear::from_canonical_u32).to_vec();
+        assert_eq!(
+            poseidon2_hash_bytes::<8, 9, 24, _>(&long, &capacity, b"abc"),
+            poseidon2_sponge::<8, 9, 24, _>(&long, &capacity, &packed)
+        );
+    }
+
+    #[test]
+    fn test_xof_output_lengths() {
+        let instance = poseidon2_instance();
+        let capacity: [BabyBear; 9] = elements(100);
+        let input: [BabyBear; 20] = elements(1);
+        let rate = 24 - 9;
+
+        // The absorbed state, whose rate lanes are the first output block
+        let mut state = [BabyBear::ZERO; 24];
+        state[15..].copy_from_slice(&capacity);
+        state[..15].copy_from_slice(&input[..15]);
+        instance.permute_mut(&mut state);
+        state[..5].iter_mut().zip(&input[15..]).for_each(|(s, &x)| *s += x);
+        state[5] += BabyBear::ONE;
+        instance.permute_mut(&mut state);
+
+        let mut blocks = Vec::new();
+        for _ in 0..10 {
+            blocks.extend_from_slice(&state[..rate]);
+            instance.permute_mut(&mut state);
+        }
+
+        for len in [0, 1, rate, rate + 1, 10 * rate] {
+            let mut out = vec![BabyBear::ZERO; len];
+            poseidon2_xof(&instance, &capacity, &input, &mut out);
+            assert_eq!(out, blocks[..len], "{len} elements");
+        }
+
+        let mut out = [BabyBear::ZERO; 8];
+        poseidon2_xof(&instance, &capacity, &input, &mut out);
+        assert_eq!(out, poseidon2_sponge::<8, 9, 24, _>(&instance, &capacity, &input));
+    }
+
+    #[test]
+    fn test_xof_outputs_are_prefix_consistent() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let instance = poseidon2_instance_short();
+        let capacity: [BabyBear; 4] = elements(100);
+
+        for _ in 0..64 {
+            let input: Vec<BabyBear> = (0..rng.gen_range(0..40))
+                .map(|_| BabyBear::new(rng.gen_range(0..1 << 30)))
+                .collect();
+            let (short_len, long_len) = (rng.gen_range(0..60), rng.gen_range(0..60));
+            let (short_len, long_len) = (short_len.min(long_len), short_len.max(long_len));
+
+            let mut short = vec![BabyBear::ZERO; short_len];
+            let mut long = vec![BabyBear::ZERO; long_len];
+            poseidon2_xof(&instance, &capacity, &input, &mut short);
+            poseidon2_xof(&instance, &capacity, &input, &mut long);
+            assert_eq!(short, long[..short_len], "{input:?}");
+        }
+    }
+
+    #[test]
+    fn test_bytes_to_field_elements_vectors() {
+        let packed = |values: &[u32]| values.iter().map(|&v| BabyBear::new(v)).collect::<Vec<_>>();
+
+        assert_eq!(bytes_to_field_elements(b""), packed(&[0]));
+        assert_eq!(bytes_to_field_elements(&[0x00]), packed(&[1, 0]));
+        assert_eq!(bytes_to_field_elements(&[0x00, 0x00]), packed(&[2, 0]));
+        assert_eq!(bytes_to_field_elements(b"abc"), packed(&[3, 0x63_62_61]));
+        assert_eq!(bytes_to_field_elements(b"hello"), packed(&[5, 0x6c_65_68, 0x6f_6c]));
+        assert_eq!(bytes_to_field_elements(&[0xff; 4]), packed(&[4, 0xff_ff_ff, 0xff]));
+    }
+
+    #[test]
+    fn test_hash_bytes_separates_zero_strings() {
+        let instance = poseidon2_instance();
+        let domain: [BabyBear; 9] = elements(100);
+        let hash = |bytes: &[u8]| poseidon2_hash_bytes::<8, 9, 24, _>(&instance, &domain, bytes);
+
+        let hashes = [hash(&[]), hash(&[0x00]), hash(&[0x00, 0x00]), hash(&[0x00, 0x00, 0x00])];
+        for (i, a) in hashes.iter().enumerate() {
+            for b in &hashes[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+
+        // Another domain gives other hashes
+        let other: [BabyBear; 9] = elements(200);
+        assert_ne!(hash(b"abc"), poseidon2_hash_bytes::<8, 9, 24, _>(&instance, &other, b"abc"));
+    }
+
+    #[test]
+    fn test_hash_bytes_matches_sponge_over_packing() {
+        let instance = poseidon2_instance_short();
+        let domain: [BabyBear; 4] = elements(100);
+
+        for bytes in [&b""[..], b"a", b"abc", b"The quick brown fox jumps over the lazy dog"] {
+            assert_eq!(
+                poseidon2_hash_bytes::<8, 4, 16, _>(&instance, &domain, bytes),
+                poseidon2_sponge::<8, 4, 16, _>(
+                    &instance,
+                    &domain,
+                    &bytes_to_field_elements(bytes)
+                ),
+                "{bytes:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_compress_len_bound_separates_trailing_zeros() {
+        let instance = poseidon2_instance_short();
+        let x: [BabyBear; 10] = elements(1);
+        let mut padded = [BabyBear::ZERO; 15];
+        padded[..10].copy_from_slice(&x);
+
+        // Zero padding makes the plain compression collide on trailing zeros
+        assert_eq!(
+            poseidon2_compress::<8, 16, _>(&instance, &x),
+            poseidon2_compress::<8, 16, _>(&instance, &padded)
+        );
+        assert_ne!(
+            poseidon2_compress_len_bound::<8, 16, _>(&instance, &x),
+            poseidon2_compress_len_bound::<8, 16, _>(&instance, &padded)
+        );
+        assert_ne!(
+            poseidon2_compress_len_bound::<8, 16, _>(&instance, &x),
+            poseidon2_compress_len_bound::<8, 16, _>(&instance, &padded[..11])
+        );
+    }
+
+    #[test]
+    fn test_compress_len_bound_state_layout() {
+        let instance = poseidon2_instance();
+        let x: [BabyBear; 12] = elements(1);
+
+        let mut state = [BabyBear::ZERO; 24];
+        state[..12].copy_from_slice(&x);
+        state[23] = BabyBear::new(12);
+        let permuted = instance.permute(state);
+
+        let out: [BabyBear; 8] = poseidon2_compress_len_bound(&instance, &x);
+        assert_eq!(out, core::array::from_fn(|i| permuted[i] + x[i]));
+    }
+
+    #[test]
+    fn test_try_compress_len_bound_reserves_the_length_lane() {
+        let instance = poseidon2_instance_short();
+
+        assert!(
+            try_poseidon2_compress_len_bound::<8, 16, _>(&instance, &[BabyBear::ONE; 15]).is_ok()
+        );
+        assert_eq!(
+            try_poseidon2_compress_len_bound::<8, 16, _>(&instance, &[BabyBear::ONE; 16]),
+            Err(Poseidon2Error::InputTooLong { len: 16, width: 15 })
+        );
+        assert_eq!(
+            try_poseidon2_compress_len_bound::<8, 16, _>(&instance, &[BabyBear::ONE; 7]),
+            Err(Poseidon2Error::OutputExceedsInput { out_len: 8, in_len: 7 })
+        );
+    }
+
+    #[test]
+    fn test_hash_picks_the_mode_by_length() {
+        let short = poseidon2_instance_short();
+        let long = poseidon2_instance();
+        let input: [BabyBear; 40] = elements(1);
+
+        for len in [0, 1, 15] {
+            let tag = ((HashMode::Short as usize) << 8) | len;
+            assert_eq!(
+                poseidon2_hash::<8>(&input[..len]),
+                compress_with_tag::<8, 16, _>(&short, &input[..len], tag)
+            );
+        }
+        for len in [16, 17, 23] {
+            let tag = ((HashMode::Long as usize) << 8) | len;
+            assert_eq!(
+                poseidon2_hash::<8>(&input[..len]),
+                compress_with_tag::<8, 24, _>(&long, &input[..len], tag)
+            );
+        }
+
+        let params = [HashMode::Sponge as usize, 24, HASH_SPONGE_CAPACITY, 8];
+        let capacity: [BabyBear; HASH_SPONGE_CAPACITY] =
+            poseidon_safe_domain_separator::<9, 24, _>(&long, &params).unwrap();
+        for len in [24, 25, 40] {
+            assert_eq!(
+                poseidon2_hash::<8>(&input[..len]),
+                poseidon2_sponge::<8, 9, 24, _>(&long, &capacity, &input[..len])
+            );
+        }
+    }
+
+    #[test]
+    fn test_hash_modes_do_not_collide_at_boundaries() {
+        let input: [BabyBear; 26] = elements(1);
+        let mut zeros = [BabyBear::ZERO; 26];
+        zeros[..14].copy_from_slice(&input[..14]);
+
+        // Inputs around each boundary, and inputs only differing by trailing zeros across it
+        let hashes: Vec<[BabyBear; 8]> = [15, 16, 17, 23, 24, 25]
+            .into_iter()
+            .flat_map(|len| [poseidon2_hash(&input[..len]), poseidon2_hash(&zeros[..len])])
+            .collect();
+        for (i, a) in hashes.iter().enumerate() {
+            for b in &hashes[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_hash_differs_from_compress_len_bound() {
+        let input: [BabyBear; 23] = elements(1);
+        for len in [8, 15] {
+            assert_ne!(
+                poseidon2_hash::<8>(&input[..len]),
+                poseidon2_compress_len_bound::<8, 16, _>(
+                    &poseidon2_instance_short(),
+                    &input[..len]
+                )
+            );
+        }
+        for len in [16, 23] {
+            assert_ne!(
+                poseidon2_hash::<8>(&input[..len]),
+                poseidon2_compress_len_bound::<8, 24, _>(&poseidon2_instance(), &input[..len])
+            );
+        }
+    }
+
+    #[test]
+    fn test_prf_index_vectors() {
+        let limbs = |values: [u32; 3]| values.map(BabyBear::new);
+
+        assert_eq!(poseidon2_prf_index(7, 3), limbs([0x0700_0303, 0, 0]));
+        assert_eq!(poseidon2_prf_index(1, 0), limbs([0x0100_0003, 0, 0]));
+        assert_eq!(poseidon2_prf_index(u32::MAX, u16::MAX), limbs([232643809, 35791394, 0]));
+    }
+
+    #[test]
+    fn test_prf_separator_is_not_a_tweak_domain() {
+        use crate::tweak::TweakDomain;
+
+        assert!(TweakDomain::ALL.iter().all(|domain| domain.as_u8() != PRF_SEPARATOR));
+        assert_eq!(TweakDomain::from_u8(PRF_SEPARATOR), None);
+    }
+
+    #[test]
+    fn test_prf_matches_compress() {
+        let key: [BabyBear; 8] = elements(1);
+        let input = [&key[..], &poseidon2_prf_index(7, 3)[..]].concat();
+
+        assert_eq!(
+            poseidon2_prf::<8, 8>(&key, 7, 3),
+            poseidon2_compress::<8, 16, _>(&poseidon2_instance_short(), &input)
+        );
+    }
+
+    #[test]
+    fn test_prf_outputs_are_distinct() {
+        let key: [BabyBear; 8] = elements(1);
+        let prf = |epoch, chain_index| poseidon2_prf::<8, 8>(&key, epoch, chain_index);
+
+        assert_ne!(prf(0, 0), prf(1, 0));
+        assert_ne!(prf(0, 0), prf(0, 1));
+        assert_ne!(prf(1, 0), prf(0, 1));
+        assert_ne!(prf(0, 256), prf(1, 0));
+        assert_ne!(prf(u32::MAX, u16::MAX), prf(u32::MAX, u16::MAX - 1));
+
+        let other: [BabyBear; 8] = elements(2);
+        assert_ne!(prf(5, 5), poseidon2_prf::<8, 8>(&other, 5, 5));
+    }
 }
@@ -1,7 +1,17 @@
+use crate::poseidon2::{
+    poseidon_safe_domain_separator, Poseidon2BabyBearLong, Poseidon2BabyBearShort,
+    Poseidon2BabyBearValidWidth, Poseidon2Error, DOMAIN_PARAMETERS_LENGTH,
+};
 use p3_baby_bear::{BabyBear, Poseidon2BabyBear};
 use p3_field::{Field, FieldAlgebra};
 use p3_poseidon2::ExternalLayerConstants;
-use std::sync::OnceLock;
+use p3_symmetric::Permutation;
+use std::{
+    any::TypeId,
+    collections::HashMap,
+    fmt,
+    sync::{OnceLock, RwLock},
+};
 use zkhash::{
     ark_ff::PrimeField,
     fields::babybear::FpBabyBear as HorizenBabyBear,
@@ -116,3 +126,247 @@ pub fn get_poseidon2_24(
         generate_round_constants::<24, BABY_BEAR_POSEIDON2_PARTIAL_ROUNDS_24>(&RC24)
     })
 }
+
+/// Lazy-loaded instance for `WIDTH = 16`
+static BABYBEAR_POSEIDON2_INSTANCE_16: OnceLock<Poseidon2BabyBear<16>> = OnceLock::new();
+/// Lazy-loaded instance for `WIDTH = 24`
+static BABYBEAR_POSEIDON2_INSTANCE_24: OnceLock<Poseidon2BabyBear<24>> = OnceLock::new();
+
+/// Get the Poseidon2 instance for `WIDTH = 16`, built on the first call only
+pub fn cached_poseidon2_instance_short() -> &'static Poseidon2BabyBear<16> {
+    BABYBEAR_POSEIDON2_INSTANCE_16.get_or_init(poseidon2_instance_short)
+}
+
+/// Get the Poseidon2 instance for `WIDTH = 24`, built on the first call only
+pub fn cached_poseidon2_instance() -> &'static Poseidon2BabyBear<24> {
+    BABYBEAR_POSEIDON2_INSTANCE_24.get_or_init(poseidon2_instance)
+}
+
+/// Poseidon2 instances that can be built from the round constants of this module.
+pub trait DefaultPoseidon2Instance<const WIDTH: usize>:
+    Poseidon2BabyBearValidWidth<WIDTH> + 'static
+{
+    /// Builds the instance.
+    fn instance() -> Self;
+}
+
+impl DefaultPoseidon2Instance<16> for Poseidon2BabyBearShort {
+    fn instance() -> Self {
+        poseidon2_instance_short()
+    }
+}
+
+impl DefaultPoseidon2Instance<24> for Poseidon2BabyBearLong {
+    fn instance() -> Self {
+        poseidon2_instance()
+    }
+}
+
+/// A lane where a Poseidon2 instance diverges from its reference digest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstanceMismatch {
+    /// The width of the diverging instance.
+    pub width: usize,
+    /// The first diverging lane.
+    pub lane: usize,
+    /// The reference value of the lane.
+    pub expected: BabyBear,
+    /// The value computed by the instance.
+    pub found: BabyBear,
+}
+
+impl fmt::Display for InstanceMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "width-{} Poseidon2 instance diverges at lane {}: expected {}, found {}",
+            self.width, self.lane, self.expected, self.found
+        )
+    }
+}
+
+impl std::error::Error for InstanceMismatch {}
+
+/// The state permuted by [`check_poseidon2_instance`]: lane `i` holds `i`.
+pub fn known_state<const WIDTH: usize>() -> [BabyBear; WIDTH] {
+    core::array::from_fn(|i| BabyBear::from_canonical_usize(i))
+}
+
+/// Permutes [`known_state`] with the default instance `I` and compares the output with
+/// `expected`.
+///
+/// The round constants go through a conversion from `zkhash`, so an upstream change or a bad
+/// conversion would silently change every hash. Services can run this check at startup against
+/// digests recorded from a known-good build; the first diverging lane is returned.
+pub fn check_poseidon2_instance<const WIDTH: usize, I>(
+    expected: &[BabyBear; WIDTH],
+) -> Result<(), InstanceMismatch>
+where
+    I: DefaultPoseidon2Instance<WIDTH>,
+{
+    let found = I::instance().permute(known_state());
+    match found.iter().zip(expected).position(|(found, expected)| found != expected) {
+        Some(lane) => Err(InstanceMismatch {
+            width: WIDTH,
+            lane,
+            expected: expected[lane],
+            found: found[lane],
+        }),
+        None => Ok(()),
+    }
+}
+
+/// A cached domain separator: the instance type, the output length and the parameters.
+type DomainSeparatorKey = (TypeId, usize, [usize; DOMAIN_PARAMETERS_LENGTH]);
+
+/// Domain separators computed so far, shared by all threads.
+static DOMAIN_SEPARATORS: OnceLock<RwLock<HashMap<DomainSeparatorKey, &'static [BabyBear]>>> =
+    OnceLock::new();
+
+/// Returns [`poseidon_safe_domain_separator`] of `params` under the default instance `I`,
+/// computing it on the first call only.
+///
+/// A scheme hashes with the same parameters every time, so the cache holds one entry per
+/// instantiation and its entries are never freed. Threads racing on the first call may each
+/// compute the separator, but all of them get the entry inserted first.
+pub fn cached_domain_separator<const OUT_LEN: usize, const WIDTH: usize, I>(
+    params: &[usize; DOMAIN_PARAMETERS_LENGTH],
+) -> Result<&'static [BabyBear; OUT_LEN], Poseidon2Error>
+where
+    I: DefaultPoseidon2Instance<WIDTH>,
+{
+    let as_array = |separator: &'static [BabyBear]| {
+        separator.try_into().expect("entries are keyed by their length")
+    };
+    let cache = DOMAIN_SEPARATORS.get_or_init(Default::default);
+    let key = (TypeId::of::<I>(), OUT_LEN, *params);
+
+    if let Some(&separator) = cache.read().unwrap_or_else(|err| err.into_inner()).get(&key) {
+        return Ok(as_array(separator));
+    }
+
+    // Errors are not cached, invalid parameters are recomputed and rejected on every call
+    let separator = poseidon_safe_domain_separator::<OUT_LEN, WIDTH, I>(&I::instance(), params)?;
+    let mut cache = cache.write().unwrap_or_else(|err| err.into_inner());
+    let separator = *cache.entry(key).or_insert_with(|| Box::leak(Box::new(separator)));
+    Ok(as_array(separator))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Barrier, thread};
+
+    #[test]
+    fn test_cached_domain_separator_matches_uncached() {
+        let params = [4, 3, 8, 2];
+        let cached = cached_domain_separator::<8, 24, Poseidon2BabyBearLong>(&params).unwrap();
+        assert_eq!(
+            Ok(*cached),
+            poseidon_safe_domain_separator::<8, 24, _>(&poseidon2_instance(), &params)
+        );
+
+        // The second call returns the cached entry
+        let again = cached_domain_separator::<8, 24, Poseidon2BabyBearLong>(&params).unwrap();
+        assert!(core::ptr::eq(cached, again));
+    }
+
+    #[test]
+    fn test_cached_instances_match_uncached() {
+        let state = known_state();
+        assert_eq!(
+            cached_poseidon2_instance_short().permute(state),
+            poseidon2_instance_short().permute(state)
+        );
+        let state = known_state();
+        assert_eq!(cached_poseidon2_instance().permute(state), poseidon2_instance().permute(state));
+
+        // Later calls return the instance built by the first one
+        assert!(core::ptr::eq(
+            cached_poseidon2_instance_short(),
+            cached_poseidon2_instance_short()
+        ));
+        assert!(core::ptr::eq(cached_poseidon2_instance(), cached_poseidon2_instance()));
+    }
+
+    #[test]
+    fn test_cached_domain_separator_keys() {
+        let params = [1, 2, 3, 4];
+        let long = cached_domain_separator::<8, 24, Poseidon2BabyBearLong>(&params).unwrap();
+        let short = cached_domain_separator::<8, 16, Poseidon2BabyBearShort>(&params).unwrap();
+        let longer = cached_domain_separator::<9, 24, Poseidon2BabyBearLong>(&params).unwrap();
+        let other = cached_domain_separator::<8, 24, Poseidon2BabyBearLong>(&[1, 2, 3, 5]).unwrap();
+
+        assert_ne!(long, short);
+        assert_eq!(long[..], longer[..8]);
+        assert_ne!(long, other);
+        assert_eq!(
+            Ok(*short),
+            poseidon_safe_domain_separator::<8, 16, _>(&poseidon2_instance_short(), &params)
+        );
+    }
+
+    #[test]
+    fn test_cached_domain_separator_rejects_invalid_parameters() {
+        let params = [1, 2, 3, 1 << 32];
+        for _ in 0..2 {
+            assert_eq!(
+                cached_domain_separator::<8, 24, Poseidon2BabyBearLong>(&params),
+                Err(Poseidon2Error::DomainParameterTooLarge { index: 3, value: 1 << 32 })
+            );
+        }
+    }
+
+    #[test]
+    fn test_cached_domain_separator_is_shared_between_threads() {
+        const THREADS: usize = 8;
+        // Parameters no other test uses, so that the threads race on the first computation
+        let params = [7, 3, 64, 8];
+        let barrier = Barrier::new(THREADS);
+
+        let separators: Vec<&'static [BabyBear; 8]> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..THREADS)
+                .map(|_| {
+                    scope.spawn(|| {
+                        barrier.wait();
+                        cached_domain_separator::<8, 24, Poseidon2BabyBearLong>(&params).unwrap()
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+
+        let expected =
+            poseidon_safe_domain_separator::<8, 24, _>(&poseidon2_instance(), &params).unwrap();
+        for &separator in &separators {
+            assert!(core::ptr::eq(separator, separators[0]));
+            assert_eq!(*separator, expected);
+        }
+    }
+
+    #[test]
+    fn test_check_poseidon2_instance_reports_the_diverging_lane() {
+        let digest_16 = poseidon2_instance_short().permute(known_state());
+        let digest_24 = poseidon2_instance().permute(known_state());
+        assert_eq!(check_poseidon2_instance::<16, Poseidon2BabyBearShort>(&digest_16), Ok(()));
+        assert_eq!(check_poseidon2_instance::<24, Poseidon2BabyBearLong>(&digest_24), Ok(()));
+
+        let mut corrupted = digest_24;
+        corrupted[5] += BabyBear::ONE;
+        corrupted[9] += BabyBear::ONE;
+        assert_eq!(
+            check_poseidon2_instance::<24, Poseidon2BabyBearLong>(&corrupted),
+            Err(InstanceMismatch {
+                width: 24,
+                lane: 5,
+                expected: corrupted[5],
+                found: digest_24[5]
+            })
+        );
+
+        // The digests of the two widths are not interchangeable
+        let mut prefix = [BabyBear::ZERO; 16];
+        prefix.copy_from_slice(&digest_24[..16]);
+        assert!(check_poseidon2_instance::<16, Poseidon2BabyBearShort>(&prefix).is_err());
+    }
+}
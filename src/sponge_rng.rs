@@ -0,0 +1,249 @@
+//! A deterministic random number generator squeezing a Poseidon2 sponge.
+//!
+//! [`SpongeRng`] needs no operating system randomness, so guests and test-vector generators
+//! derive the same stream from the same seed on every platform.
+
+use crate::poseidon2::{
+    byte_length_element, pack_bytes, permute, Poseidon2BabyBearValidWidth, BYTES_PER_FIELD_ELEMENT,
+};
+use p3_baby_bear::BabyBear;
+use p3_field::{FieldAlgebra, PrimeField32};
+use rand_core::{impls, CryptoRng, Error, RngCore};
+
+/// The number of capacity lanes of the sponge of a [`SpongeRng`].
+pub const SPONGE_RNG_CAPACITY: usize = 8;
+
+/// Squeezed elements at or above this bound are rejected.
+///
+/// It is `120 * 2^24`, so the low 24 bits of the accepted elements are uniform.
+const ACCEPT_BOUND: u32 = BabyBear::ORDER_U32 - 1;
+
+/// The capacity tag of a sponge seeded with field elements.
+const FIELD_SEED_TAG: u32 = 1;
+
+/// The capacity tag of a sponge seeded with bytes.
+const BYTE_SEED_TAG: u32 = 2;
+
+/// A random number generator over the Poseidon2 sponge of the instance `I`.
+///
+/// The seed is absorbed with `10*` padding like in
+/// [`poseidon2_sponge`](crate::poseidon2::poseidon2_sponge), into a state whose first capacity
+/// lane tells field seeds from byte seeds. The stream is then squeezed from the rate lanes,
+/// permuting between blocks. Every squeezed element below `120 * 2^24` gives the three bytes of
+/// its low 24 bits, little-endian; the other elements are skipped, so the bytes are uniform.
+#[derive(Debug, Clone)]
+pub struct SpongeRng<I, const WIDTH: usize> {
+    poseidon_instance: I,
+    state: [BabyBear; WIDTH],
+    /// The next rate lane to read bytes from.
+    lane: usize,
+    /// The bytes of the last accepted element not returned yet, last byte first.
+    pending: [u8; BYTES_PER_FIELD_ELEMENT],
+    pending_len: usize,
+}
+
+impl<I, const WIDTH: usize> SpongeRng<I, WIDTH>
+where
+    I: Poseidon2BabyBearValidWidth<WIDTH>,
+{
+    /// The number of lanes the seed is absorbed into and the stream is squeezed from.
+    const RATE: usize = {
+        assert!(SPONGE_RNG_CAPACITY < WIDTH, "the sponge needs at least one rate lane");
+        WIDTH - SPONGE_RNG_CAPACITY
+    };
+
+    /// Creates a generator seeded with field elements.
+    pub fn from_field_seed<const N: usize>(poseidon_instance: I, seed: &[BabyBear; N]) -> Self {
+        Self::absorb(poseidon_instance, FIELD_SEED_TAG, seed.iter().copied())
+    }
+
+    /// Creates a generator seeded with bytes, packed like
+    /// [`bytes_to_field_elements`](crate::poseidon2::bytes_to_field_elements).
+    pub fn from_byte_seed(poseidon_instance: I, seed: &[u8; 32]) -> Self {
+        let elements = core::iter::once(byte_length_element(seed.len()))
+            .chain(seed.chunks(BYTES_PER_FIELD_ELEMENT).map(pack_bytes));
+        Self::absorb(poseidon_instance, BYTE_SEED_TAG, elements)
+    }
+
+    fn absorb(poseidon_instance: I, tag: u32, seed: impl Iterator<Item = BabyBear>) -> Self {
+        let mut state = [BabyBear::ZERO; WIDTH];
+        state[Self::RATE] = BabyBear::new(tag);
+
+        let mut absorbed = 0;
+        for element in seed {
+            state[absorbed] += element;
+            absorbed += 1;
+            if absorbed == Self::RATE {
+                permute(&poseidon_instance, &mut state);
+                absorbed = 0;
+            }
+        }
+        state[absorbed] += BabyBear::ONE;
+        permute(&poseidon_instance, &mut state);
+
+        Self {
+            poseidon_instance,
+            state,
+            lane: 0,
+            pending: [0; BYTES_PER_FIELD_ELEMENT],
+            pending_len: 0,
+        }
+    }
+
+    /// Returns the next accepted squeezed element.
+    fn next_element(&mut self) -> u32 {
+        loop {
+            if self.lane == Self::RATE {
+                permute(&self.poseidon_instance, &mut self.state);
+                self.lane = 0;
+            }
+            let element = self.state[self.lane].as_canonical_u32();
+            self.lane += 1;
+            if element < ACCEPT_BOUND {
+                return element;
+            }
+        }
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        if self.pending_len == 0 {
+            let [b0, b1, b2, _] = self.next_element().to_le_bytes();
+            self.pending = [b2, b1, b0];
+            self.pending_len = BYTES_PER_FIELD_ELEMENT;
+        }
+        self.pending_len -= 1;
+        self.pending[self.pending_len]
+    }
+}
+
+impl<I, const WIDTH: usize> RngCore for SpongeRng<I, WIDTH>
+where
+    I: Poseidon2BabyBearValidWidth<WIDTH>,
+{
+    fn next_u32(&mut self) -> u32 {
+        impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        impls::next_u64_via_fill(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        dest.iter_mut().for_each(|byte| *byte = self.next_byte());
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl<I, const WIDTH: usize> CryptoRng for SpongeRng<I, WIDTH> where
+    I: Poseidon2BabyBearValidWidth<WIDTH>
+{
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::poseidon2_config::{poseidon2_instance, poseidon2_instance_short};
+
+    /// The first `blocks` rate blocks of a field-seeded generator, computed from the
+    /// permutation directly.
+    fn expected_blocks(seed: &[BabyBear; 4], blocks: usize) -> Vec<BabyBear> {
+        let instance = poseidon2_instance();
+        let mut state = [BabyBear::ZERO; 24];
+        state[..4].copy_from_slice(seed);
+        state[4] = BabyBear::ONE;
+        state[16] = BabyBear::new(FIELD_SEED_TAG);
+
+        let mut out = Vec::new();
+        for _ in 0..blocks {
+            instance.permute_mut(&mut state);
+            out.extend_from_slice(&state[..16]);
+        }
+        out
+    }
+
+    fn bytes_of(elements: &[BabyBear]) -> Vec<u8> {
+        elements
+            .iter()
+            .map(PrimeField32::as_canonical_u32)
+            .filter(|&element| element < ACCEPT_BOUND)
+            .flat_map(|element| element.to_le_bytes().into_iter().take(3))
+            .collect()
+    }
+
+    #[test]
+    fn test_first_64_bytes_follow_the_sponge() {
+        let seed = [1, 2, 3, 4].map(BabyBear::new);
+        let mut rng = SpongeRng::from_field_seed(poseidon2_instance(), &seed);
+
+        let mut bytes = [0; 64];
+        rng.fill_bytes(&mut bytes);
+        assert_eq!(bytes[..], bytes_of(&expected_blocks(&seed, 2))[..64]);
+    }
+
+    #[test]
+    fn test_fill_bytes_does_not_depend_on_buffer_sizes() {
+        let seed = [5, 6, 7, 8].map(BabyBear::new);
+        let mut rng = SpongeRng::from_field_seed(poseidon2_instance(), &seed);
+        let mut expected = [0; 400];
+        rng.fill_bytes(&mut expected);
+
+        for sizes in [&[1][..], &[2, 5], &[3, 7, 11], &[47, 1, 64, 2], &[399, 1]] {
+            let mut rng = SpongeRng::from_field_seed(poseidon2_instance(), &seed);
+            let mut stream = Vec::new();
+            for &size in sizes.iter().cycle() {
+                if stream.len() >= expected.len() {
+                    break;
+                }
+                let mut buffer = vec![0; size];
+                rng.fill_bytes(&mut buffer);
+                stream.extend_from_slice(&buffer);
+            }
+            assert_eq!(stream[..400], expected, "{sizes:?}");
+        }
+    }
+
+    #[test]
+    fn test_integers_are_little_endian_bytes() {
+        let seed = [9u8; 32];
+        let mut bytes = SpongeRng::from_byte_seed(poseidon2_instance_short(), &seed);
+        let mut ints = bytes.clone();
+
+        let mut buffer = [0; 12];
+        bytes.fill_bytes(&mut buffer);
+        assert_eq!(ints.next_u32().to_le_bytes(), buffer[..4]);
+        assert_eq!(ints.next_u64().to_le_bytes(), buffer[4..]);
+    }
+
+    #[test]
+    fn test_seeds_separate_streams() {
+        let stream = |mut rng: SpongeRng<_, 24>| {
+            let mut bytes = [0; 32];
+            rng.fill_bytes(&mut bytes);
+            bytes
+        };
+        let field = |seed: [u32; 4]| {
+            stream(SpongeRng::from_field_seed(poseidon2_instance(), &seed.map(BabyBear::new)))
+        };
+
+        assert_eq!(field([1, 2, 3, 4]), field([1, 2, 3, 4]));
+        assert_ne!(field([1, 2, 3, 4]), field([1, 2, 3, 5]));
+        assert_ne!(
+            stream(SpongeRng::from_byte_seed(poseidon2_instance(), &[0; 32])),
+            stream(SpongeRng::from_byte_seed(poseidon2_instance(), &[1; 32]))
+        );
+
+        // A byte seed and the field seed of its packing do not share a stream
+        let packed: [BabyBear; 12] = core::array::from_fn(|i| match i {
+            0 => BabyBear::new(32),
+            _ => BabyBear::ZERO,
+        });
+        assert_ne!(
+            stream(SpongeRng::from_byte_seed(poseidon2_instance(), &[0; 32])),
+            stream(SpongeRng::from_field_seed(poseidon2_instance(), &packed))
+        );
+    }
+}
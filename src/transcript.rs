@@ -0,0 +1,205 @@
+use crate::tweak::PoseidonTweak;
+use core::panic::Location;
+use p3_baby_bear::BabyBear;
+
+/// A single call through the tweak hash layer.
+#[derive(Debug, Clone)]
+pub struct HashCall {
+    /// Position of the call in the transcript.
+    pub seq: usize,
+    /// The tweak used for this call.
+    pub tweak: PoseidonTweak,
+    /// The full permutation input: parameter, tweak and message elements.
+    pub input: Vec<BabyBear>,
+    /// The hash output.
+    pub output: Vec<BabyBear>,
+    /// Where the hash was requested from.
+    pub location: &'static Location<'static>,
+}
+
+impl HashCall {
+    /// Two calls match when they hash the same tweak and input to the same output.
+    ///
+    /// Call-site metadata is deliberately ignored so that transcripts recorded by different
+    /// implementations (e.g. native and circuit witness generation) can be compared.
+    fn matches(&self, other: &Self) -> bool {
+        self.tweak == other.tweak && self.input == other.input && self.output == other.output
+    }
+}
+
+/// The first point at which two transcripts disagree.
+#[derive(Debug, Clone)]
+pub struct FirstDivergence {
+    /// Sequence number of the first mismatching call.
+    pub seq: usize,
+    /// The call recorded by the left transcript, if it has one at `seq`.
+    pub left: Option<HashCall>,
+    /// The call recorded by the right transcript, if it has one at `seq`.
+    pub right: Option<HashCall>,
+}
+
+/// An ordered record of every tweak hash evaluated through a [`HashCtx`].
+#[derive(Debug, Clone, Default)]
+pub struct HashTranscript {
+    calls: Vec<HashCall>,
+}
+
+impl HashTranscript {
+    /// Appends a call to the transcript.
+    pub fn record(
+        &mut self,
+        tweak: PoseidonTweak,
+        input: Vec<BabyBear>,
+        output: Vec<BabyBear>,
+        location: &'static Location<'static>,
+    ) {
+        let seq = self.calls.len();
+        self.calls.push(HashCall { seq, tweak, input, output, location });
+    }
+
+    /// Returns the recorded calls in order.
+    pub fn calls(&self) -> &[HashCall] {
+        &self.calls
+    }
+
+    /// Returns the earliest call at which `self` and `other` disagree.
+    ///
+    /// A transcript that is a strict prefix of the other diverges at the first missing call.
+    pub fn diff(&self, other: &Self) -> Option<FirstDivergence> {
+        let len = self.calls.len().max(other.calls.len());
+        (0..len).find_map(|seq| {
+            let left = self.calls.get(seq);
+            let right = other.calls.get(seq);
+            match (left, right) {
+                (Some(l), Some(r)) if l.matches(r) => None,
+                _ => Some(FirstDivergence { seq, left: left.cloned(), right: right.cloned() }),
+            }
+        })
+    }
+}
+
+/// Hashing context threaded through tweak hash evaluations.
+///
+/// Recording is opt-in: a default context evaluates hashes without keeping anything.
+#[derive(Debug, Clone, Default)]
+pub struct HashCtx {
+    transcript: Option<HashTranscript>,
+}
+
+impl HashCtx {
+    /// Creates a context that records every hash into a [`HashTranscript`].
+    pub fn recording() -> Self {
+        Self { transcript: Some(HashTranscript::default()) }
+    }
+
+    /// Returns the transcript recorded so far, if recording is enabled.
+    pub const fn transcript(&self) -> Option<&HashTranscript> {
+        self.transcript.as_ref()
+    }
+
+    /// Consumes the context and returns its transcript, if recording is enabled.
+    pub fn into_transcript(self) -> Option<HashTranscript> {
+        self.transcript
+    }
+
+    /// Records a call if recording is enabled.
+    pub(crate) fn record(
+        &mut self,
+        tweak: PoseidonTweak,
+        input: Vec<BabyBear>,
+        output: &[BabyBear],
+        location: &'static Location<'static>,
+    ) {
+        if let Some(transcript) = &mut self.transcript {
+            transcript.record(tweak, input, output.to_vec(), location);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tweak::TreeTweak, tweak_hash::PoseidonTweakHash};
+    use p3_field::FieldAlgebra;
+
+    type TestHash = PoseidonTweakHash<3, 2, 2, 4, 4, 3, 8, 2>;
+
+    /// Recomputes the root from leaf 5 of a depth-3 tree, recording every node hash.
+    fn merkle_path_transcript(
+        leaf: [BabyBear; 4],
+        siblings: &[[BabyBear; 4]; 3],
+    ) -> HashTranscript {
+        let parameter = [BabyBear::ONE; 4];
+        let mut ctx = HashCtx::recording();
+
+        let mut node = leaf;
+        let mut position = 5u64;
+        for (level, sibling) in (1..).zip(siblings) {
+            let (left, right) = if position % 2 == 0 { (node, *sibling) } else { (*sibling, node) };
+            position /= 2;
+            let tweak = PoseidonTweak::Tree(TreeTweak::new_unchecked(level, position));
+            node = TestHash::new(parameter, tweak, vec![left, right]).apply_with_ctx(&mut ctx);
+        }
+
+        ctx.into_transcript().unwrap()
+    }
+
+    fn siblings() -> [[BabyBear; 4]; 3] {
+        core::array::from_fn(|i| core::array::from_fn(|j| BabyBear::new((10 * i + j) as u32)))
+    }
+
+    #[test]
+    fn test_identical_transcripts_do_not_diverge() {
+        let leaf = [BabyBear::new(7); 4];
+        let a = merkle_path_transcript(leaf, &siblings());
+        let b = merkle_path_transcript(leaf, &siblings());
+
+        assert_eq!(a.calls().len(), 3);
+        assert!(a.diff(&b).is_none());
+    }
+
+    #[test]
+    fn test_diff_pinpoints_corrupted_sibling() {
+        let leaf = [BabyBear::new(7); 4];
+        let honest = merkle_path_transcript(leaf, &siblings());
+
+        let mut corrupted_siblings = siblings();
+        corrupted_siblings[1][2] += BabyBear::ONE;
+        let corrupted = merkle_path_transcript(leaf, &corrupted_siblings);
+
+        // The leaf's parent is hashed identically, the level-2 node is the first to differ
+        let divergence = honest.diff(&corrupted).unwrap();
+        assert_eq!(divergence.seq, 1);
+
+        let left = divergence.left.unwrap();
+        let right = divergence.right.unwrap();
+        assert_eq!(left.tweak, PoseidonTweak::Tree(TreeTweak::new_unchecked(2, 1)));
+        assert_eq!(left.tweak, right.tweak);
+        assert_ne!(left.input, right.input);
+        assert_eq!(left.location.file(), file!());
+    }
+
+    #[test]
+    fn test_diff_reports_missing_calls() {
+        let leaf = [BabyBear::new(7); 4];
+        let full = merkle_path_transcript(leaf, &siblings());
+
+        let mut truncated = full.clone();
+        truncated.calls.pop();
+
+        let divergence = full.diff(&truncated).unwrap();
+        assert_eq!(divergence.seq, 2);
+        assert!(divergence.left.is_some());
+        assert!(divergence.right.is_none());
+    }
+
+    #[test]
+    fn test_default_ctx_does_not_record() {
+        let mut ctx = HashCtx::default();
+        let tweak = PoseidonTweak::Tree(TreeTweak::new_unchecked(1, 0));
+        TestHash::new([BabyBear::ONE; 4], tweak, vec![[BabyBear::ZERO; 4]; 2])
+            .apply_with_ctx(&mut ctx);
+
+        assert!(ctx.transcript().is_none());
+    }
+}
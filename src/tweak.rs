@@ -1,179 +1,2766 @@
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::{fmt, iter::FusedIterator};
 use p3_baby_bear::{BabyBear, BabyBearParameters};
-use p3_field::FieldAlgebra;
+use p3_field::PrimeField32;
 use p3_monty_31::MontyParameters;
 
+#[cfg(feature = "proptest")]
+pub mod arbitrary;
+#[cfg(feature = "std")]
+pub mod audit;
+#[cfg(feature = "borsh")]
+mod borsh_encoding;
+#[cfg(feature = "serde")]
+mod serialization;
+mod text;
+
+/// The hash a tweak is used for, encoded as the separator byte of the packed tweak.
+///
+/// The separator is the least significant byte of every packed tweak, so tweaks of different
+/// domains never share an encoding. The values are part of the encoding and must never change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum TweakDomain {
+    /// Hashes along the hash chains.
+    Chain = 0x00,
+    /// Hashes of the Merkle tree nodes.
+    Tree = 0x01,
+    /// Hashes of the message.
+    MessageHash = 0x02,
+}
+
+impl TweakDomain {
+    /// Every domain, in separator order.
+    pub const ALL: [Self; 3] = [Self::Chain, Self::Tree, Self::MessageHash];
+
+    /// Returns the separator byte of the domain.
+    pub const fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    /// Returns the domain with the separator byte `separator`, if any.
+    pub const fn from_u8(separator: u8) -> Option<Self> {
+        match separator {
+            0x00 => Some(Self::Chain),
+            0x01 => Some(Self::Tree),
+            0x02 => Some(Self::MessageHash),
+            _ => None,
+        }
+    }
+
+    /// Returns `chain`, `tree` or `message_hash` depending on the domain.
+    ///
+    /// Unlike a `match` with a wildcard arm, this does not compile anymore once a domain is
+    /// added, so every caller has to decide how to handle the new domain.
+    pub fn select<T>(self, chain: T, tree: T, message_hash: T) -> T {
+        match self {
+            Self::Chain => chain,
+            Self::Tree => tree,
+            Self::MessageHash => message_hash,
+        }
+    }
+}
+
 /// The separator for message hash tweaks.
-pub const TWEAK_SEPARATOR_FOR_MESSAGE_HASH: u8 = 0x02;
+#[deprecated(note = "use `TweakDomain::MessageHash.as_u8()`")]
+pub const TWEAK_SEPARATOR_FOR_MESSAGE_HASH: u8 = TweakDomain::MessageHash.as_u8();
 /// The separator for tree hash tweaks.
-pub const TWEAK_SEPARATOR_FOR_TREE_HASH: u8 = 0x01;
+#[deprecated(note = "use `TweakDomain::Tree.as_u8()`")]
+pub const TWEAK_SEPARATOR_FOR_TREE_HASH: u8 = TweakDomain::Tree.as_u8();
 /// The separator for chain hash tweaks.
-pub const TWEAK_SEPARATOR_FOR_CHAIN_HASH: u8 = 0x00;
+#[deprecated(note = "use `TweakDomain::Chain.as_u8()`")]
+pub const TWEAK_SEPARATOR_FOR_CHAIN_HASH: u8 = TweakDomain::Chain.as_u8();
+
+/// Errors returned when building a tweak from out-of-range components, or encoding a tweak in a
+/// form that cannot tell it apart from another one.
+///
+/// Out-of-range components must be rejected rather than encoded: the packed layout gives each
+/// component a fixed bit range, so a value that overflows its range would alias another tweak.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TweakError {
+    /// The tree level is above the root of a tree of height `log_lifetime`.
+    LevelOutOfRange { level: u8, log_lifetime: usize },
+    /// There is no node at `position` on `level` of a tree of height `log_lifetime`.
+    TreePositionOutOfRange { level: u8, position: u64, log_lifetime: usize },
+    /// The epoch does not fit in a lifetime of `2^log_lifetime` epochs.
+    EpochOutOfRange { epoch: u32, log_lifetime: usize },
+    /// The chain index does not fit in `2^ceil_log_num_chains` chains.
+    ChainIndexOutOfRange { chain_index: u32, ceil_log_num_chains: usize },
+    /// The position does not fit in a chain of length `2^chunk_size`.
+    ChainPositionOutOfRange { position: u32, chunk_size: usize },
+    /// Advancing `steps` positions from `position` goes past the last position of the chain.
+    ChainAdvanceOutOfRange { position: u16, steps: u16, max_position: u16 },
+    /// The packed tweak needs `required` limbs but only `provided` are available.
+    Overflow { required: usize, provided: usize },
+    /// The tree tweak is in the [legacy layout](TreeTweakLayout::Legacy), which has no byte
+    /// encoding.
+    LegacyLayout,
+}
+
+impl fmt::Display for TweakError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LevelOutOfRange { level, log_lifetime } => {
+                write!(f, "tree level {level} is above the root of a tree of height {log_lifetime}")
+            }
+            Self::TreePositionOutOfRange { level, position, log_lifetime } => write!(
+                f,
+                "tree position {position} does not exist on level {level} of a tree of height \
+                 {log_lifetime}"
+            ),
+            Self::EpochOutOfRange { epoch, log_lifetime } => {
+                write!(f, "epoch {epoch} is out of range for a lifetime of 2^{log_lifetime}")
+            }
+            Self::ChainIndexOutOfRange { chain_index, ceil_log_num_chains } => write!(
+                f,
+                "chain index {chain_index} is out of range for 2^{ceil_log_num_chains} chains"
+            ),
+            Self::ChainPositionOutOfRange { position, chunk_size } => write!(
+                f,
+                "chain position {position} is out of range for chains of length 2^{chunk_size}"
+            ),
+            Self::ChainAdvanceOutOfRange { position, steps, max_position } => write!(
+                f,
+                "advancing chain position {position} by {steps} goes past the last position \
+                 {max_position}"
+            ),
+            Self::Overflow { required, provided } => {
+                write!(f, "tweak needs {required} limbs but only {provided} are available")
+            }
+            Self::LegacyLayout => write!(f, "legacy tree tweaks have no byte encoding"),
+        }
+    }
+}
+
+impl core::error::Error for TweakError {}
+
+/// Errors returned when decoding a tweak from its field-element encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TweakDecodeError {
+    /// The separator byte does not match the tweak type being decoded.
+    WrongSeparator { expected: u8, found: u8 },
+    /// The separator byte does not belong to any tweak type.
+    UnknownSeparator(u8),
+    /// The limbs encode an integer that does not fit the tweak's packed layout.
+    Overflow,
+    /// The byte encoding does not have the length of the tweak type.
+    InvalidLength { expected: usize, found: usize },
+}
+
+impl fmt::Display for TweakDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WrongSeparator { expected, found } => {
+                write!(f, "expected tweak separator {expected:#04x}, found {found:#04x}")
+            }
+            Self::UnknownSeparator(separator) => {
+                write!(f, "unknown tweak separator {separator:#04x}")
+            }
+            Self::Overflow => write!(f, "encoded tweak overflows the packed tweak layout"),
+            Self::InvalidLength { expected, found } => {
+                write!(f, "expected {expected} tweak bytes, found {found}")
+            }
+        }
+    }
+}
+
+impl core::error::Error for TweakDecodeError {}
+
+/// Errors returned when parsing a tweak from its text form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TweakParseError {
+    /// The string is neither `kind(field=value, ...)` nor a `0x`-prefixed hex integer.
+    Malformed,
+    /// The tweak kind is unknown, or not the one of the type being parsed.
+    UnexpectedKind,
+    /// The field with this name is missing or out of order.
+    ExpectedField(&'static str),
+    /// There are more fields than the tweak kind has.
+    TrailingField,
+    /// The value of the field with this name is not a number of the field's width.
+    InvalidNumber(&'static str),
+    /// The hex form has non-hex digits or does not fit in a packed tweak integer.
+    InvalidHex,
+    /// The hex form is not the packed integer of a tweak of the type being parsed.
+    Decode(TweakDecodeError),
+    /// The components are out of range, as for [`TreeTweak::new`].
+    OutOfRange(TweakError),
+}
+
+impl fmt::Display for TweakParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed => {
+                write!(f, "expected a tweak as `kind(field=value, ...)` or a `0x` hex integer")
+            }
+            Self::UnexpectedKind => write!(f, "unexpected tweak kind"),
+            Self::ExpectedField(name) => write!(f, "expected tweak field `{name}`"),
+            Self::TrailingField => write!(f, "unexpected trailing tweak field"),
+            Self::InvalidNumber(name) => write!(f, "invalid value for tweak field `{name}`"),
+            Self::InvalidHex => write!(f, "invalid packed tweak hex integer"),
+            Self::Decode(err) => write!(f, "invalid packed tweak: {err}"),
+            Self::OutOfRange(err) => write!(f, "invalid tweak: {err}"),
+        }
+    }
+}
+
+impl core::error::Error for TweakParseError {}
+
+impl From<TweakDecodeError> for TweakParseError {
+    fn from(err: TweakDecodeError) -> Self {
+        Self::Decode(err)
+    }
+}
+
+impl From<TweakError> for TweakParseError {
+    fn from(err: TweakError) -> Self {
+        Self::OutOfRange(err)
+    }
+}
+
+/// The height of the largest tree whose positions fit in a [`TreeTweak`].
+pub const MAX_TREE_HEIGHT: usize = u64::BITS as usize;
+
+/// Returns whether `value < 2^bits`.
+const fn fits_in_bits(value: u64, bits: usize) -> bool {
+    bits >= u64::BITS as usize || value >> bits == 0
+}
+
+/// Recombines base-p limbs (least significant first) into the packed tweak integer.
+fn packed_from_limbs<F: PrimeField32, const N: usize>(
+    elements: &[F; N],
+) -> Result<u128, TweakDecodeError> {
+    let prime = F::ORDER_U32 as u128;
+    elements.iter().rev().try_fold(0u128, |acc, limb| {
+        acc.checked_mul(prime)
+            .and_then(|acc| acc.checked_add(limb.as_canonical_u32() as u128))
+            .ok_or(TweakDecodeError::Overflow)
+    })
+}
+
+/// Splits a packed tweak integer into `N` BabyBear limbs, least significant first.
+///
+/// This is the limb splitting of [`TweakEncoding::to_field_elements`]: for any tweak `t` with
+/// a `packed` method, `limbs_from_packed(t.packed().into()) == t.to_field_elements()`. Limbs
+/// beyond `N` are dropped, so `N` must be at least [`min_tweak_len`] of the packed width.
+pub fn limbs_from_packed<const N: usize>(packed: u128) -> [BabyBear; N] {
+    limbs_from_packed_over(packed)
+}
+
+/// Splits the packed tweak integer into `N` base-p limbs of `F`, least significant first.
+///
+/// Every limb is computed, including the zero limbs of small tweaks, so the number of divisions
+/// does not depend on the tweak.
+fn limbs_from_packed_over<F: PrimeField32, const N: usize>(mut packed: u128) -> [F; N] {
+    // Prime modulus used to map the tweak into the finite field `F`
+    let prime = F::ORDER_U32 as u128;
+
+    // Extract field elements by dividing tweak into chunks mod `prime`
+    let mut result = [F::ZERO; N];
+    for r in result.iter_mut() {
+        *r = F::from_canonical_u32((packed % prime) as u32);
+        packed /= prime;
+    }
+
+    result
+}
+
+/// The number of BabyBear limbs able to hold any packed tweak integer.
+const MAX_TWEAK_LEN: usize = min_tweak_len(u128::BITS);
+
+/// Splits the packed tweak integer into exactly `tweak_len` BabyBear limbs, least significant
+/// first, rejecting integers that need more limbs instead of truncating them.
+#[cfg(feature = "alloc")]
+fn try_limbs_from_packed(packed: u128, tweak_len: usize) -> Result<Vec<BabyBear>, TweakError> {
+    let limbs: [BabyBear; MAX_TWEAK_LEN] = limbs_from_packed(packed);
+
+    let required = limbs.iter().rposition(|limb| limb.as_canonical_u32() != 0).map_or(0, |i| i + 1);
+    if required > tweak_len {
+        return Err(TweakError::Overflow { required, provided: tweak_len });
+    }
+
+    let mut elements = limbs.to_vec();
+    elements.resize(tweak_len, BabyBear::new(0));
+    Ok(elements)
+}
+
+/// Returns the encoding of the packed tweak `base + delta` from the encoding of `base`.
+///
+/// This propagates the carries of `delta` through the limbs instead of splitting the whole
+/// packed integer again, which saves most of the divisions when encoding tweaks in bulk.
+fn offset_limbs<const N: usize>(base: &[BabyBear; N], delta: u128) -> [BabyBear; N] {
+    let prime = BabyBearParameters::PRIME as u128;
+
+    let mut carry = delta;
+    base.map(|limb| {
+        let sum = limb.as_canonical_u32() as u128 + carry;
+        carry = sum / prime;
+        BabyBear::new((sum % prime) as u32)
+    })
+}
+
+/// Checks that the separator byte `found` of an encoded tweak is the `expected` one.
+const fn check_separator(found: u8, expected: TweakDomain) -> Result<(), TweakDecodeError> {
+    if found == expected.as_u8() {
+        Ok(())
+    } else {
+        Err(TweakDecodeError::WrongSeparator { expected: expected.as_u8(), found })
+    }
+}
+
+/// The number of bits of a packed tree tweak (level, position, separator).
+pub const TREE_TWEAK_BITS: u32 = 80;
+/// The number of bits of a packed tree tweak in the [legacy layout](TreeTweakLayout::Legacy).
+pub const LEGACY_TREE_TWEAK_BITS: u32 = 48;
+/// The number of bits of a packed chain tweak (epoch, chain index, position, separator).
+pub const CHAIN_TWEAK_BITS: u32 = 72;
+/// The number of bits of a packed [`WideChainTweak`] (epoch, chain index, position, separator).
+pub const WIDE_CHAIN_TWEAK_BITS: u32 = 104;
+/// The number of bits of a packed message tweak (epoch, separator).
+pub const MESSAGE_TWEAK_BITS: u32 = 40;
+
+/// Returns the minimum number of BabyBear limbs able to hold any integer of `bits` bits.
+///
+/// See [`min_tweak_len_for_prime`] for other fields.
+pub const fn min_tweak_len(bits: u32) -> usize {
+    min_tweak_len_for_prime(bits, BabyBearParameters::PRIME)
+}
+
+/// Returns the minimum number of base-`prime` limbs able to hold any integer of `bits` bits.
+///
+/// This is `ceil(bits / log2(p))`, computed exactly as the smallest `n` with `p^n >= 2^bits`.
+pub const fn min_tweak_len_for_prime(bits: u32, prime: u32) -> usize {
+    let prime = prime as u128;
+
+    let mut len = 0;
+    let mut capacity = 1u128;
+    loop {
+        // `capacity = p^len` covers every `bits`-bit integer once it reaches `2^bits`
+        if bits < u128::BITS && capacity >> bits != 0 {
+            return len;
+        }
+        len += 1;
+        match capacity.checked_mul(prime) {
+            Some(next) => capacity = next,
+            // `p^len` exceeds `2^128`, which covers any packed tweak
+            None => return len,
+        }
+    }
+}
+
+/// The number of field elements every tweak is encoded into.
+///
+/// The length is fixed for the whole scheme rather than chosen per call site: tweaks are only
+/// domain separated if every component hashes them in the same encoding. It is the smallest
+/// length that holds a tree tweak, the widest of the three, and the smallest tweak length a
+/// `PoseidonTweakHash` accepts.
+pub const TWEAK_LEN: usize = min_tweak_len(TREE_TWEAK_BITS);
+
+/// The number of field elements a [`WideChainTweak`] is encoded into.
+pub const WIDE_CHAIN_TWEAK_LEN: usize = min_tweak_len(WIDE_CHAIN_TWEAK_BITS);
+
+/// The number of bits of the key identifier folded into keyed tweaks.
+pub const KEY_ID_BITS: u32 = u32::BITS;
+/// The number of bits of the widest packed keyed tweak (key id above a tree tweak).
+pub const KEYED_TWEAK_BITS: u32 = TREE_TWEAK_BITS + KEY_ID_BITS;
+
+/// The number of field elements keyed tweaks are encoded into.
+///
+/// It is the smallest length that holds a keyed tree tweak under every [`TweakLimbEncoding`],
+/// so base-p encodings of keyed tweaks end with a zero limb.
+pub const KEYED_TWEAK_LEN: usize = {
+    let base_p = min_tweak_len(KEYED_TWEAK_BITS);
+    let bytes = (KEYED_TWEAK_BITS as usize).div_ceil(8 * BytesPerElement::BYTES);
+    if base_p > bytes {
+        base_p
+    } else {
+        bytes
+    }
+};
+
+/// A trait for converting tweaks into field elements for Poseidon2 hashing.
+///
+/// The tweak is packed into an integer and split into [`TWEAK_LEN`] base-p limbs of a prime field:
+/// [`to_field_elements`](Self::to_field_elements) encodes into [`BabyBear`], the field of the
+/// scheme, and [`to_field_elements_over`](Self::to_field_elements_over) into any other
+/// [`PrimeField32`], e.g. KoalaBear.
+///
+/// The length of the encoding is not a choice of the caller, so two components cannot disagree
+/// on it:
+///
+/// ```compile_fail
+/// use openvm_sig_agg::tweak::{ChainTweak, TweakEncoding};
+/// use p3_baby_bear::BabyBear;
+///
+/// let _: [BabyBear; 6] = ChainTweak::new_unchecked(1, 2, 3).to_field_elements();
+/// ```
+pub trait TweakEncoding {
+    /// Converts the tweak into field elements for Poseidon2 hashing.
+    ///
+    /// In Poseidon2, tweaks need to be encoded as elements of a finite field.
+    fn to_field_elements(&self) -> [BabyBear; TWEAK_LEN] {
+        self.to_field_elements_over()
+    }
+
+    /// Converts the tweak into elements of the prime field `F`.
+    ///
+    /// The limbs are taken modulo the order of `F`, so the encoding depends on the field. `F`
+    /// must be large enough for [`TWEAK_LEN`] limbs to hold the tweak, which is checked at compile
+    /// time against [`min_tweak_len_for_prime`].
+    fn to_field_elements_over<F: PrimeField32>(&self) -> [F; TWEAK_LEN];
+}
+
+/// A strategy for spreading a packed tweak integer over [`TWEAK_LEN`] BabyBear elements.
+///
+/// The packed integer is the one returned by the `packed` method of every tweak type, so two
+/// strategies only differ in how it is split into limbs. [`BasePLimbs`] is the encoding of
+/// [`TweakEncoding::to_field_elements`]; [`BytesPerElement`] is the one of implementations that
+/// spread the tweak bytes over the field elements instead.
+pub trait TweakLimbEncoding {
+    /// Splits the packed tweak integer into field elements.
+    ///
+    /// Returns [`TweakError::Overflow`] if the packed integer does not fit in [`TWEAK_LEN`]
+    /// elements of the encoding.
+    fn encode(packed: u128) -> Result<[BabyBear; TWEAK_LEN], TweakError>;
+
+    /// Splits the packed integer of a keyed tweak, as returned by `packed_with_key_id`, into
+    /// field elements.
+    fn encode_keyed(packed: u128) -> [BabyBear; KEYED_TWEAK_LEN];
+}
+
+/// Base-p limbs of the packed tweak integer, least significant first.
+///
+/// This is the encoding of [`TweakEncoding::to_field_elements`] and [`limbs_from_packed`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BasePLimbs;
+
+impl TweakLimbEncoding for BasePLimbs {
+    fn encode(packed: u128) -> Result<[BabyBear; TWEAK_LEN], TweakError> {
+        Ok(limbs_from_packed(packed))
+    }
+
+    fn encode_keyed(packed: u128) -> [BabyBear; KEYED_TWEAK_LEN] {
+        const {
+            assert!(
+                KEYED_TWEAK_LEN >= min_tweak_len(KEYED_TWEAK_BITS),
+                "KEYED_TWEAK_LEN limbs are too small to hold a keyed tree tweak"
+            );
+        };
+
+        limbs_from_packed(packed)
+    }
+}
+
+/// [`BytesPerElement::BYTES`] bytes of the packed tweak integer per field element, least
+/// significant first.
+///
+/// Element `i` holds bytes `BYTES * i..BYTES * (i + 1)` of the little-endian packed integer, so
+/// every element is below `2^(8 * BYTES)` regardless of the tweak.
+///
+/// [`TWEAK_LEN`] elements hold 72 bits: every chain and message tweak, and tree tweaks in the
+/// [legacy layout](TreeTweakLayout::Legacy), the layout of the implementations using this
+/// encoding. [`TweakLimbEncoding::encode`] returns [`TweakError::Overflow`] on the wider tree
+/// tweaks of the default layout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BytesPerElement;
+
+impl BytesPerElement {
+    /// The number of whole bytes a BabyBear element holds.
+    pub const BYTES: usize = 3;
+
+    /// Splits `packed` into `N` elements of [`Self::BYTES`] bytes.
+    fn split<const N: usize>(packed: u128) -> [BabyBear; N] {
+        let bits = 8 * Self::BYTES;
+        let mask = (1u128 << bits) - 1;
+        core::array::from_fn(|i| BabyBear::new(((packed >> (bits * i)) & mask) as u32))
+    }
+}
+
+impl TweakLimbEncoding for BytesPerElement {
+    fn encode(packed: u128) -> Result<[BabyBear; TWEAK_LEN], TweakError> {
+        const {
+            assert!(
+                TWEAK_LEN * Self::BYTES * 8 >= CHAIN_TWEAK_BITS as usize,
+                "TWEAK_LEN elements of BytesPerElement::BYTES bytes are too small for a chain tweak"
+            );
+        };
+
+        let required = (u128::BITS - packed.leading_zeros()).div_ceil(8 * Self::BYTES as u32);
+        if required as usize > TWEAK_LEN {
+            return Err(TweakError::Overflow { required: required as usize, provided: TWEAK_LEN });
+        }
+        Ok(Self::split(packed))
+    }
+
+    fn encode_keyed(packed: u128) -> [BabyBear; KEYED_TWEAK_LEN] {
+        const {
+            assert!(
+                KEYED_TWEAK_LEN * Self::BYTES * 8 >= KEYED_TWEAK_BITS as usize,
+                "KEYED_TWEAK_LEN elements of BytesPerElement::BYTES bytes are too small for a \
+                 keyed tree tweak"
+            );
+        };
+
+        Self::split(packed)
+    }
+}
+
+/// The former name of [`TweakEncoding`], whose length was chosen by each caller.
+///
+/// It is only implemented for `TWEAK_LEN` limbs, so a bound on any other length is rejected:
+///
+/// ```compile_fail
+/// #![allow(deprecated)]
+/// use openvm_sig_agg::tweak::{ChainTweak, TweakTransfformation};
+///
+/// fn encode<T: TweakTransfformation<4>>(_: &T) {}
+/// encode(&ChainTweak::new_unchecked(1, 2, 3));
+/// ```
+#[deprecated(note = "renamed to `TweakEncoding`, whose length is fixed to `TWEAK_LEN`")]
+pub trait TweakTransfformation<const LEN: usize>: TweakEncoding {}
+
+#[allow(deprecated)]
+impl<T: TweakEncoding> TweakTransfformation<TWEAK_LEN> for T {}
+
+/// The packing layout of a [`TreeTweak`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum TreeTweakLayout {
+    /// `level << 72 | position << 8 | separator`, for positions of up to 64 bits.
+    #[default]
+    Wide,
+    /// `level << 40 | position << 8 | separator`, for positions of up to 32 bits.
+    ///
+    /// This is the layout of trees of at most `2^32` leaves before positions were widened. Only
+    /// tweaks created with [`TreeTweak::new_legacy`] use it. Packed integers of the two layouts
+    /// overlap, so all the trees of a scheme must use the same one.
+    Legacy,
+}
+
+impl TreeTweakLayout {
+    /// The shift of the level in the packed tweak integer.
+    const fn level_shift(self) -> u32 {
+        match self {
+            Self::Wide => 72,
+            Self::Legacy => 40,
+        }
+    }
+
+    /// The number of bits of a position in this layout.
+    const fn position_bits(self) -> u32 {
+        match self {
+            Self::Wide => u64::BITS,
+            Self::Legacy => u32::BITS,
+        }
+    }
+
+    /// The number of bits of a packed tree tweak in this layout.
+    const fn bits(self) -> u32 {
+        match self {
+            Self::Wide => TREE_TWEAK_BITS,
+            Self::Legacy => LEGACY_TREE_TWEAK_BITS,
+        }
+    }
+}
+
+/// The tweak of a node of a Merkle tree.
+///
+/// Levels count from the leaves: leaves are on level `0` and the root of a tree of height `h` is
+/// on level `h`. Positions count from the left, starting at `0` on every level, so the parent of
+/// the node at `position` is at `position / 2` on the level above.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "serialization::RawTreeTweak"))]
+pub struct TreeTweak {
+    /// The level of the node in the Merkle tree, counted from the leaves (level `0`).
+    pub(crate) level: u8,
+    /// The position of the node in the given level.
+    pub(crate) position: u64,
+    /// The packing layout of the tweak.
+    pub(crate) layout: TreeTweakLayout,
+}
+
+impl TreeTweak {
+    /// The domain of every tree tweak.
+    pub const DOMAIN: TweakDomain = TweakDomain::Tree;
+
+    /// Creates the tweak of the node at `position` on `level` of a tree of height `LOG_LIFETIME`.
+    ///
+    /// Levels count from the leaves: leaves are on level `0` and the root is on level
+    /// `LOG_LIFETIME`. Level `level` holds `2^(LOG_LIFETIME - level)` nodes.
+    pub const fn new<const LOG_LIFETIME: usize>(
+        level: u8,
+        position: u64,
+    ) -> Result<Self, TweakError> {
+        if level as usize > LOG_LIFETIME {
+            return Err(TweakError::LevelOutOfRange { level, log_lifetime: LOG_LIFETIME });
+        }
+        if !fits_in_bits(position, LOG_LIFETIME - level as usize) {
+            return Err(TweakError::TreePositionOutOfRange {
+                level,
+                position,
+                log_lifetime: LOG_LIFETIME,
+            });
+        }
+        Ok(Self::new_unchecked(level, position))
+    }
+
+    /// Creates a tree tweak without any range check.
+    ///
+    /// The caller is responsible for `level` and `position` being valid for the tree.
+    pub const fn new_unchecked(level: u8, position: u64) -> Self {
+        Self { level, position, layout: TreeTweakLayout::Wide }
+    }
+
+    /// Creates a tree tweak in the [legacy layout](TreeTweakLayout::Legacy), without any range
+    /// check.
+    ///
+    /// Its field elements are those of tree tweaks before positions were widened to 64 bits, and
+    /// are decoded with [`Self::from_field_elements_with_layout`]. Legacy tweaks have no byte,
+    /// serde or borsh form: encoding one returns an error.
+    pub const fn new_legacy(level: u8, position: u32) -> Self {
+        Self { level, position: position as u64, layout: TreeTweakLayout::Legacy }
+    }
+
+    /// Creates the tweak of the node at `position` on `level` of a tree of height `tree_height`.
+    ///
+    /// Levels count from the leaves up: the leaf of epoch `e` is node `e` of level `0`
+    /// ([`Self::leaf`]), the parent of the nodes `2i` and `2i + 1` of level `l` is node `i` of
+    /// level `l + 1`, and the root is the single node of level `tree_height` ([`Self::root`]).
+    ///
+    /// This is [`Self::new`] for a height only known at runtime.
+    pub const fn node(level: u8, position: u64, tree_height: u8) -> Result<Self, TweakError> {
+        if tree_height as usize > MAX_TREE_HEIGHT {
+            return Err(TweakError::LevelOutOfRange {
+                level: tree_height,
+                log_lifetime: MAX_TREE_HEIGHT,
+            });
+        }
+        if level > tree_height {
+            return Err(TweakError::LevelOutOfRange { level, log_lifetime: tree_height as usize });
+        }
+        if !fits_in_bits(position, (tree_height - level) as usize) {
+            return Err(TweakError::TreePositionOutOfRange {
+                level,
+                position,
+                log_lifetime: tree_height as usize,
+            });
+        }
+        Ok(Self::new_unchecked(level, position))
+    }
+
+    /// Creates the tweak of the leaf of `epoch`, on level `0`.
+    ///
+    /// See [`Self::node`] for the level convention.
+    pub const fn leaf(epoch: u32) -> Self {
+        Self::new_unchecked(0, epoch as u64)
+    }
+
+    /// Creates the tweak of the root of a tree of height `tree_height`, on level `tree_height`.
+    ///
+    /// See [`Self::node`] for the level convention.
+    pub const fn root(tree_height: u8) -> Self {
+        Self::new_unchecked(tree_height, 0)
+    }
+
+    /// Returns the packing layout of the tweak.
+    pub const fn layout(&self) -> TreeTweakLayout {
+        self.layout
+    }
+
+    /// Reconstructs a tree tweak in the [wide layout](TreeTweakLayout::Wide) from its
+    /// field-element encoding.
+    ///
+    /// This is the inverse of [`TweakEncoding::to_field_elements_over`] for tweaks of the default
+    /// layout. The packed integers of the two layouts overlap, e.g. the legacy tweak `(3, 17)`
+    /// encodes like the wide tweak `(0, 3 << 32 | 17)`, so legacy tweaks must be decoded with
+    /// [`Self::from_field_elements_with_layout`].
+    pub fn from_field_elements<F: PrimeField32>(
+        elements: &[F; TWEAK_LEN],
+    ) -> Result<Self, TweakDecodeError> {
+        Self::from_field_elements_with_layout(elements, TreeTweakLayout::Wide)
+    }
+
+    /// Reconstructs a tree tweak in the packing layout `layout` from its field-element encoding.
+    pub fn from_field_elements_with_layout<F: PrimeField32>(
+        elements: &[F; TWEAK_LEN],
+        layout: TreeTweakLayout,
+    ) -> Result<Self, TweakDecodeError> {
+        let packed = packed_from_limbs(elements)?;
+        check_separator(packed as u8, Self::DOMAIN)?;
+
+        let level =
+            u8::try_from(packed >> layout.level_shift()).map_err(|_| TweakDecodeError::Overflow)?;
+        let position = (packed >> 8) & ((1 << layout.position_bits()) - 1);
+        Ok(Self { level, position: position as u64, layout })
+    }
+
+    /// The length of the canonical byte encoding of a tree tweak.
+    pub const BYTES_LEN: usize = 10;
+
+    /// Returns the canonical byte encoding of the tweak.
+    ///
+    /// The layout is stable: `level || position (big-endian) || separator`, i.e. the big-endian
+    /// bytes of the packed tweak integer in the [wide layout](TreeTweakLayout::Wide).
+    ///
+    /// Returns [`TweakError::LegacyLayout`] for tweaks in the
+    /// [legacy layout](TreeTweakLayout::Legacy), which [`Self::from_bytes`] would decode into a
+    /// wide tweak of other field elements.
+    pub const fn to_bytes(&self) -> Result<[u8; Self::BYTES_LEN], TweakError> {
+        if !matches!(self.layout, TreeTweakLayout::Wide) {
+            return Err(TweakError::LegacyLayout);
+        }
+        let [p0, p1, p2, p3, p4, p5, p6, p7] = self.position.to_be_bytes();
+        Ok([self.level, p0, p1, p2, p3, p4, p5, p6, p7, Self::DOMAIN.as_u8()])
+    }
+
+    /// Returns the packed tweak integer that is split into limbs by
+    /// [`TweakEncoding::to_field_elements`].
+    ///
+    /// The layout is part of the stable encoding: `level << 72 | position << 8 | separator`, or
+    /// `level << 40 | position << 8 | separator` for tweaks in the
+    /// [legacy layout](TreeTweakLayout::Legacy). [`limbs_from_packed`] turns it into the field
+    /// elements of the tweak.
+    pub const fn packed(&self) -> u128 {
+        // Construct the tweak using bitwise shifts to encode level and position
+        ((self.level as u128) << self.layout.level_shift())
+            | ((self.position as u128) << 8)
+            | Self::DOMAIN.as_u8() as u128
+    }
+
+    /// Returns the packed integer of the tweak for the key `key_id`.
+    ///
+    /// The key id occupies the bits above the level: `key_id << 80 | packed`, or
+    /// `key_id << 48 | packed` in the legacy layout. Key id `0` gives the packed integer of
+    /// [`Self::packed`], i.e. the legacy encoding.
+    pub const fn packed_with_key_id(&self, key_id: u32) -> u128 {
+        ((key_id as u128) << self.layout.bits()) | self.packed()
+    }
+
+    /// Encodes the tweak into `tweak_len` base-p limbs, for lengths only known at runtime.
+    ///
+    /// The limbs are those of [`TweakEncoding::to_field_elements`], padded with zeros. Unlike
+    /// the fixed-length encoding, this returns [`TweakError::Overflow`] if the packed tweak does
+    /// not fit in `tweak_len` limbs.
+    #[cfg(feature = "alloc")]
+    pub fn try_to_field_elements(&self, tweak_len: usize) -> Result<Vec<BabyBear>, TweakError> {
+        try_limbs_from_packed(self.packed(), tweak_len)
+    }
+
+    /// Decodes a tree tweak in the [wide layout](TreeTweakLayout::Wide) from its canonical byte
+    /// encoding.
+    pub const fn from_bytes(bytes: [u8; Self::BYTES_LEN]) -> Result<Self, TweakDecodeError> {
+        let [level, p0, p1, p2, p3, p4, p5, p6, p7, separator] = bytes;
+        if let Err(err) = check_separator(separator, Self::DOMAIN) {
+            return Err(err);
+        }
+        Ok(Self::new_unchecked(level, u64::from_be_bytes([p0, p1, p2, p3, p4, p5, p6, p7])))
+    }
+
+    /// Returns the tweaks of the nodes on the path from leaf `leaf_index` up to the root of a
+    /// tree of height `tree_height`.
+    ///
+    /// The tweaks are yielded from the leaf's parent (level `1`) to the root (level
+    /// `tree_height`), halving the position at every level. This is the order in which they are
+    /// needed to recompute a root from a leaf and its authentication path.
+    pub const fn path_tweaks(leaf_index: u64, tree_height: u8) -> Result<PathTweaks, TweakError> {
+        if tree_height as usize > MAX_TREE_HEIGHT {
+            return Err(TweakError::LevelOutOfRange {
+                level: tree_height,
+                log_lifetime: MAX_TREE_HEIGHT,
+            });
+        }
+        if !fits_in_bits(leaf_index, tree_height as usize) {
+            return Err(TweakError::TreePositionOutOfRange {
+                level: 0,
+                position: leaf_index,
+                log_lifetime: tree_height as usize,
+            });
+        }
+        Ok(PathTweaks { leaf_index, next_level: 1, tree_height })
+    }
+
+    /// Returns the tweak of the parent of this node in a tree of height `tree_height`.
+    ///
+    /// Returns `None` for the root, i.e. when the node is on level `tree_height` or above.
+    pub const fn parent(&self, tree_height: u8) -> Option<Self> {
+        if self.level >= tree_height {
+            return None;
+        }
+        Some(Self { level: self.level + 1, position: self.position >> 1, ..*self })
+    }
+
+    /// Returns the tweak of the other child of this node's parent.
+    ///
+    /// The root has no sibling; for it this returns a node outside of the tree.
+    #[must_use]
+    pub const fn sibling(&self) -> Self {
+        Self { position: self.position ^ 1, ..*self }
+    }
+
+    /// Returns whether this node is the left child of its parent, i.e. has an even position.
+    pub const fn is_left_child(&self) -> bool {
+        self.position & 1 == 0
+    }
+
+    /// Encodes every tweak of `tweaks` into the matching entry of `out`.
+    ///
+    /// The output is identical to calling [`TweakEncoding::to_field_elements`] on each tweak, but
+    /// runs of tweaks on the same level and in the same layout only split the packed integer once.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tweaks` and `out` have different lengths.
+    pub fn encode_batch(tweaks: &[Self], out: &mut [[BabyBear; TWEAK_LEN]]) {
+        assert_eq!(tweaks.len(), out.len(), "one output is needed per tree tweak");
+
+        let mut base: Option<(Self, [BabyBear; TWEAK_LEN])> = None;
+        for (tweak, out) in tweaks.iter().zip(out) {
+            let limbs = match base {
+                Some((first, limbs))
+                    if first.level == tweak.level && first.layout == tweak.layout =>
+                {
+                    limbs
+                }
+                _ => {
+                    let first = Self { position: 0, ..*tweak };
+                    let limbs = first.to_field_elements();
+                    base = Some((first, limbs));
+                    limbs
+                }
+            };
+            *out = offset_limbs(&limbs, (tweak.position as u128) << 8);
+        }
+    }
+}
+
+impl TweakEncoding for TreeTweak {
+    fn to_field_elements_over<F: PrimeField32>(&self) -> [F; TWEAK_LEN] {
+        const {
+            assert!(
+                TWEAK_LEN >= min_tweak_len_for_prime(TREE_TWEAK_BITS, F::ORDER_U32),
+                "TWEAK_LEN limbs of this field are too small to hold a tree tweak"
+            );
+        };
+
+        limbs_from_packed_over(self.packed())
+    }
+}
+
+/// Iterator over the tree tweaks along a Merkle authentication path.
+///
+/// Created by [`TreeTweak::path_tweaks`].
+#[derive(Debug, Clone)]
+pub struct PathTweaks {
+    leaf_index: u64,
+    /// The level of the next node to yield.
+    next_level: u8,
+    tree_height: u8,
+}
+
+impl Iterator for PathTweaks {
+    type Item = TreeTweak;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_level > self.tree_height {
+            return None;
+        }
+        // The root of a tree of height 64 is at position 0, where the shift would overflow.
+        let position = self.leaf_index.checked_shr(self.next_level as u32).unwrap_or(0);
+        let tweak = TreeTweak::new_unchecked(self.next_level, position);
+        self.next_level += 1;
+        Some(tweak)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = (self.tree_height + 1).saturating_sub(self.next_level) as usize;
+        (len, Some(len))
+    }
+}
+
+impl ExactSizeIterator for PathTweaks {}
+
+impl FusedIterator for PathTweaks {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "borsh", derive(borsh::BorshSerialize, borsh::BorshDeserialize))]
+pub struct ChainTweak {
+    /// The key epoch (time interval) of the signature scheme.
+    pub(crate) epoch: u32,
+    /// Which chain the value belongs to.
+    pub(crate) chain_index: u16,
+    /// The position of the value in the chain.
+    pub(crate) position: u16,
+}
+
+impl ChainTweak {
+    /// The domain of every chain tweak.
+    pub const DOMAIN: TweakDomain = TweakDomain::Chain;
+
+    /// Creates the tweak of the value at `position` in chain `chain_index` for `epoch`.
+    ///
+    /// The bounds are those of the scheme: `epoch < 2^LOG_LIFETIME`,
+    /// `chain_index < 2^CEIL_LOG_NUM_CHAINS` and `position < 2^CHUNK_SIZE`.
+    pub const fn new<
+        const LOG_LIFETIME: usize,
+        const CEIL_LOG_NUM_CHAINS: usize,
+        const CHUNK_SIZE: usize,
+    >(
+        epoch: u32,
+        chain_index: u16,
+        position: u16,
+    ) -> Result<Self, TweakError> {
+        if !fits_in_bits(epoch as u64, LOG_LIFETIME) {
+            return Err(TweakError::EpochOutOfRange { epoch, log_lifetime: LOG_LIFETIME });
+        }
+        if !fits_in_bits(chain_index as u64, CEIL_LOG_NUM_CHAINS) {
+            return Err(TweakError::ChainIndexOutOfRange {
+                chain_index: chain_index as u32,
+                ceil_log_num_chains: CEIL_LOG_NUM_CHAINS,
+            });
+        }
+        if !fits_in_bits(position as u64, CHUNK_SIZE) {
+            return Err(TweakError::ChainPositionOutOfRange {
+                position: position as u32,
+                chunk_size: CHUNK_SIZE,
+            });
+        }
+        Ok(Self { epoch, chain_index, position })
+    }
+
+    /// Creates a chain tweak without any range check.
+    ///
+    /// The caller is responsible for the components being valid for the scheme.
+    pub const fn new_unchecked(epoch: u32, chain_index: u16, position: u16) -> Self {
+        Self { epoch, chain_index, position }
+    }
+
+    /// Reconstructs a chain tweak from its field-element encoding.
+    ///
+    /// This is the inverse of [`TweakEncoding::to_field_elements_over`].
+    pub fn from_field_elements<F: PrimeField32>(
+        elements: &[F; TWEAK_LEN],
+    ) -> Result<Self, TweakDecodeError> {
+        let packed = packed_from_limbs(elements)?;
+        check_separator(packed as u8, Self::DOMAIN)?;
+
+        let epoch = u32::try_from(packed >> 40).map_err(|_| TweakDecodeError::Overflow)?;
+        Ok(Self { epoch, chain_index: (packed >> 24) as u16, position: (packed >> 8) as u16 })
+    }
+
+    /// The length of the canonical byte encoding of a chain tweak.
+    pub const BYTES_LEN: usize = 9;
+
+    /// Returns the canonical byte encoding of the tweak.
+    ///
+    /// The layout is stable: `epoch || chain_index || position || separator` with every field
+    /// big-endian, i.e. the big-endian bytes of the packed tweak integer.
+    pub const fn to_bytes(&self) -> [u8; Self::BYTES_LEN] {
+        let [e0, e1, e2, e3] = self.epoch.to_be_bytes();
+        let [c0, c1] = self.chain_index.to_be_bytes();
+        let [p0, p1] = self.position.to_be_bytes();
+        [e0, e1, e2, e3, c0, c1, p0, p1, Self::DOMAIN.as_u8()]
+    }
+
+    /// Returns the packed tweak integer that is split into limbs by
+    /// [`TweakEncoding::to_field_elements`].
+    ///
+    /// The layout is part of the stable encoding:
+    /// `epoch << 40 | chain_index << 24 | position << 8 | separator`. [`limbs_from_packed`] turns
+    /// it into the field elements of the tweak.
+    pub const fn packed(&self) -> u128 {
+        // Construct the tweak using bitwise shifts to encode epoch, chain_index and position
+        ((self.epoch as u128) << 40)
+            | ((self.chain_index as u128) << 24)
+            | ((self.position as u128) << 8)
+            | Self::DOMAIN.as_u8() as u128
+    }
+
+    /// Returns the packed integer of the tweak for the key `key_id`.
+    ///
+    /// The key id occupies the bits above the epoch: `key_id << 72 | packed`. Key id `0` gives
+    /// the packed integer of [`Self::packed`], i.e. the legacy encoding.
+    pub const fn packed_with_key_id(&self, key_id: u32) -> u128 {
+        ((key_id as u128) << CHAIN_TWEAK_BITS) | self.packed()
+    }
+
+    /// Encodes the tweak into `tweak_len` base-p limbs, for lengths only known at runtime.
+    ///
+    /// The limbs are those of [`TweakEncoding::to_field_elements`], padded with zeros. Unlike
+    /// the fixed-length encoding, this returns [`TweakError::Overflow`] if the packed tweak does
+    /// not fit in `tweak_len` limbs.
+    #[cfg(feature = "alloc")]
+    pub fn try_to_field_elements(&self, tweak_len: usize) -> Result<Vec<BabyBear>, TweakError> {
+        try_limbs_from_packed(self.packed(), tweak_len)
+    }
+
+    /// Decodes a chain tweak from its canonical byte encoding.
+    pub const fn from_bytes(bytes: [u8; Self::BYTES_LEN]) -> Result<Self, TweakDecodeError> {
+        let [e0, e1, e2, e3, c0, c1, p0, p1, separator] = bytes;
+        if let Err(err) = check_separator(separator, Self::DOMAIN) {
+            return Err(err);
+        }
+        Ok(Self {
+            epoch: u32::from_be_bytes([e0, e1, e2, e3]),
+            chain_index: u16::from_be_bytes([c0, c1]),
+            position: u16::from_be_bytes([p0, p1]),
+        })
+    }
+
+    /// Returns the tweak of the value `steps` positions further along the same chain.
+    ///
+    /// `max_position` is the last position of the chain, i.e. `2^CHUNK_SIZE - 1` for the scheme.
+    pub const fn advance(&self, steps: u16, max_position: u16) -> Result<Self, TweakError> {
+        match self.position.checked_add(steps) {
+            Some(position) if position <= max_position => Ok(self.at_position(position)),
+            _ => Err(TweakError::ChainAdvanceOutOfRange {
+                position: self.position,
+                steps,
+                max_position,
+            }),
+        }
+    }
+
+    /// Returns the tweak of the value at `position` in the same chain.
+    #[must_use]
+    pub const fn at_position(&self, position: u16) -> Self {
+        Self { epoch: self.epoch, chain_index: self.chain_index, position }
+    }
+
+    /// Returns the tweaks of positions `start_pos..end_pos` in chain `chain_index` for `epoch`.
+    ///
+    /// The range is half-open like [`core::ops::Range`], so `end_pos` is a `u32` in order to
+    /// reach position `u16::MAX`. An empty or reversed range yields no tweaks.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `end_pos` is above `2^16`.
+    pub fn iter(epoch: u32, chain_index: u16, start_pos: u16, end_pos: u32) -> ChainTweakIter {
+        assert!(end_pos <= 1 << u16::BITS, "chain position {end_pos} does not fit in a u16");
+        ChainTweakIter {
+            epoch,
+            chain_index,
+            next: start_pos as u32,
+            end: end_pos.max(start_pos as u32),
+        }
+    }
+
+    /// Encodes every tweak of `tweaks` into the matching entry of `out`.
+    ///
+    /// The output is identical to calling [`TweakEncoding::to_field_elements`] on each tweak, but
+    /// runs of tweaks of the same chain, as produced by chain walking, only split the packed
+    /// integer once.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tweaks` and `out` have different lengths.
+    pub fn encode_batch(tweaks: &[Self], out: &mut [[BabyBear; TWEAK_LEN]]) {
+        assert_eq!(tweaks.len(), out.len(), "one output is needed per chain tweak");
+
+        let mut base: Option<(u32, u16, [BabyBear; TWEAK_LEN])> = None;
+        for (tweak, out) in tweaks.iter().zip(out) {
+            let limbs = match base {
+                Some((epoch, chain_index, limbs))
+                    if epoch == tweak.epoch && chain_index == tweak.chain_index =>
+                {
+                    limbs
+                }
+                _ => {
+                    let limbs =
+                        Self::new_unchecked(tweak.epoch, tweak.chain_index, 0).to_field_elements();
+                    base = Some((tweak.epoch, tweak.chain_index, limbs));
+                    limbs
+                }
+            };
+            *out = offset_limbs(&limbs, (tweak.position as u128) << 8);
+        }
+    }
+}
+
+impl TweakEncoding for ChainTweak {
+    /// Converts the tweak into field elements for Poseidon2 hashing.
+    ///
+    /// In Poseidon2, tweaks need to be encoded as elements of a finite field.
+    fn to_field_elements_over<F: PrimeField32>(&self) -> [F; TWEAK_LEN] {
+        const {
+            assert!(
+                TWEAK_LEN >= min_tweak_len_for_prime(CHAIN_TWEAK_BITS, F::ORDER_U32),
+                "TWEAK_LEN limbs of this field are too small to hold a chain tweak"
+            );
+        };
+
+        limbs_from_packed_over(self.packed())
+    }
+}
+
+/// Iterator over the tweaks of consecutive positions of one hash chain.
+///
+/// Created by [`ChainTweak::iter`].
+#[derive(Debug, Clone)]
+pub struct ChainTweakIter {
+    epoch: u32,
+    chain_index: u16,
+    /// The next position to yield from the front.
+    next: u32,
+    /// One past the last position to yield.
+    end: u32,
+}
+
+impl ChainTweakIter {
+    /// Maps every remaining tweak to its field-element encoding.
+    pub fn iter_field_elements(
+        self,
+    ) -> impl ExactSizeIterator<Item = [BabyBear; TWEAK_LEN]> + DoubleEndedIterator {
+        let cache = ChainTweakCache::new(self.epoch, self.chain_index);
+        self.map(move |tweak| cache.get(tweak.position))
+    }
+
+    const fn tweak_at(&self, position: u32) -> ChainTweak {
+        ChainTweak::new_unchecked(self.epoch, self.chain_index, position as u16)
+    }
+}
+
+impl Iterator for ChainTweakIter {
+    type Item = ChainTweak;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == self.end {
+            return None;
+        }
+        let tweak = self.tweak_at(self.next);
+        self.next += 1;
+        Some(tweak)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = (self.end - self.next) as usize;
+        (len, Some(len))
+    }
+}
+
+impl DoubleEndedIterator for ChainTweakIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.next == self.end {
+            return None;
+        }
+        self.end -= 1;
+        Some(self.tweak_at(self.end))
+    }
+}
+
+impl ExactSizeIterator for ChainTweakIter {}
+
+impl FusedIterator for ChainTweakIter {}
+
+/// The encodings of every position of one hash chain, derived from the encoding of position 0.
+///
+/// The tweaks of a chain only differ in their position, which sits right above the separator
+/// byte, so [`get`](Self::get) adds `position << 8` to the cached limbs of position 0 and
+/// propagates the carry past the prime instead of splitting the packed integer again. The
+/// result is the same as [`limbs_from_packed`] of the chain tweak at that position.
+///
+/// ```
+/// use openvm_sig_agg::tweak::{ChainTweak, ChainTweakCache, TweakEncoding};
+///
+/// let cache: ChainTweakCache = ChainTweakCache::new(9, 4);
+/// assert_eq!(cache.get(2), ChainTweak::new_unchecked(9, 4, 2).to_field_elements());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainTweakCache<const LEN: usize = TWEAK_LEN> {
+    epoch: u32,
+    chain_index: u16,
+    /// The encoding of position 0 of the chain.
+    base: [BabyBear; LEN],
+}
+
+impl<const LEN: usize> ChainTweakCache<LEN> {
+    /// Precomputes the encoding of chain `chain_index` for `epoch`.
+    pub fn new(epoch: u32, chain_index: u16) -> Self {
+        const {
+            assert!(
+                LEN >= min_tweak_len(CHAIN_TWEAK_BITS),
+                "LEN limbs are too small to hold a chain tweak"
+            );
+        };
+
+        let base = limbs_from_packed(ChainTweak::new_unchecked(epoch, chain_index, 0).packed());
+        Self { epoch, chain_index, base }
+    }
+
+    /// Returns the epoch of the cached chain.
+    pub const fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// Returns the index of the cached chain.
+    pub const fn chain_index(&self) -> u16 {
+        self.chain_index
+    }
+
+    /// Returns the encoding of the tweak at `position` in the cached chain.
+    pub fn get(&self, position: u16) -> [BabyBear; LEN] {
+        offset_limbs(&self.base, (position as u128) << 8)
+    }
+}
+
+/// A chain tweak with 32-bit chain indices and positions, for schemes with more than `2^16`
+/// chains or chains longer than `2^16` values.
+///
+/// The packed layout is `epoch << 72 | chain_index << 40 | position << 8 | separator`, which
+/// needs [`WIDE_CHAIN_TWEAK_LEN`] limbs instead of [`TWEAK_LEN`]. It is a separate type rather
+/// than a change of [`ChainTweak`] so that the encodings of chain tweaks stay reproducible. The
+/// two types encode the same chain value differently, so a scheme must use one of them
+/// throughout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WideChainTweak {
+    /// The key epoch (time interval) of the signature scheme.
+    pub(crate) epoch: u32,
+    /// Which chain the value belongs to.
+    pub(crate) chain_index: u32,
+    /// The position of the value in the chain.
+    pub(crate) position: u32,
+}
+
+impl WideChainTweak {
+    /// The domain of every wide chain tweak.
+    pub const DOMAIN: TweakDomain = TweakDomain::Chain;
+
+    /// Creates the tweak of the value at `position` in chain `chain_index` for `epoch`.
+    ///
+    /// The bounds are those of the scheme: `epoch < 2^LOG_LIFETIME`,
+    /// `chain_index < 2^CEIL_LOG_NUM_CHAINS` and `position < 2^CHUNK_SIZE`.
+    pub const fn new<
+        const LOG_LIFETIME: usize,
+        const CEIL_LOG_NUM_CHAINS: usize,
+        const CHUNK_SIZE: usize,
+    >(
+        epoch: u32,
+        chain_index: u32,
+        position: u32,
+    ) -> Result<Self, TweakError> {
+        if !fits_in_bits(epoch as u64, LOG_LIFETIME) {
+            return Err(TweakError::EpochOutOfRange { epoch, log_lifetime: LOG_LIFETIME });
+        }
+        if !fits_in_bits(chain_index as u64, CEIL_LOG_NUM_CHAINS) {
+            return Err(TweakError::ChainIndexOutOfRange {
+                chain_index,
+                ceil_log_num_chains: CEIL_LOG_NUM_CHAINS,
+            });
+        }
+        if !fits_in_bits(position as u64, CHUNK_SIZE) {
+            return Err(TweakError::ChainPositionOutOfRange { position, chunk_size: CHUNK_SIZE });
+        }
+        Ok(Self { epoch, chain_index, position })
+    }
+
+    /// Creates a wide chain tweak without any range check.
+    ///
+    /// The caller is responsible for the components being valid for the scheme.
+    pub const fn new_unchecked(epoch: u32, chain_index: u32, position: u32) -> Self {
+        Self { epoch, chain_index, position }
+    }
+
+    /// Returns the packed tweak integer that is split into limbs by
+    /// [`Self::to_field_elements`].
+    ///
+    /// The layout is part of the stable encoding:
+    /// `epoch << 72 | chain_index << 40 | position << 8 | separator`.
+    pub const fn packed(&self) -> u128 {
+        ((self.epoch as u128) << 72)
+            | ((self.chain_index as u128) << 40)
+            | ((self.position as u128) << 8)
+            | Self::DOMAIN.as_u8() as u128
+    }
+
+    /// Converts the tweak into [`WIDE_CHAIN_TWEAK_LEN`] BabyBear limbs, least significant first.
+    pub fn to_field_elements(&self) -> [BabyBear; WIDE_CHAIN_TWEAK_LEN] {
+        self.to_field_elements_over()
+    }
+
+    /// Converts the tweak into [`WIDE_CHAIN_TWEAK_LEN`] base-p limbs of `F`, least significant
+    /// first.
+    pub fn to_field_elements_over<F: PrimeField32>(&self) -> [F; WIDE_CHAIN_TWEAK_LEN] {
+        const {
+            assert!(
+                WIDE_CHAIN_TWEAK_LEN
+                    >= min_tweak_len_for_prime(WIDE_CHAIN_TWEAK_BITS, F::ORDER_U32),
+                "WIDE_CHAIN_TWEAK_LEN limbs of this field are too small to hold a wide chain tweak"
+            );
+        };
+
+        limbs_from_packed_over(self.packed())
+    }
+
+    /// Reconstructs a wide chain tweak from its field-element encoding.
+    ///
+    /// This is the inverse of [`Self::to_field_elements_over`].
+    pub fn from_field_elements<F: PrimeField32>(
+        elements: &[F; WIDE_CHAIN_TWEAK_LEN],
+    ) -> Result<Self, TweakDecodeError> {
+        let packed = packed_from_limbs(elements)?;
+        check_separator(packed as u8, Self::DOMAIN)?;
+
+        let epoch = u32::try_from(packed >> 72).map_err(|_| TweakDecodeError::Overflow)?;
+        Ok(Self { epoch, chain_index: (packed >> 40) as u32, position: (packed >> 8) as u32 })
+    }
+}
+
+impl From<ChainTweak> for WideChainTweak {
+    fn from(tweak: ChainTweak) -> Self {
+        Self {
+            epoch: tweak.epoch,
+            chain_index: tweak.chain_index.into(),
+            position: tweak.position.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "borsh", derive(borsh::BorshSerialize, borsh::BorshDeserialize))]
+pub struct MessageTweak {
+    /// The key epoch (time interval) the message is signed for.
+    pub(crate) epoch: u32,
+}
+
+impl MessageTweak {
+    /// The domain of every message tweak.
+    pub const DOMAIN: TweakDomain = TweakDomain::MessageHash;
+
+    /// Reconstructs a message tweak from its field-element encoding.
+    ///
+    /// This is the inverse of [`TweakEncoding::to_field_elements_over`].
+    pub fn from_field_elements<F: PrimeField32>(
+        elements: &[F; TWEAK_LEN],
+    ) -> Result<Self, TweakDecodeError> {
+        let packed = packed_from_limbs(elements)?;
+        check_separator(packed as u8, Self::DOMAIN)?;
+
+        let epoch = u32::try_from(packed >> 8).map_err(|_| TweakDecodeError::Overflow)?;
+        Ok(Self { epoch })
+    }
+
+    /// The length of the canonical byte encoding of a message tweak.
+    pub const BYTES_LEN: usize = 5;
+
+    /// Returns the canonical byte encoding of the tweak.
+    ///
+    /// The layout is stable: `epoch (big-endian) || separator`, i.e. the big-endian bytes of the
+    /// packed tweak integer.
+    pub const fn to_bytes(&self) -> [u8; Self::BYTES_LEN] {
+        let [e0, e1, e2, e3] = self.epoch.to_be_bytes();
+        [e0, e1, e2, e3, Self::DOMAIN.as_u8()]
+    }
+
+    /// Returns the packed tweak integer that is split into limbs by
+    /// [`TweakEncoding::to_field_elements`].
+    ///
+    /// The layout is part of the stable encoding: `epoch << 8 | separator`.
+    pub const fn packed(&self) -> u64 {
+        // Construct the tweak using bitwise shifts to encode the epoch
+        ((self.epoch as u64) << 8) | Self::DOMAIN.as_u8() as u64
+    }
+
+    /// Returns the packed integer of the tweak for the key `key_id`.
+    ///
+    /// The key id occupies the bits above the epoch: `key_id << 40 | packed`. Key id `0` gives
+    /// the packed integer of [`Self::packed`], i.e. the legacy encoding.
+    pub const fn packed_with_key_id(&self, key_id: u32) -> u128 {
+        ((key_id as u128) << MESSAGE_TWEAK_BITS) | self.packed() as u128
+    }
+
+    /// Decodes a message tweak from its canonical byte encoding.
+    pub const fn from_bytes(bytes: [u8; Self::BYTES_LEN]) -> Result<Self, TweakDecodeError> {
+        let [e0, e1, e2, e3, separator] = bytes;
+        if let Err(err) = check_separator(separator, Self::DOMAIN) {
+            return Err(err);
+        }
+        Ok(Self { epoch: u32::from_be_bytes([e0, e1, e2, e3]) })
+    }
+}
+
+impl TweakEncoding for MessageTweak {
+    fn to_field_elements_over<F: PrimeField32>(&self) -> [F; TWEAK_LEN] {
+        const {
+            assert!(
+                TWEAK_LEN >= min_tweak_len_for_prime(MESSAGE_TWEAK_BITS, F::ORDER_U32),
+                "TWEAK_LEN limbs of this field are too small to hold a message tweak"
+            );
+        };
+
+        limbs_from_packed_over(u128::from(self.packed()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "borsh", derive(borsh::BorshSerialize, borsh::BorshDeserialize))]
+pub enum PoseidonTweak {
+    Tree(TreeTweak),
+    Chain(ChainTweak),
+    Message(MessageTweak),
+}
+
+impl PoseidonTweak {
+    /// Returns the domain of the tree, chain or message tweak.
+    pub const fn domain(&self) -> TweakDomain {
+        match self {
+            Self::Tree(_) => TreeTweak::DOMAIN,
+            Self::Chain(_) => ChainTweak::DOMAIN,
+            Self::Message(_) => MessageTweak::DOMAIN,
+        }
+    }
+
+    /// Reconstructs a tweak of any type from its field-element encoding.
+    ///
+    /// The tweak type is selected by the separator byte.
+    pub fn from_field_elements<F: PrimeField32>(
+        elements: &[F; TWEAK_LEN],
+    ) -> Result<Self, TweakDecodeError> {
+        let separator = packed_from_limbs(elements)? as u8;
+        match TweakDomain::from_u8(separator) {
+            Some(TweakDomain::Tree) => TreeTweak::from_field_elements(elements).map(Self::Tree),
+            Some(TweakDomain::Chain) => ChainTweak::from_field_elements(elements).map(Self::Chain),
+            Some(TweakDomain::MessageHash) => {
+                MessageTweak::from_field_elements(elements).map(Self::Message)
+            }
+            None => Err(TweakDecodeError::UnknownSeparator(separator)),
+        }
+    }
+
+    /// Returns the packed tweak integer of the tree, chain or message tweak.
+    ///
+    /// The tweak type is identified by the separator in the least significant byte.
+    pub const fn packed(&self) -> u128 {
+        match self {
+            Self::Tree(tweak) => tweak.packed(),
+            Self::Chain(tweak) => tweak.packed(),
+            Self::Message(tweak) => tweak.packed() as u128,
+        }
+    }
+
+    /// Returns the packed integer of the tree, chain or message tweak for the key `key_id`.
+    ///
+    /// Key id `0` gives [`Self::packed`].
+    pub const fn packed_with_key_id(&self, key_id: u32) -> u128 {
+        match self {
+            Self::Tree(tweak) => tweak.packed_with_key_id(key_id),
+            Self::Chain(tweak) => tweak.packed_with_key_id(key_id),
+            Self::Message(tweak) => tweak.packed_with_key_id(key_id),
+        }
+    }
+
+    /// Returns the canonical, length-prefixed byte encoding of the tweak.
+    ///
+    /// The layout is stable: one byte holding the length `n` of the inner encoding, followed by
+    /// the `n` bytes of the tree, chain or message tweak encoding. The tweak type is identified by
+    /// the trailing separator byte.
+    ///
+    /// Returns [`TweakError::LegacyLayout`] for tree tweaks in the
+    /// [legacy layout](TreeTweakLayout::Legacy), see [`TreeTweak::to_bytes`].
+    #[cfg(feature = "alloc")]
+    pub fn to_bytes(&self) -> Result<Vec<u8>, TweakError> {
+        let mut bytes = Vec::with_capacity(1 + ChainTweak::BYTES_LEN);
+        match self {
+            Self::Tree(tweak) => {
+                bytes.push(TreeTweak::BYTES_LEN as u8);
+                bytes.extend_from_slice(&tweak.to_bytes()?);
+            }
+            Self::Chain(tweak) => {
+                bytes.push(ChainTweak::BYTES_LEN as u8);
+                bytes.extend_from_slice(&tweak.to_bytes());
+            }
+            Self::Message(tweak) => {
+                bytes.push(MessageTweak::BYTES_LEN as u8);
+                bytes.extend_from_slice(&tweak.to_bytes());
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Decodes a tweak of any type from its length-prefixed byte encoding.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TweakDecodeError> {
+        let (&len, body) =
+            bytes.split_first().ok_or(TweakDecodeError::InvalidLength { expected: 1, found: 0 })?;
+        if body.len() != len as usize {
+            return Err(TweakDecodeError::InvalidLength {
+                expected: 1 + len as usize,
+                found: bytes.len(),
+            });
+        }
+
+        let separator = *body.last().ok_or(TweakDecodeError::InvalidLength {
+            expected: 1 + MessageTweak::BYTES_LEN,
+            found: bytes.len(),
+        })?;
+        let invalid_length = |expected: usize| TweakDecodeError::InvalidLength {
+            expected: 1 + expected,
+            found: bytes.len(),
+        };
+        match TweakDomain::from_u8(separator) {
+            Some(TweakDomain::Tree) => body
+                .try_into()
+                .map_err(|_| invalid_length(TreeTweak::BYTES_LEN))
+                .and_then(TreeTweak::from_bytes)
+                .map(Self::Tree),
+            Some(TweakDomain::Chain) => body
+                .try_into()
+                .map_err(|_| invalid_length(ChainTweak::BYTES_LEN))
+                .and_then(ChainTweak::from_bytes)
+                .map(Self::Chain),
+            Some(TweakDomain::MessageHash) => body
+                .try_into()
+                .map_err(|_| invalid_length(MessageTweak::BYTES_LEN))
+                .and_then(MessageTweak::from_bytes)
+                .map(Self::Message),
+            None => Err(TweakDecodeError::UnknownSeparator(separator)),
+        }
+    }
+}
+
+impl TweakEncoding for PoseidonTweak {
+    fn to_field_elements_over<F: PrimeField32>(&self) -> [F; TWEAK_LEN] {
+        match self {
+            PoseidonTweak::Tree(tree_tweak) => tree_tweak.to_field_elements_over(),
+            PoseidonTweak::Chain(chain_tweak) => chain_tweak.to_field_elements_over(),
+            PoseidonTweak::Message(message_tweak) => message_tweak.to_field_elements_over(),
+        }
+    }
+}
+
+/// Tweaks of every kind, including zero and maximal values, shared by the tests of the
+/// submodules.
+#[cfg(test)]
+pub(crate) fn sample_tweaks() -> [PoseidonTweak; 6] {
+    [
+        PoseidonTweak::Tree(TreeTweak::new_unchecked(0, 0)),
+        PoseidonTweak::Tree(TreeTweak::new_unchecked(3, 17)),
+        PoseidonTweak::Chain(ChainTweak { epoch: 9, chain_index: 4, position: 2 }),
+        PoseidonTweak::Chain(ChainTweak {
+            epoch: u32::MAX,
+            chain_index: u16::MAX,
+            position: u16::MAX,
+        }),
+        PoseidonTweak::Message(MessageTweak { epoch: 0 }),
+        PoseidonTweak::Message(MessageTweak { epoch: u32::MAX }),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p3_field::FieldAlgebra;
+    use p3_koala_bear::KoalaBear;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    #[test]
+    fn test_tree_tweak_to_field_elements_small_values() {
+        let tweak = TreeTweak::new_legacy(1, 2);
+        let field_elements = tweak.to_field_elements();
+        assert_eq!(field_elements, [BabyBear::new(268435423), BabyBear::new(546), BabyBear::ZERO]);
+    }
+
+    #[test]
+    fn test_tree_tweak_to_field_elements_random_values() {
+        assert_eq!(
+            TreeTweak::new_legacy(u8::MAX, u32::MAX).to_field_elements(),
+            [BabyBear::new(268295391), BabyBear::new(139810), BabyBear::ZERO]
+        );
+
+        assert_eq!(
+            TreeTweak::new_legacy(25, 26282).to_field_elements(),
+            [BabyBear::new(677803180), BabyBear::new(13653), BabyBear::ZERO]
+        );
+    }
+
+    #[test]
+    fn test_tree_tweak_to_field_elements_edge_cases() {
+        let tweak = TreeTweak::new_legacy(0, 0);
+        let field_elements = tweak.to_field_elements();
+        // Only the separator should be set
+        assert_eq!(
+            field_elements,
+            [BabyBear::new(TweakDomain::Tree.as_u8() as u32), BabyBear::ZERO, BabyBear::ZERO]
+        );
+    }
+
+    #[test]
+    fn test_chain_tweak_to_field_elements_small_values() {
+        let tweak = ChainTweak { epoch: 1, chain_index: 2, position: 3 };
+        let field_elements = tweak.to_field_elements();
+        assert_eq!(field_elements, [BabyBear::new(301990110), BabyBear::new(546), BabyBear::ZERO]);
+    }
+
+    #[test]
+    fn test_chain_tweak_to_field_elements_random_values() {
+        let tweak = ChainTweak { epoch: u32::MAX, chain_index: u16::MAX, position: u16::MAX };
+        let field_elements = tweak.to_field_elements();
+        assert_eq!(
+            field_elements,
+            [BabyBear::new(98427243), BabyBear::new(170006792), BabyBear::new(1165)]
+        );
+    }
+
+    #[test]
+    fn test_chain_tweak_to_field_elements_edge_cases() {
+        let tweak = ChainTweak { epoch: 0, chain_index: 0, position: 0 };
+        let field_elements = tweak.to_field_elements();
+        // Only the separator should be set
+        assert_eq!(
+            field_elements,
+            [BabyBear::new(TweakDomain::Chain.as_u8() as u32), BabyBear::ZERO, BabyBear::ZERO]
+        );
+    }
+
+    #[test]
+    fn test_message_tweak_to_field_elements_edge_cases() {
+        let tweak = MessageTweak { epoch: 0 };
+        let field_elements = tweak.to_field_elements();
+        // Only the separator should be set
+        assert_eq!(
+            field_elements,
+            [
+                BabyBear::new(TweakDomain::MessageHash.as_u8() as u32),
+                BabyBear::ZERO,
+                BabyBear::ZERO
+            ]
+        );
+    }
+
+    #[test]
+    fn test_message_tweak_to_field_elements_max_epoch() {
+        let tweak = MessageTweak { epoch: u32::MAX };
+        let field_elements = tweak.to_field_elements();
+        assert_eq!(field_elements, [BabyBear::new(268434656), BabyBear::new(546), BabyBear::ZERO]);
+    }
+
+    #[test]
+    fn test_message_tweak_to_field_elements_random_values() {
+        let tweak = MessageTweak { epoch: 123456 };
+        let field_elements = tweak.to_field_elements();
+        assert_eq!(field_elements, [BabyBear::new(31604738), BabyBear::ZERO, BabyBear::ZERO]);
+    }
+
+    #[test]
+    fn test_poseidon_tweak_message_variant() {
+        let tweak = MessageTweak { epoch: 123456 };
+        let expected = tweak.to_field_elements();
+        assert_eq!(PoseidonTweak::Message(tweak).to_field_elements(), expected);
+    }
+
+    #[test]
+    fn test_tweak_to_field_elements_over_koala_bear() {
+        // Limbs are taken modulo the KoalaBear prime 2^31 - 2^24 + 1
+        let chain: [KoalaBear; 3] =
+            ChainTweak { epoch: 1, chain_index: 2, position: 3 }.to_field_elements_over();
+        assert_eq!(chain, [KoalaBear::new(100663548), KoalaBear::new(516), KoalaBear::ZERO]);
+
+        let chain: [KoalaBear; 3] =
+            ChainTweak { epoch: u32::MAX, chain_index: u16::MAX, position: u16::MAX }
+                .to_field_elements_over();
+        assert_eq!(
+            chain,
+            [KoalaBear::new(670032592), KoalaBear::new(403707936), KoalaBear::new(1040)]
+        );
+
+        let tree: [KoalaBear; 3] = TreeTweak::new_legacy(25, 26282).to_field_elements_over();
+        assert_eq!(tree, [KoalaBear::new(1684436893), KoalaBear::new(12900), KoalaBear::ZERO]);
+
+        let message: [KoalaBear; 3] = MessageTweak { epoch: 123456 }.to_field_elements_over();
+        assert_eq!(message, [KoalaBear::new(31604738), KoalaBear::ZERO, KoalaBear::ZERO]);
+    }
+
+    #[test]
+    fn test_tweak_to_field_elements_over_baby_bear_matches_default() {
+        let tweak = PoseidonTweak::Chain(ChainTweak { epoch: 1, chain_index: 2, position: 3 });
+        let over: [BabyBear; 3] = tweak.to_field_elements_over();
+        assert_eq!(over, tweak.to_field_elements());
+    }
+
+    #[test]
+    fn test_tweak_decode_roundtrip_koala_bear() {
+        let mut rng = StdRng::seed_from_u64(5);
+        for _ in 0..100 {
+            let tree = TreeTweak::new_unchecked(rng.gen(), rng.gen());
+            let chain =
+                ChainTweak { epoch: rng.gen(), chain_index: rng.gen(), position: rng.gen() };
+
+            let encoded: [KoalaBear; 3] = PoseidonTweak::Tree(tree).to_field_elements_over();
+            assert_eq!(PoseidonTweak::from_field_elements(&encoded), Ok(PoseidonTweak::Tree(tree)));
+            let encoded: [KoalaBear; 3] = PoseidonTweak::Chain(chain).to_field_elements_over();
+            assert_eq!(
+                PoseidonTweak::from_field_elements(&encoded),
+                Ok(PoseidonTweak::Chain(chain))
+            );
+        }
+    }
+
+    #[test]
+    fn test_min_tweak_len_for_koala_bear() {
+        let prime = KoalaBear::ORDER_U32;
+        assert_eq!(min_tweak_len_for_prime(TREE_TWEAK_BITS, prime), 3);
+        assert_eq!(min_tweak_len_for_prime(LEGACY_TREE_TWEAK_BITS, prime), 2);
+        assert_eq!(min_tweak_len_for_prime(CHAIN_TWEAK_BITS, prime), 3);
+        assert_eq!(min_tweak_len_for_prime(MESSAGE_TWEAK_BITS, prime), 2);
+    }
+
+    #[test]
+    fn test_tree_tweak_new_valid() {
+        // Leaves of a height-4 tree
+        assert_eq!(TreeTweak::new::<4>(0, 15), Ok(TreeTweak::new_unchecked(0, 15)));
+        // Root of a height-4 tree
+        assert_eq!(TreeTweak::new::<4>(4, 0), Ok(TreeTweak::new_unchecked(4, 0)));
+        // Full 64-bit position range for a height-64 tree
+        assert_eq!(TreeTweak::new::<64>(0, u64::MAX), Ok(TreeTweak::new_unchecked(0, u64::MAX)));
+        // Positions above 32 bits for a height-40 tree
+        assert_eq!(
+            TreeTweak::new::<40>(0, (1 << 40) - 1),
+            Ok(TreeTweak::new_unchecked(0, (1 << 40) - 1))
+        );
+        assert_eq!(
+            TreeTweak::new::<40>(0, 1 << 40),
+            Err(TweakError::TreePositionOutOfRange {
+                level: 0,
+                position: 1 << 40,
+                log_lifetime: 40
+            })
+        );
+    }
+
+    #[test]
+    fn test_tree_tweak_positions_above_32_bits_do_not_collide() {
+        let mut rng = StdRng::seed_from_u64(10);
+        let positions = [u64::from(u32::MAX), 1 << 32, (1 << 32) + 1, 1 << 40, u64::MAX - 1]
+            .into_iter()
+            .chain((0..200).map(|_| rng.gen_range(1 << 32..=u64::MAX)));
+
+        let mut packed = std::collections::HashSet::new();
+        let mut encoded = std::collections::HashSet::new();
+        for position in positions {
+            for level in [0, 1, 40, u8::MAX] {
+                let tweak = TreeTweak::new_unchecked(level, position);
+                assert!(packed.insert(tweak.packed()), "{tweak:?}");
+                assert!(encoded.insert(tweak.to_field_elements()), "{tweak:?}");
+                assert_eq!(TreeTweak::from_field_elements(&tweak.to_field_elements()), Ok(tweak));
+            }
+        }
+
+        // Nodes of a height-40 tree along the path of its last leaf
+        let path: Vec<_> = TreeTweak::path_tweaks((1 << 40) - 1, 40).unwrap().collect();
+        assert_eq!(path[0], TreeTweak::new_unchecked(1, (1 << 39) - 1));
+        assert_eq!(path[39], TreeTweak::new_unchecked(40, 0));
+    }
+
+    #[test]
+    fn test_tree_tweak_new_out_of_range() {
+        assert_eq!(
+            TreeTweak::new::<4>(5, 0),
+            Err(TweakError::LevelOutOfRange { level: 5, log_lifetime: 4 })
+        );
+        assert_eq!(
+            TreeTweak::new::<4>(0, 16),
+            Err(TweakError::TreePositionOutOfRange { level: 0, position: 16, log_lifetime: 4 })
+        );
+        // The root level only has position 0
+        assert_eq!(
+            TreeTweak::new::<4>(4, 1),
+            Err(TweakError::TreePositionOutOfRange { level: 4, position: 1, log_lifetime: 4 })
+        );
+        assert_eq!(
+            TreeTweak::new::<4>(0, 16).unwrap_err().to_string(),
+            "tree position 16 does not exist on level 0 of a tree of height 4"
+        );
+    }
+
+    #[test]
+    fn test_chain_tweak_new_valid() {
+        assert_eq!(
+            ChainTweak::new::<8, 6, 4>(255, 63, 15),
+            Ok(ChainTweak { epoch: 255, chain_index: 63, position: 15 })
+        );
+        assert_eq!(
+            ChainTweak::new::<32, 16, 16>(u32::MAX, u16::MAX, u16::MAX),
+            Ok(ChainTweak { epoch: u32::MAX, chain_index: u16::MAX, position: u16::MAX })
+        );
+    }
+
+    #[test]
+    fn test_chain_tweak_new_out_of_range() {
+        assert_eq!(
+            ChainTweak::new::<8, 6, 4>(256, 0, 0),
+            Err(TweakError::EpochOutOfRange { epoch: 256, log_lifetime: 8 })
+        );
+        assert_eq!(
+            ChainTweak::new::<8, 6, 4>(0, 64, 0),
+            Err(TweakError::ChainIndexOutOfRange { chain_index: 64, ceil_log_num_chains: 6 })
+        );
+        assert_eq!(
+            ChainTweak::new::<8, 6, 4>(0, 0, 16),
+            Err(TweakError::ChainPositionOutOfRange { position: 16, chunk_size: 4 })
+        );
+        assert_eq!(
+            ChainTweak::new::<8, 6, 4>(0, 0, 16).unwrap_err().to_string(),
+            "chain position 16 is out of range for chains of length 2^4"
+        );
+    }
+
+    #[test]
+    fn test_tree_tweak_navigation_depth_3() {
+        // Every node of a tree of height 3 with its parent, sibling and side
+        #[rustfmt::skip]
+        let nodes = [
+            ((0, 0), Some((1, 0)), (0, 1), true),
+            ((0, 1), Some((1, 0)), (0, 0), false),
+            ((0, 2), Some((1, 1)), (0, 3), true),
+            ((0, 3), Some((1, 1)), (0, 2), false),
+            ((0, 4), Some((1, 2)), (0, 5), true),
+            ((0, 5), Some((1, 2)), (0, 4), false),
+            ((0, 6), Some((1, 3)), (0, 7), true),
+            ((0, 7), Some((1, 3)), (0, 6), false),
+            ((1, 0), Some((2, 0)), (1, 1), true),
+            ((1, 1), Some((2, 0)), (1, 0), false),
+            ((1, 2), Some((2, 1)), (1, 3), true),
+            ((1, 3), Some((2, 1)), (1, 2), false),
+            ((2, 0), Some((3, 0)), (2, 1), true),
+            ((2, 1), Some((3, 0)), (2, 0), false),
+            ((3, 0), None, (3, 1), true),
+        ];
+
+        let tweak = |(level, position)| TreeTweak::new::<3>(level, position).unwrap();
+        for (node, parent, sibling, is_left) in nodes {
+            let node = tweak(node);
+            assert_eq!(node.parent(3), parent.map(tweak), "{node:?}");
+            assert_eq!(node.is_left_child(), is_left, "{node:?}");
+            if node.level < 3 {
+                assert_eq!(node.sibling(), tweak(sibling), "{node:?}");
+                assert_eq!(node.sibling().sibling(), node);
+                assert_eq!(node.sibling().parent(3), node.parent(3));
+            }
+        }
+    }
+
+    #[test]
+    fn test_tree_tweak_parent_matches_path_tweaks() {
+        let mut node = TreeTweak::new_unchecked(0, 11);
+        for expected in TreeTweak::path_tweaks(11, 4).unwrap() {
+            node = node.parent(4).unwrap();
+            assert_eq!(node, expected);
+        }
+        assert_eq!(node.parent(4), None);
+    }
+
+    #[test]
+    fn test_tree_path_tweaks_depth_4() {
+        // Leaf 11 = 0b1011: its ancestors are at positions 5, 2, 1 and 0.
+        let tweaks: Vec<_> = TreeTweak::path_tweaks(11, 4).unwrap().collect();
+        assert_eq!(
+            tweaks,
+            [
+                TreeTweak::new_unchecked(1, 5),
+                TreeTweak::new_unchecked(2, 2),
+                TreeTweak::new_unchecked(3, 1),
+                TreeTweak::new_unchecked(4, 0),
+            ]
+        );
+        assert_eq!(TreeTweak::path_tweaks(11, 4).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_tree_path_tweaks_edge_leaves() {
+        let first: Vec<_> = TreeTweak::path_tweaks(0, 4).unwrap().map(|t| t.position).collect();
+        assert_eq!(first, [0, 0, 0, 0]);
+
+        let last: Vec<_> = TreeTweak::path_tweaks(15, 4).unwrap().map(|t| t.position).collect();
+        assert_eq!(last, [7, 3, 1, 0]);
+
+        let height_one: Vec<_> = TreeTweak::path_tweaks(1, 1).unwrap().collect();
+        assert_eq!(height_one, [TreeTweak::new_unchecked(1, 0)]);
+
+        let full: Vec<_> = TreeTweak::path_tweaks(u64::MAX, 64).unwrap().collect();
+        assert_eq!(full.len(), 64);
+        assert_eq!(full[0], TreeTweak::new_unchecked(1, u64::MAX >> 1));
+        assert_eq!(full[31], TreeTweak::new_unchecked(32, u64::from(u32::MAX)));
+        assert_eq!(full[63], TreeTweak::new_unchecked(64, 0));
+    }
+
+    #[test]
+    fn test_tree_path_tweaks_are_valid_tree_tweaks() {
+        for tweak in TreeTweak::path_tweaks(9, 4).unwrap() {
+            assert_eq!(TreeTweak::new::<4>(tweak.level, tweak.position), Ok(tweak));
+        }
+    }
+
+    #[test]
+    fn test_tree_path_tweaks_out_of_range() {
+        assert_eq!(
+            TreeTweak::path_tweaks(16, 4).unwrap_err(),
+            TweakError::TreePositionOutOfRange { level: 0, position: 16, log_lifetime: 4 }
+        );
+        assert_eq!(
+            TreeTweak::path_tweaks(0, 65).unwrap_err(),
+            TweakError::LevelOutOfRange { level: 65, log_lifetime: MAX_TREE_HEIGHT }
+        );
+    }
+
+    #[test]
+    fn test_chain_tweak_advance() {
+        // Chains of length 2^4
+        let max_position = (1 << 4) - 1;
+        let start = ChainTweak::new_unchecked(7, 3, 5);
+
+        assert_eq!(start.advance(0, max_position), Ok(start));
+        assert_eq!(start.advance(4, max_position), Ok(ChainTweak::new_unchecked(7, 3, 9)));
+        assert_eq!(start.advance(10, max_position), Ok(ChainTweak::new_unchecked(7, 3, 15)));
+        assert_eq!(
+            start.advance(11, max_position),
+            Err(TweakError::ChainAdvanceOutOfRange { position: 5, steps: 11, max_position: 15 })
+        );
+        assert_eq!(
+            start.advance(11, max_position).unwrap_err().to_string(),
+            "advancing chain position 5 by 11 goes past the last position 15"
+        );
+    }
+
+    #[test]
+    fn test_chain_tweak_advance_at_u16_bounds() {
+        let last = ChainTweak::new_unchecked(0, 0, u16::MAX);
+        assert_eq!(last.advance(0, u16::MAX), Ok(last));
+        assert_eq!(
+            last.advance(1, u16::MAX),
+            Err(TweakError::ChainAdvanceOutOfRange {
+                position: u16::MAX,
+                steps: 1,
+                max_position: u16::MAX
+            })
+        );
+        assert_eq!(ChainTweak::new_unchecked(0, 0, 0).advance(u16::MAX, u16::MAX).unwrap(), last);
+    }
+
+    #[test]
+    fn test_chain_tweak_at_position() {
+        let tweak = ChainTweak::new_unchecked(7, 3, 5);
+        assert_eq!(tweak.at_position(0), ChainTweak::new_unchecked(7, 3, 0));
+        assert_eq!(tweak.at_position(u16::MAX), ChainTweak::new_unchecked(7, 3, u16::MAX));
+    }
+
+    #[test]
+    fn test_chain_tweak_iter_yields_consecutive_positions() {
+        let tweaks: Vec<_> = ChainTweak::iter(7, 3, 2, 5).collect();
+        assert_eq!(
+            tweaks,
+            [
+                ChainTweak::new_unchecked(7, 3, 2),
+                ChainTweak::new_unchecked(7, 3, 3),
+                ChainTweak::new_unchecked(7, 3, 4),
+            ]
+        );
+
+        let mut iter = ChainTweak::iter(7, 3, 2, 5);
+        assert_eq!(iter.len(), 3);
+        assert_eq!(iter.next_back(), Some(ChainTweak::new_unchecked(7, 3, 4)));
+        assert_eq!(iter.next(), Some(ChainTweak::new_unchecked(7, 3, 2)));
+        assert_eq!(iter.len(), 1);
+    }
+
+    #[test]
+    fn test_chain_tweak_iter_empty_and_reversed_ranges() {
+        let mut iter = ChainTweak::iter(1, 1, 4, 4);
+        assert_eq!(iter.len(), 0);
+        assert_eq!(iter.next(), None);
+
+        let mut iter = ChainTweak::iter(1, 1, 5, 2);
+        assert_eq!(iter.len(), 0);
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next_back(), None);
+    }
+
+    #[test]
+    fn test_chain_tweak_iter_reaches_last_position() {
+        const CHUNK_SIZE: usize = 8;
+        let last = ChainTweak::iter(9, 1, 0, 1 << CHUNK_SIZE).next_back();
+        assert_eq!(last, ChainTweak::new::<8, 1, CHUNK_SIZE>(9, 1, 255).ok());
+
+        let mut iter = ChainTweak::iter(9, 1, u16::MAX - 1, 1 << 16);
+        assert_eq!(iter.len(), 2);
+        assert_eq!(iter.next_back(), Some(ChainTweak::new_unchecked(9, 1, u16::MAX)));
+    }
+
+    #[test]
+    fn test_chain_tweak_iter_field_elements() {
+        let encoded: Vec<[BabyBear; 3]> =
+            ChainTweak::iter(1, 2, 3, 5).iter_field_elements().collect();
+        assert_eq!(
+            encoded,
+            [
+                ChainTweak::new_unchecked(1, 2, 3).to_field_elements(),
+                ChainTweak::new_unchecked(1, 2, 4).to_field_elements(),
+            ]
+        );
+        assert_eq!(encoded[0], [BabyBear::new(301990110), BabyBear::new(546), BabyBear::ZERO]);
+    }
+
+    #[test]
+    fn test_leaf_root_and_node_constructors() {
+        // Every node of a tree of height 3, level by level
+        let mut tweaks = Vec::new();
+        tweaks.extend((0..8).map(TreeTweak::leaf));
+        for level in 1..3 {
+            tweaks.extend(
+                (0..1 << (3 - level)).map(|position| TreeTweak::node(level, position, 3).unwrap()),
+            );
+        }
+        tweaks.push(TreeTweak::root(3));
+
+        let mut expected = Vec::new();
+        for level in 0..=3u8 {
+            for position in 0..1u64 << (3 - level) {
+                expected.push(TreeTweak { level, position, layout: TreeTweakLayout::Wide });
+            }
+        }
+        assert_eq!(tweaks, expected);
+
+        // The helpers agree with the tree navigation
+        assert_eq!(
+            TreeTweak::leaf(5).parent(3).and_then(|t| t.parent(3)),
+            TreeTweak::node(2, 1, 3).ok()
+        );
+        assert_eq!(TreeTweak::node(2, 1, 3).unwrap().parent(3), Some(TreeTweak::root(3)));
+        assert_eq!(TreeTweak::root(3).parent(3), None);
+        assert_eq!(TreeTweak::path_tweaks(5, 3).unwrap().last(), Some(TreeTweak::root(3)));
+        assert_eq!(TreeTweak::node(3, 0, 3), TreeTweak::new::<3>(3, 0));
+    }
+
+    #[test]
+    fn test_node_checks_the_tree_height() {
+        assert_eq!(
+            TreeTweak::node(4, 0, 3),
+            Err(TweakError::LevelOutOfRange { level: 4, log_lifetime: 3 })
+        );
+        assert_eq!(
+            TreeTweak::node(1, 4, 3),
+            Err(TweakError::TreePositionOutOfRange { level: 1, position: 4, log_lifetime: 3 })
+        );
+        assert_eq!(
+            TreeTweak::node(0, 0, 65),
+            Err(TweakError::LevelOutOfRange { level: 65, log_lifetime: MAX_TREE_HEIGHT })
+        );
+        assert_eq!(TreeTweak::node(0, u64::MAX, 64), Ok(TreeTweak::new_unchecked(0, u64::MAX)));
+        assert_eq!(TreeTweak::leaf(u32::MAX), TreeTweak::new_unchecked(0, u32::MAX as u64));
+    }
+
+    #[test]
+    fn test_wide_chain_tweak_fields_do_not_overlap() {
+        let epoch = WideChainTweak::new_unchecked(u32::MAX, 0, 0).packed();
+        let chain_index = WideChainTweak::new_unchecked(0, u32::MAX, 0).packed();
+        let position = WideChainTweak::new_unchecked(0, 0, u32::MAX).packed();
+
+        assert_eq!(epoch, 0xffff_ffff << 72);
+        assert_eq!(chain_index, 0xffff_ffff << 40);
+        assert_eq!(position, 0xffff_ffff << 8);
+        assert_eq!(epoch & chain_index, 0);
+        assert_eq!(chain_index & position, 0);
+        assert_eq!(epoch & position, 0);
+
+        // Every bit of the layout belongs to exactly one field or the separator byte
+        let max = WideChainTweak::new_unchecked(u32::MAX, u32::MAX, u32::MAX).packed();
+        assert_eq!(max, epoch | chain_index | position);
+        assert_eq!(max, (1 << WIDE_CHAIN_TWEAK_BITS) - 256);
+    }
+
+    #[test]
+    fn test_wide_chain_tweak_encoding() {
+        assert_eq!(WIDE_CHAIN_TWEAK_LEN, 4);
+
+        let tweaks = [
+            WideChainTweak::new_unchecked(0, 0, 0),
+            WideChainTweak::new_unchecked(1, 2, 3),
+            WideChainTweak::new_unchecked(0, 0, u32::MAX),
+            WideChainTweak::new_unchecked(0, u32::MAX, 0),
+            WideChainTweak::new_unchecked(u32::MAX, u32::MAX, u32::MAX),
+        ];
+        for tweak in tweaks {
+            let elements = tweak.to_field_elements();
+            assert_eq!(elements, limbs_from_packed(tweak.packed()));
+            assert_eq!(WideChainTweak::from_field_elements(&elements), Ok(tweak));
+        }
+
+        // The largest tweak needs the fourth limb
+        let max = WideChainTweak::new_unchecked(u32::MAX, u32::MAX, u32::MAX).to_field_elements();
+        assert_ne!(max[3], BabyBear::ZERO);
+
+        let tree = [BabyBear::new(1), BabyBear::ZERO, BabyBear::ZERO, BabyBear::ZERO];
+        assert_eq!(
+            WideChainTweak::from_field_elements(&tree),
+            Err(TweakDecodeError::WrongSeparator {
+                expected: TweakDomain::Chain.as_u8(),
+                found: TweakDomain::Tree.as_u8()
+            })
+        );
+    }
 
-/// A trait for converting tweaks into field elements for Poseidon2 hashing.
-pub trait TweakTransfformation<const TWEAK_LEN: usize> {
-    /// Converts the tweak into field elements for Poseidon2 hashing.
-    ///
-    /// In Poseidon2, tweaks need to be encoded as elements of a finite field.
-    fn to_field_elements(&self) -> [BabyBear; TWEAK_LEN];
-}
+    #[test]
+    fn test_wide_chain_tweak_is_a_separate_encoding() {
+        // Converting keeps the fields but not the encoding, existing vectors stay valid
+        let tweak = ChainTweak::new_unchecked(1, 2, 3);
+        let wide = WideChainTweak::from(tweak);
+        assert_eq!(wide, WideChainTweak::new_unchecked(1, 2, 3));
+        assert_ne!(wide.packed(), tweak.packed());
+        assert_eq!(
+            tweak.to_field_elements(),
+            [BabyBear::new(301990110), BabyBear::new(546), BabyBear::ZERO]
+        );
+    }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct TreeTweak {
-    /// The depth of the node in the Merkle tree.
-    level: u8,
-    /// The position of the node in the given level.
-    position: u32,
-}
+    #[test]
+    fn test_wide_chain_tweak_new_checks_ranges() {
+        assert_eq!(
+            WideChainTweak::new::<8, 17, 20>(255, 1 << 16, (1 << 20) - 1),
+            Ok(WideChainTweak::new_unchecked(255, 1 << 16, (1 << 20) - 1))
+        );
+        assert_eq!(
+            WideChainTweak::new::<8, 17, 20>(256, 0, 0),
+            Err(TweakError::EpochOutOfRange { epoch: 256, log_lifetime: 8 })
+        );
+        assert_eq!(
+            WideChainTweak::new::<8, 17, 20>(0, 1 << 17, 0),
+            Err(TweakError::ChainIndexOutOfRange { chain_index: 1 << 17, ceil_log_num_chains: 17 })
+        );
+        assert_eq!(
+            WideChainTweak::new::<8, 17, 20>(0, 0, 1 << 20),
+            Err(TweakError::ChainPositionOutOfRange { position: 1 << 20, chunk_size: 20 })
+        );
+        assert!(WideChainTweak::new::<32, 32, 32>(u32::MAX, u32::MAX, u32::MAX).is_ok());
+    }
 
-impl<const TWEAK_LEN: usize> TweakTransfformation<TWEAK_LEN> for TreeTweak {
-    fn to_field_elements(&self) -> [BabyBear; TWEAK_LEN] {
-        // Construct the tweak using bitwise shifts to encode level and position
-        let mut tweak = ((self.level as u64) << 40) |
-            ((self.position as u64) << 8) |
-            (TWEAK_SEPARATOR_FOR_TREE_HASH as u64);
+    #[test]
+    #[allow(deprecated)]
+    fn test_tweak_domain_values_are_stable() {
+        // The separators are part of every encoded tweak, changing one changes every hash
+        assert_eq!(TweakDomain::Chain.as_u8(), 0x00);
+        assert_eq!(TweakDomain::Tree.as_u8(), 0x01);
+        assert_eq!(TweakDomain::MessageHash.as_u8(), 0x02);
+
+        assert_eq!(TWEAK_SEPARATOR_FOR_CHAIN_HASH, 0x00);
+        assert_eq!(TWEAK_SEPARATOR_FOR_TREE_HASH, 0x01);
+        assert_eq!(TWEAK_SEPARATOR_FOR_MESSAGE_HASH, 0x02);
+
+        for domain in TweakDomain::ALL {
+            assert_eq!(TweakDomain::from_u8(domain.as_u8()), Some(domain));
+        }
+        assert_eq!(TweakDomain::from_u8(0x03), None);
+        assert_eq!(TweakDomain::from_u8(0xff), None);
+    }
 
-        // Prime modulus used to map the tweak into the finite field `BabyBear`
-        let prime = BabyBearParameters::PRIME as u64;
+    #[test]
+    fn test_tweak_domain_matches_packed_separator() {
+        let tweaks = [
+            PoseidonTweak::Tree(TreeTweak::new_unchecked(3, 17)),
+            PoseidonTweak::Tree(TreeTweak::new_legacy(3, 17)),
+            PoseidonTweak::Chain(ChainTweak::new_unchecked(9, 4, 2)),
+            PoseidonTweak::Message(MessageTweak { epoch: 5 }),
+        ];
+        for tweak in tweaks {
+            assert_eq!(tweak.packed() as u8, tweak.domain().as_u8());
+            assert_eq!(tweak.packed_with_key_id(7) as u8, tweak.domain().as_u8());
+        }
+
+        assert_eq!(TweakDomain::Tree.select("chain", "tree", "message"), "tree");
+        assert_eq!(TweakDomain::MessageHash.select(0, 1, 2), 2);
+    }
 
-        // Extract field elements efficiently by dividing tweak into chunks mod `prime`
-        let mut result = [BabyBear::ZERO; TWEAK_LEN];
-        for r in result.iter_mut() {
-            if tweak == 0 {
-                break;
+    #[test]
+    fn test_chain_tweak_cache_matches_naive_encoding() {
+        // Position 0 of chain 8 of epoch 7 has a low limb 3823 below the prime, so the low limb
+        // wraps from position 15 on
+        let wrapping: ChainTweakCache = ChainTweakCache::new(7, 8);
+        assert!(wrapping.get(15)[0] < wrapping.get(0)[0]);
+
+        for (epoch, chain_index) in [(7, 8), (0, 0), (123456, 28), (u32::MAX, u16::MAX)] {
+            let cache: ChainTweakCache = ChainTweakCache::new(epoch, chain_index);
+            assert_eq!((cache.epoch(), cache.chain_index()), (epoch, chain_index));
+            for position in 0..=u16::MAX {
+                let tweak = ChainTweak::new_unchecked(epoch, chain_index, position);
+                assert_eq!(cache.get(position), tweak.to_field_elements(), "{tweak:?}");
             }
-            *r = BabyBear::new((tweak % prime) as u32);
-            tweak /= prime;
         }
 
-        result
+        // Wider caches pad the naive encoding with zero limbs
+        let wide = ChainTweakCache::<5>::new(u32::MAX, u16::MAX);
+        for position in [0, 1, u16::MAX] {
+            let tweak = ChainTweak::new_unchecked(u32::MAX, u16::MAX, position);
+            assert_eq!(wide.get(position), limbs_from_packed::<5>(tweak.packed()));
+        }
     }
-}
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct ChainTweak {
-    /// The key epoch (time interval) of the signature scheme.
-    epoch: u32,
-    /// Which chain the value belongs to.
-    chain_index: u16,
-    /// The position of the value in the chain.
-    position: u16,
-}
+    #[test]
+    #[should_panic(expected = "does not fit in a u16")]
+    fn test_chain_tweak_iter_rejects_end_past_u16() {
+        let _ = ChainTweak::iter(0, 0, 0, (1 << 16) + 1);
+    }
 
-impl<const TWEAK_LEN: usize> TweakTransfformation<TWEAK_LEN> for ChainTweak {
-    /// Converts the tweak into field elements for Poseidon2 hashing.
-    ///
-    /// In Poseidon2, tweaks need to be encoded as elements of a finite field.
-    fn to_field_elements(&self) -> [BabyBear; TWEAK_LEN] {
-        // Construct the tweak using bitwise shifts to encode epoch, chain_index and position
-        let mut tweak = ((self.epoch as u128) << 40) |
-            ((self.chain_index as u128) << 24) |
-            ((self.position as u128) << 8) |
-            TWEAK_SEPARATOR_FOR_CHAIN_HASH as u128;
+    #[test]
+    fn test_chain_tweak_encode_batch_matches_single_encoding() {
+        let mut rng = StdRng::seed_from_u64(1);
+
+        // Whole chains, as walked during key generation, followed by unrelated tweaks
+        let mut tweaks: Vec<_> = (0..64)
+            .flat_map(|chain_index| ChainTweak::iter(rng.gen(), chain_index, 0, 1 << 8))
+            .collect();
+        tweaks.extend(ChainTweak::iter(u32::MAX, u16::MAX, u16::MAX - 3, 1 << 16));
+        tweaks.extend((0..1000).map(|_| ChainTweak {
+            epoch: rng.gen(),
+            chain_index: rng.gen(),
+            position: rng.gen(),
+        }));
+
+        let mut batched = vec![[BabyBear::ZERO; TWEAK_LEN]; tweaks.len()];
+        ChainTweak::encode_batch(&tweaks, &mut batched);
+        for (tweak, encoded) in tweaks.iter().zip(&batched) {
+            assert_eq!(*encoded, tweak.to_field_elements(), "{tweak:?}");
+        }
+    }
+
+    #[test]
+    fn test_tree_tweak_encode_batch_matches_single_encoding() {
+        let mut rng = StdRng::seed_from_u64(2);
+
+        // Every node of a tree, level by level, followed by unrelated tweaks
+        let mut tweaks: Vec<_> = (0..=10u8)
+            .flat_map(|level| {
+                (0..1 << (10 - level)).map(move |p| TreeTweak::new_unchecked(level, p))
+            })
+            .collect();
+        tweaks.push(TreeTweak::new_unchecked(0, u64::MAX));
+        tweaks.push(TreeTweak::new_unchecked(u8::MAX, u64::MAX));
+        // Runs on the same level in both layouts do not share their limbs
+        tweaks.extend((0..4).map(|p| TreeTweak::new_legacy(3, p)));
+        tweaks.extend((0..4).map(|p| TreeTweak::new_unchecked(3, p)));
+        tweaks.push(TreeTweak::new_legacy(u8::MAX, u32::MAX));
+        tweaks.extend((0..1000).map(|_| TreeTweak::new_unchecked(rng.gen(), rng.gen())));
+
+        let mut batched = vec![[BabyBear::ZERO; TWEAK_LEN]; tweaks.len()];
+        TreeTweak::encode_batch(&tweaks, &mut batched);
+        for (tweak, encoded) in tweaks.iter().zip(&batched) {
+            assert_eq!(*encoded, tweak.to_field_elements(), "{tweak:?}");
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "one output is needed per chain tweak")]
+    fn test_encode_batch_rejects_length_mismatch() {
+        let tweaks = [ChainTweak::new_unchecked(0, 0, 0); 3];
+        ChainTweak::encode_batch(&tweaks, &mut [[BabyBear::ZERO; TWEAK_LEN]; 2]);
+    }
+
+    #[test]
+    fn test_new_unchecked_matches_struct() {
+        assert_eq!(
+            TreeTweak::new_unchecked(3, 7),
+            TreeTweak { level: 3, position: 7, layout: TreeTweakLayout::Wide }
+        );
+        assert_eq!(
+            TreeTweak::new_legacy(3, 7),
+            TreeTweak { level: 3, position: 7, layout: TreeTweakLayout::Legacy }
+        );
+        assert_eq!(
+            ChainTweak::new_unchecked(1, 2, 3),
+            ChainTweak { epoch: 1, chain_index: 2, position: 3 }
+        );
+    }
+
+    #[test]
+    fn test_tweak_decode_roundtrip_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+
+        for _ in 0..1000 {
+            let tree = TreeTweak::new_unchecked(rng.gen(), rng.gen());
+            let encoded = tree.to_field_elements();
+            assert_eq!(TreeTweak::from_field_elements(&encoded), Ok(tree));
+            assert_eq!(PoseidonTweak::from_field_elements(&encoded), Ok(PoseidonTweak::Tree(tree)));
+
+            let chain =
+                ChainTweak { epoch: rng.gen(), chain_index: rng.gen(), position: rng.gen() };
+            let encoded = chain.to_field_elements();
+            assert_eq!(ChainTweak::from_field_elements(&encoded), Ok(chain));
+            assert_eq!(
+                PoseidonTweak::from_field_elements(&encoded),
+                Ok(PoseidonTweak::Chain(chain))
+            );
+
+            let message = MessageTweak { epoch: rng.gen() };
+            let encoded = message.to_field_elements();
+            assert_eq!(MessageTweak::from_field_elements(&encoded), Ok(message));
+            assert_eq!(
+                PoseidonTweak::from_field_elements(&encoded),
+                Ok(PoseidonTweak::Message(message))
+            );
+        }
+    }
+
+    #[test]
+    fn test_tweak_decode_roundtrip_edge_cases() {
+        for tree in [TreeTweak::new_unchecked(0, 0), TreeTweak::new_unchecked(u8::MAX, u64::MAX)] {
+            let encoded = tree.to_field_elements();
+            assert_eq!(TreeTweak::from_field_elements(&encoded), Ok(tree));
+        }
+
+        for chain in [
+            ChainTweak { epoch: 0, chain_index: 0, position: 0 },
+            ChainTweak { epoch: u32::MAX, chain_index: u16::MAX, position: u16::MAX },
+        ] {
+            let encoded = chain.to_field_elements();
+            assert_eq!(ChainTweak::from_field_elements(&encoded), Ok(chain));
+        }
+    }
+
+    #[test]
+    fn test_tweak_decode_wrong_separator() {
+        let encoded = ChainTweak { epoch: 1, chain_index: 2, position: 3 }.to_field_elements();
+        assert_eq!(
+            TreeTweak::from_field_elements(&encoded),
+            Err(TweakDecodeError::WrongSeparator {
+                expected: TweakDomain::Tree.as_u8(),
+                found: TweakDomain::Chain.as_u8()
+            })
+        );
+
+        let encoded = TreeTweak::new_unchecked(1, 2).to_field_elements();
+        assert_eq!(
+            ChainTweak::from_field_elements(&encoded),
+            Err(TweakDecodeError::WrongSeparator {
+                expected: TweakDomain::Chain.as_u8(),
+                found: TweakDomain::Tree.as_u8()
+            })
+        );
+        assert_eq!(
+            MessageTweak::from_field_elements(&encoded),
+            Err(TweakDecodeError::WrongSeparator {
+                expected: TweakDomain::MessageHash.as_u8(),
+                found: TweakDomain::Tree.as_u8()
+            })
+        );
+
+        assert_eq!(
+            PoseidonTweak::from_field_elements(&[
+                BabyBear::new(0x03),
+                BabyBear::ZERO,
+                BabyBear::ZERO
+            ]),
+            Err(TweakDecodeError::UnknownSeparator(0x03))
+        );
+    }
+
+    #[test]
+    fn test_tweak_decode_overflow() {
+        // A level that does not fit in a byte
+        let encoded = [BabyBear::new(0x01), BabyBear::ZERO, BabyBear::new(1 << 20)];
+        assert_eq!(TreeTweak::from_field_elements(&encoded), Err(TweakDecodeError::Overflow));
+
+        // An epoch wider than 32 bits
+        let encoded = [BabyBear::ZERO, BabyBear::ZERO, BabyBear::new(1 << 20)];
+        assert_eq!(ChainTweak::from_field_elements(&encoded), Err(TweakDecodeError::Overflow));
+        assert_eq!(
+            MessageTweak::from_field_elements(&[
+                BabyBear::new(0x02),
+                BabyBear::new(1 << 20),
+                BabyBear::ZERO
+            ]),
+            Err(TweakDecodeError::Overflow)
+        );
+
+        // The largest limbs recombine into a chain tweak whose epoch is far too wide
+        let encoded = [BabyBear::NEG_ONE; TWEAK_LEN];
+        assert_eq!(PoseidonTweak::from_field_elements(&encoded), Err(TweakDecodeError::Overflow));
+    }
+
+    #[test]
+    fn test_min_tweak_len() {
+        assert_eq!(min_tweak_len(0), 0);
+        assert_eq!(min_tweak_len(30), 1);
+        // log2(p) is just below 31
+        assert_eq!(min_tweak_len(31), 2);
+        assert_eq!(min_tweak_len(MESSAGE_TWEAK_BITS), 2);
+        assert_eq!(min_tweak_len(LEGACY_TREE_TWEAK_BITS), 2);
+        assert_eq!(min_tweak_len(TREE_TWEAK_BITS), 3);
+        assert_eq!(min_tweak_len(CHAIN_TWEAK_BITS), 3);
+        assert_eq!(min_tweak_len(128), 5);
+    }
+
+    #[test]
+    fn test_min_tweak_len_holds_largest_tweaks() {
+        // The largest tweaks of each type fill exactly the minimum number of limbs
+        let tree = TreeTweak::new_unchecked(u8::MAX, u64::MAX).to_field_elements();
+        assert_ne!(tree[2], BabyBear::ZERO);
+        assert_eq!(
+            TreeTweak::from_field_elements(&tree),
+            Ok(TreeTweak::new_unchecked(u8::MAX, u64::MAX))
+        );
+
+        let max_chain = ChainTweak { epoch: u32::MAX, chain_index: u16::MAX, position: u16::MAX };
+        let chain = max_chain.to_field_elements();
+        assert_ne!(chain[2], BabyBear::ZERO);
+        assert_eq!(ChainTweak::from_field_elements(&chain), Ok(max_chain));
+    }
+
+    #[test]
+    fn test_tweak_bytes_golden_vectors() {
+        assert_eq!(
+            TreeTweak::new_unchecked(3, 17).to_bytes(),
+            Ok([0x03, 0, 0, 0, 0, 0, 0, 0, 0x11, 0x01])
+        );
+        assert_eq!(
+            TreeTweak::new_unchecked(0xab, 0x0102_0304_0506_0708).to_bytes(),
+            Ok([0xab, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x01])
+        );
+        assert_eq!(
+            ChainTweak { epoch: 0x0102_0304, chain_index: 0x0506, position: 0x0708 }.to_bytes(),
+            [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x00]
+        );
+        assert_eq!(MessageTweak { epoch: 0x0102_0304 }.to_bytes(), [0x01, 0x02, 0x03, 0x04, 0x02]);
+    }
+
+    #[test]
+    fn test_legacy_tree_tweaks_roundtrip_with_their_layout() {
+        let legacy = [
+            TreeTweak::new_legacy(0, 0),
+            TreeTweak::new_legacy(3, 17),
+            TreeTweak::new_legacy(25, 26282),
+            TreeTweak::new_legacy(u8::MAX, u32::MAX),
+        ];
+        for tweak in legacy {
+            let layout = TreeTweakLayout::Legacy;
+            assert_eq!(
+                TreeTweak::from_field_elements_with_layout(&tweak.to_field_elements(), layout),
+                Ok(tweak)
+            );
+            // The byte encoding would decode into a wide tweak, so it is refused
+            assert_eq!(tweak.to_bytes(), Err(TweakError::LegacyLayout));
+            #[cfg(feature = "alloc")]
+            assert_eq!(PoseidonTweak::Tree(tweak).to_bytes(), Err(TweakError::LegacyLayout));
+        }
+
+        // The legacy tweak (3, 17) packs like the wide tweak (0, 3 << 32 | 17), which the default
+        // decoding returns
+        let elements = TreeTweak::new_legacy(3, 17).to_field_elements();
+        assert_eq!(
+            TreeTweak::from_field_elements(&elements),
+            Ok(TreeTweak::new_unchecked(0, 3 << 32 | 17))
+        );
+
+        // Wide tweaks keep their layout, and wide positions do not fit the legacy one
+        let wide = TreeTweak::new_unchecked(3, 1 << 32);
+        assert_eq!(
+            TreeTweak::from_field_elements_with_layout(
+                &wide.to_field_elements(),
+                TreeTweakLayout::Wide
+            ),
+            Ok(wide)
+        );
+        assert_eq!(TreeTweak::from_bytes(wide.to_bytes().unwrap()), Ok(wide));
+        assert_eq!(
+            TreeTweak::from_field_elements_with_layout(
+                &TreeTweak::new_unchecked(1, 0).to_field_elements(),
+                TreeTweakLayout::Legacy
+            ),
+            Err(TweakDecodeError::Overflow)
+        );
+    }
 
-        // Prime modulus used to map the tweak into the finite field `BabyBear`
-        let prime = BabyBearParameters::PRIME as u128;
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn test_poseidon_tweak_bytes_golden_vectors() {
+        assert_eq!(
+            PoseidonTweak::Tree(TreeTweak::new_unchecked(3, 17)).to_bytes().unwrap(),
+            [0x0a, 0x03, 0, 0, 0, 0, 0, 0, 0, 0x11, 0x01]
+        );
+        assert_eq!(
+            PoseidonTweak::Chain(ChainTweak { epoch: 9, chain_index: 4, position: 2 })
+                .to_bytes()
+                .unwrap(),
+            [0x09, 0, 0, 0, 0x09, 0, 0x04, 0, 0x02, 0x00]
+        );
+        assert_eq!(
+            PoseidonTweak::Message(MessageTweak { epoch: 5 }).to_bytes().unwrap(),
+            [0x05, 0, 0, 0, 0x05, 0x02]
+        );
+    }
 
-        // Extract field elements efficiently by dividing tweak into chunks mod `prime`
-        let mut result = [BabyBear::ZERO; TWEAK_LEN];
-        for r in result.iter_mut() {
-            if tweak == 0 {
-                break;
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn test_tweak_bytes_roundtrip_random() {
+        let mut rng = StdRng::seed_from_u64(1);
+
+        for _ in 0..1000 {
+            let tree = TreeTweak::new_unchecked(rng.gen(), rng.gen());
+            assert_eq!(TreeTweak::from_bytes(tree.to_bytes().unwrap()), Ok(tree));
+
+            let chain =
+                ChainTweak { epoch: rng.gen(), chain_index: rng.gen(), position: rng.gen() };
+            assert_eq!(ChainTweak::from_bytes(chain.to_bytes()), Ok(chain));
+
+            let message = MessageTweak { epoch: rng.gen() };
+            assert_eq!(MessageTweak::from_bytes(message.to_bytes()), Ok(message));
+
+            for tweak in [
+                PoseidonTweak::Tree(tree),
+                PoseidonTweak::Chain(chain),
+                PoseidonTweak::Message(message),
+            ] {
+                assert_eq!(PoseidonTweak::from_bytes(&tweak.to_bytes().unwrap()), Ok(tweak));
             }
-            *r = BabyBear::new((tweak % prime) as u32);
-            tweak /= prime;
         }
+    }
+
+    #[test]
+    fn test_tweak_bytes_match_packed_integer() {
+        // The byte encoding is the big-endian packed integer recombined from the field elements
+        let tree = TreeTweak::new_unchecked(200, 123_456_789_012);
+        let packed = packed_from_limbs(&tree.to_field_elements()).unwrap();
+        assert_eq!(packed.to_be_bytes()[16 - TreeTweak::BYTES_LEN..], tree.to_bytes().unwrap());
 
-        result
+        let chain = ChainTweak { epoch: 987_654_321, chain_index: 4321, position: 1234 };
+        let packed = packed_from_limbs(&chain.to_field_elements()).unwrap();
+        assert_eq!(packed.to_be_bytes()[16 - ChainTweak::BYTES_LEN..], chain.to_bytes());
     }
-}
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub enum PoseidonTweak {
-    Tree(TreeTweak),
-    Chain(ChainTweak),
-}
+    #[test]
+    fn test_limbs_from_packed_matches_to_field_elements() {
+        let mut rng = StdRng::seed_from_u64(6);
+        for _ in 0..100 {
+            let tree = TreeTweak::new_unchecked(rng.gen(), rng.gen());
+            assert_eq!(limbs_from_packed(tree.packed()), tree.to_field_elements());
 
-impl<const TWEAK_LEN: usize> TweakTransfformation<TWEAK_LEN> for PoseidonTweak {
-    fn to_field_elements(&self) -> [BabyBear; TWEAK_LEN] {
-        match self {
-            PoseidonTweak::Tree(tree_tweak) => tree_tweak.to_field_elements(),
-            PoseidonTweak::Chain(chain_tweak) => chain_tweak.to_field_elements(),
+            let chain =
+                ChainTweak { epoch: rng.gen(), chain_index: rng.gen(), position: rng.gen() };
+            assert_eq!(limbs_from_packed(chain.packed()), chain.to_field_elements());
         }
+
+        // The packed integer is the one recombined from the limbs
+        let chain = ChainTweak { epoch: u32::MAX, chain_index: u16::MAX, position: u16::MAX };
+        assert_eq!(packed_from_limbs(&chain.to_field_elements()), Ok(chain.packed()));
+        assert_eq!(TreeTweak::new_unchecked(1, 2).packed(), (1 << 72) | (2 << 8) | 0x01);
+        assert_eq!(TreeTweak::new_legacy(1, 2).packed(), (1 << 40) | (2 << 8) | 0x01);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_limbs_from_packed_matches_early_exit_split() {
+        // The split that stops at the first zero quotient, as tweaks used to be encoded
+        fn early_exit<const N: usize>(mut packed: u128) -> [BabyBear; N] {
+            let prime = BabyBearParameters::PRIME as u128;
+            let mut result = [BabyBear::ZERO; N];
+            for r in result.iter_mut() {
+                if packed == 0 {
+                    break;
+                }
+                *r = BabyBear::new((packed % prime) as u32);
+                packed /= prime;
+            }
+            result
+        }
+
+        let tweaks = [
+            PoseidonTweak::Tree(TreeTweak::new_legacy(0, 0)),
+            PoseidonTweak::Tree(TreeTweak::new_legacy(25, 26282)),
+            PoseidonTweak::Tree(TreeTweak::new_unchecked(u8::MAX, u64::MAX)),
+            PoseidonTweak::Chain(ChainTweak::new_unchecked(0, 0, 0)),
+            PoseidonTweak::Chain(ChainTweak::new_unchecked(1, 2, 3)),
+            PoseidonTweak::Chain(ChainTweak::new_unchecked(u32::MAX, u16::MAX, u16::MAX)),
+            PoseidonTweak::Message(MessageTweak { epoch: 123456 }),
+        ];
+        for tweak in tweaks {
+            assert_eq!(tweak.to_field_elements(), early_exit(tweak.packed()), "{tweak:?}");
+        }
+
+        let mut rng = StdRng::seed_from_u64(12);
+        for _ in 0..1000 {
+            let packed: u128 = rng.gen::<u128>() >> rng.gen_range(0..128);
+            assert_eq!(limbs_from_packed::<5>(packed), early_exit::<5>(packed));
+            assert_eq!(limbs_from_packed::<3>(packed), early_exit::<3>(packed));
+        }
+        assert_eq!(limbs_from_packed::<3>(0), [BabyBear::ZERO; 3]);
+    }
 
     #[test]
-    fn test_tree_tweak_to_field_elements_small_values() {
-        let tweak = TreeTweak { level: 1, position: 2 };
-        let field_elements = tweak.to_field_elements();
-        assert_eq!(field_elements, [BabyBear::new(268435423), BabyBear::new(546)]);
+    fn test_base_p_limbs_matches_to_field_elements() {
+        let mut rng = StdRng::seed_from_u64(8);
+        for _ in 0..100 {
+            let tree = TreeTweak::new_unchecked(rng.gen(), rng.gen());
+            assert_eq!(BasePLimbs::encode(tree.packed()), Ok(tree.to_field_elements()));
+
+            let chain =
+                ChainTweak { epoch: rng.gen(), chain_index: rng.gen(), position: rng.gen() };
+            assert_eq!(BasePLimbs::encode(chain.packed()), Ok(chain.to_field_elements()));
+        }
     }
 
     #[test]
-    fn test_tree_tweak_to_field_elements_random_values() {
+    fn test_bytes_per_element_vectors() {
         assert_eq!(
-            TreeTweak { level: u8::MAX, position: u32::MAX }.to_field_elements(),
-            [
-                BabyBear::new(268295391),
-                BabyBear::new(139810),
-                BabyBear::ZERO,
+            BytesPerElement::encode(TreeTweak::new_legacy(3, 17).packed()),
+            Ok([BabyBear::new(0x00_11_01), BabyBear::new(0x03_00_00), BabyBear::ZERO])
+        );
+        assert_eq!(
+            BytesPerElement::encode(ChainTweak { epoch: 1, chain_index: 2, position: 3 }.packed()),
+            Ok([BabyBear::new(0x00_03_00), BabyBear::new(0x01_00_02), BabyBear::ZERO])
+        );
+        assert_eq!(
+            BytesPerElement::encode(
+                ChainTweak { epoch: u32::MAX, chain_index: u16::MAX, position: u16::MAX }.packed()
+            ),
+            Ok([BabyBear::new(0xff_ff_00), BabyBear::new(0xff_ff_ff), BabyBear::new(0xff_ff_ff)])
+        );
+        assert_eq!(
+            BytesPerElement::encode(MessageTweak { epoch: 5 }.packed().into()),
+            Ok([BabyBear::new(0x05_02), BabyBear::ZERO, BabyBear::ZERO])
+        );
+    }
+
+    #[test]
+    fn test_bytes_per_element_rejects_wide_tree_tweaks() {
+        // Level 1 is bit 72 of the wide layout, one bit past the 72 bits of TWEAK_LEN elements
+        assert_eq!(
+            BytesPerElement::encode(TreeTweak::new_unchecked(1, 0).packed()),
+            Err(TweakError::Overflow { required: 4, provided: TWEAK_LEN })
+        );
+        assert!(BytesPerElement::encode(TreeTweak::new_unchecked(0, u64::MAX).packed()).is_ok());
+    }
+
+    #[test]
+    fn test_try_to_field_elements_matches_fixed_length() {
+        let mut rng = StdRng::seed_from_u64(9);
+        for _ in 0..100 {
+            let tree = TreeTweak::new_unchecked(rng.gen(), rng.gen());
+            assert_eq!(tree.try_to_field_elements(TWEAK_LEN).unwrap(), tree.to_field_elements());
+
+            let chain =
+                ChainTweak { epoch: rng.gen(), chain_index: rng.gen(), position: rng.gen() };
+            assert_eq!(chain.try_to_field_elements(TWEAK_LEN).unwrap(), chain.to_field_elements());
+        }
+
+        // Longer encodings are padded with zeros
+        let tree = TreeTweak::new_legacy(1, 2);
+        assert_eq!(
+            tree.try_to_field_elements(5),
+            Ok(vec![
+                BabyBear::new(268435423),
+                BabyBear::new(546),
                 BabyBear::ZERO,
                 BabyBear::ZERO,
                 BabyBear::ZERO
-            ]
+            ])
         );
+    }
 
+    #[test]
+    fn test_try_to_field_elements_rejects_overflow() {
+        let chain = ChainTweak { epoch: u32::MAX, chain_index: u16::MAX, position: u16::MAX };
         assert_eq!(
-            TreeTweak { level: 25, position: 26282 }.to_field_elements(),
-            [BabyBear::new(677803180), BabyBear::new(13653),]
+            chain.try_to_field_elements(2),
+            Err(TweakError::Overflow { required: 3, provided: 2 })
         );
-    }
 
-    #[test]
-    fn test_tree_tweak_to_field_elements_edge_cases() {
-        let tweak = TreeTweak { level: 0, position: 0 };
-        let field_elements = tweak.to_field_elements();
-        // Only the separator should be set
+        // A legacy tree tweak needs two limbs, a small one only the first
+        let tree = TreeTweak::new_legacy(1, 2);
         assert_eq!(
-            field_elements,
-            [BabyBear::new(TWEAK_SEPARATOR_FOR_TREE_HASH as u32), BabyBear::ZERO]
+            tree.try_to_field_elements(1),
+            Err(TweakError::Overflow { required: 2, provided: 1 })
+        );
+        assert_eq!(
+            TreeTweak::new_legacy(0, 0).try_to_field_elements(1),
+            Ok(vec![BabyBear::new(TweakDomain::Tree.as_u8() as u32)])
+        );
+        assert_eq!(
+            ChainTweak { epoch: 0, chain_index: 0, position: 0 }.try_to_field_elements(0),
+            Ok(vec![])
         );
     }
 
     #[test]
-    fn test_chain_tweak_to_field_elements_small_values() {
-        let tweak = ChainTweak { epoch: 1, chain_index: 2, position: 3 };
-        let field_elements = tweak.to_field_elements();
-        assert_eq!(field_elements, [BabyBear::new(301990110), BabyBear::new(546)]);
+    fn test_packed_with_key_id() {
+        let tree = TreeTweak::new_legacy(3, 17);
+        let chain = ChainTweak { epoch: 9, chain_index: 4, position: 2 };
+        let message = MessageTweak { epoch: 5 };
+
+        // Key id 0 is the legacy packed integer
+        assert_eq!(tree.packed_with_key_id(0), tree.packed());
+        assert_eq!(chain.packed_with_key_id(0), chain.packed());
+        assert_eq!(message.packed_with_key_id(0), u128::from(message.packed()));
+
+        assert_eq!(tree.packed_with_key_id(7), 0x7_03_00000011_01);
+        assert_eq!(chain.packed_with_key_id(7), 0x7_00000009_0004_0002_00);
+        assert_eq!(message.packed_with_key_id(7), 0x7_00000005_02);
+        assert_eq!(
+            TreeTweak::new_unchecked(3, 17).packed_with_key_id(7),
+            0x7_03_0000000000000011_01
+        );
+        assert_eq!(
+            PoseidonTweak::Chain(chain).packed_with_key_id(u32::MAX),
+            (u128::from(u32::MAX) << CHAIN_TWEAK_BITS) | chain.packed()
+        );
     }
 
     #[test]
-    fn test_chain_tweak_to_field_elements_random_values() {
-        let tweak = ChainTweak { epoch: u32::MAX, chain_index: u16::MAX, position: u16::MAX };
-        let field_elements = tweak.to_field_elements();
+    fn test_keyed_encoding_vectors() {
+        assert_eq!(KEYED_TWEAK_LEN, 5);
+
+        // A zero key id keeps the legacy limbs, followed by zeros
+        let chain = ChainTweak { epoch: 1, chain_index: 2, position: 3 };
+        let keyed = BasePLimbs::encode_keyed(chain.packed_with_key_id(0));
+        assert_eq!(keyed[..TWEAK_LEN], chain.to_field_elements());
+        assert_eq!(keyed[TWEAK_LEN..], [BabyBear::ZERO; KEYED_TWEAK_LEN - TWEAK_LEN]);
+
+        let chain = ChainTweak { epoch: u32::MAX, chain_index: u16::MAX, position: u16::MAX };
         assert_eq!(
-            field_elements,
+            BytesPerElement::encode_keyed(chain.packed_with_key_id(u32::MAX)),
             [
-                BabyBear::new(98427243),
-                BabyBear::new(170006792),
-                BabyBear::new(1165),
-                BabyBear::ZERO
+                BabyBear::new(0xff_ff_00),
+                BabyBear::new(0xff_ff_ff),
+                BabyBear::new(0xff_ff_ff),
+                BabyBear::new(0xff_ff_ff),
+                BabyBear::new(0xff)
             ]
         );
+
+        // The same node under two key ids
+        let tree = TreeTweak::new_unchecked(3, 17);
+        assert_ne!(
+            BasePLimbs::encode_keyed(tree.packed_with_key_id(1)),
+            BasePLimbs::encode_keyed(tree.packed_with_key_id(2))
+        );
     }
 
     #[test]
-    fn test_chain_tweak_to_field_elements_edge_cases() {
-        let tweak = ChainTweak { epoch: 0, chain_index: 0, position: 0 };
-        let field_elements = tweak.to_field_elements();
-        // Only the separator should be set
+    fn test_tweak_from_bytes_rejects_invalid_encodings() {
         assert_eq!(
-            field_elements,
-            [BabyBear::new(TWEAK_SEPARATOR_FOR_CHAIN_HASH as u32), BabyBear::ZERO]
+            TreeTweak::from_bytes([0x03, 0, 0, 0, 0, 0, 0, 0, 0x11, 0x00]),
+            Err(TweakDecodeError::WrongSeparator {
+                expected: TweakDomain::Tree.as_u8(),
+                found: TweakDomain::Chain.as_u8()
+            })
+        );
+
+        // Empty input and a length prefix that does not match the body
+        assert_eq!(
+            PoseidonTweak::from_bytes(&[]),
+            Err(TweakDecodeError::InvalidLength { expected: 1, found: 0 })
+        );
+        assert_eq!(
+            PoseidonTweak::from_bytes(&[0x06, 0x03, 0, 0, 0, 0x11]),
+            Err(TweakDecodeError::InvalidLength { expected: 7, found: 6 })
+        );
+        assert_eq!(
+            PoseidonTweak::from_bytes(&[0x00]),
+            Err(TweakDecodeError::InvalidLength { expected: 6, found: 1 })
+        );
+
+        // A well-formed prefix whose body has the wrong length for its separator
+        assert_eq!(
+            PoseidonTweak::from_bytes(&[0x05, 0, 0, 0, 0x11, 0x01]),
+            Err(TweakDecodeError::InvalidLength { expected: 11, found: 6 })
+        );
+        assert_eq!(
+            PoseidonTweak::from_bytes(&[0x02, 0x00, 0x07]),
+            Err(TweakDecodeError::UnknownSeparator(0x07))
         );
     }
 }
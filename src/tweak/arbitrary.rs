@@ -0,0 +1,125 @@
+//! `proptest` strategies for the tweak types.
+//!
+//! The strategies cover the full valid range of every component and draw its boundary values
+//! (zero and the largest value) more often than uniform sampling would. Tree tweaks are valid
+//! nodes of the largest tree, as for [`TreeTweak::new`] with [`MAX_TREE_HEIGHT`].
+
+use super::{ChainTweak, MessageTweak, PoseidonTweak, TreeTweak, MAX_TREE_HEIGHT};
+use core::{fmt::Debug, ops::RangeInclusive};
+use proptest::prelude::*;
+
+/// Values in `0..=max`, with both bounds drawn more often.
+fn with_boundaries<T>(max: T) -> impl Strategy<Value = T>
+where
+    T: Copy + Debug + Default + 'static,
+    RangeInclusive<T>: Strategy<Value = T>,
+{
+    prop_oneof![
+        1 => Just(T::default()),
+        1 => Just(max),
+        8 => T::default()..=max,
+    ]
+}
+
+/// Tree tweaks of any node of a tree of height [`MAX_TREE_HEIGHT`].
+pub fn tree_tweak() -> impl Strategy<Value = TreeTweak> {
+    with_boundaries(MAX_TREE_HEIGHT as u8).prop_flat_map(|level| {
+        // Level `level` of a tree of height 64 holds `2^(64 - level)` nodes
+        let max_position = u64::MAX.checked_shr(level as u32).unwrap_or(0);
+        with_boundaries(max_position)
+            .prop_map(move |position| TreeTweak::new_unchecked(level, position))
+    })
+}
+
+/// Chain tweaks with any epoch, chain index and position.
+pub fn chain_tweak() -> impl Strategy<Value = ChainTweak> {
+    (with_boundaries(u32::MAX), with_boundaries(u16::MAX), with_boundaries(u16::MAX)).prop_map(
+        |(epoch, chain_index, position)| ChainTweak::new_unchecked(epoch, chain_index, position),
+    )
+}
+
+/// Message tweaks with any epoch.
+pub fn message_tweak() -> impl Strategy<Value = MessageTweak> {
+    with_boundaries(u32::MAX).prop_map(|epoch| MessageTweak { epoch })
+}
+
+/// Tweaks of any of the three types.
+pub fn poseidon_tweak() -> impl Strategy<Value = PoseidonTweak> {
+    prop_oneof![
+        tree_tweak().prop_map(PoseidonTweak::Tree),
+        chain_tweak().prop_map(PoseidonTweak::Chain),
+        message_tweak().prop_map(PoseidonTweak::Message),
+    ]
+}
+
+impl Arbitrary for TreeTweak {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+        tree_tweak().boxed()
+    }
+}
+
+impl Arbitrary for ChainTweak {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+        chain_tweak().boxed()
+    }
+}
+
+impl Arbitrary for MessageTweak {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+        message_tweak().boxed()
+    }
+}
+
+impl Arbitrary for PoseidonTweak {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+        poseidon_tweak().boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tweak::TweakEncoding;
+
+    proptest! {
+        #[test]
+        fn test_tree_tweaks_are_valid(tweak in any::<TreeTweak>()) {
+            prop_assert_eq!(
+                TreeTweak::new::<MAX_TREE_HEIGHT>(tweak.level, tweak.position),
+                Ok(tweak)
+            );
+        }
+
+        #[test]
+        fn test_encoding_is_deterministic(tweak in any::<PoseidonTweak>()) {
+            prop_assert_eq!(tweak.to_field_elements(), tweak.to_field_elements());
+        }
+
+        #[test]
+        fn test_decoding_inverts_encoding(tweak in any::<PoseidonTweak>()) {
+            prop_assert_eq!(PoseidonTweak::from_field_elements(&tweak.to_field_elements()), Ok(tweak));
+            prop_assert_eq!(PoseidonTweak::from_bytes(&tweak.to_bytes().unwrap()), Ok(tweak));
+        }
+
+        #[test]
+        fn test_unequal_tweaks_encode_differently(
+            a in any::<PoseidonTweak>(),
+            b in any::<PoseidonTweak>(),
+        ) {
+            prop_assume!(a != b);
+            prop_assert_ne!(a.to_field_elements(), b.to_field_elements());
+        }
+    }
+}
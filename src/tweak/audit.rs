@@ -0,0 +1,264 @@
+//! Injectivity audit of the tweak encoding over a whole parameter set.
+//!
+//! Tree, chain and message tweaks share the same limb space and only differ by their separator
+//! byte, so the audit puts the encodings of all three types into a single set. Small parameter
+//! sets are enumerated exhaustively; above [`AuditConfig::exhaustive_limit`] tweaks, a fixed
+//! number of tweaks of each type is sampled instead.
+//!
+//! This is slow by design and meant for tests and one-off checks, not for the signing path.
+
+use super::{ChainTweak, MessageTweak, PoseidonTweak, TreeTweak, TweakEncoding, TWEAK_LEN};
+use core::fmt;
+use p3_baby_bear::BabyBear;
+use std::collections::HashMap;
+
+/// The largest `log_lifetime` whose epochs fit in chain and message tweaks.
+pub const MAX_LOG_LIFETIME: usize = u32::BITS as usize;
+
+/// How [`verify_injectivity_with`] chooses between enumerating and sampling tweaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuditConfig {
+    /// The largest number of tweaks that is enumerated exhaustively.
+    pub exhaustive_limit: u128,
+    /// The number of tweaks of each type checked when the parameter set is too large.
+    pub samples: usize,
+    /// The seed of the sampler.
+    pub seed: u64,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self { exhaustive_limit: 1 << 20, samples: 1 << 16, seed: 0 }
+    }
+}
+
+/// Two distinct tweaks with the same field-element encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CollisionReport {
+    /// The tweak that was encoded first.
+    pub first: PoseidonTweak,
+    /// The tweak whose encoding collided with the one of `first`.
+    pub second: PoseidonTweak,
+    /// The shared encoding.
+    pub encoding: [BabyBear; TWEAK_LEN],
+}
+
+impl fmt::Display for CollisionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} and {:?} are both encoded as {:?}", self.first, self.second, self.encoding)
+    }
+}
+
+impl std::error::Error for CollisionReport {}
+
+/// Checks that no two tweaks of the parameter set share an encoding, with the default
+/// [`AuditConfig`].
+///
+/// The parameter set covers every tree node of a tree of height `log_lifetime`, and the chain
+/// and message tweaks of its `2^log_lifetime` epochs for `num_chains` chains of length
+/// `chain_len`.
+///
+/// # Panics
+///
+/// Panics if `log_lifetime` is above [`MAX_LOG_LIFETIME`], or if `num_chains` or `chain_len` is
+/// above `2^16`.
+pub fn verify_injectivity(
+    log_lifetime: usize,
+    num_chains: usize,
+    chain_len: usize,
+) -> Result<(), CollisionReport> {
+    verify_injectivity_with(log_lifetime, num_chains, chain_len, &AuditConfig::default())
+}
+
+/// Checks that no two tweaks of the parameter set share an encoding.
+///
+/// See [`verify_injectivity`] for the parameter set.
+pub fn verify_injectivity_with(
+    log_lifetime: usize,
+    num_chains: usize,
+    chain_len: usize,
+    config: &AuditConfig,
+) -> Result<(), CollisionReport> {
+    let params = AuditParams::new(log_lifetime, num_chains, chain_len);
+    let encode = |tweak: &PoseidonTweak| tweak.to_field_elements();
+
+    if params.num_tweaks() <= config.exhaustive_limit {
+        find_collision(params.all_tweaks(), encode)
+    } else {
+        find_collision(params.sampled_tweaks(config.samples, config.seed), encode)
+    }
+}
+
+/// The ranges of the tweaks of a parameter set.
+#[derive(Debug, Clone, Copy)]
+struct AuditParams {
+    log_lifetime: usize,
+    num_chains: u32,
+    chain_len: u32,
+}
+
+impl AuditParams {
+    fn new(log_lifetime: usize, num_chains: usize, chain_len: usize) -> Self {
+        assert!(log_lifetime <= MAX_LOG_LIFETIME, "log_lifetime {log_lifetime} is too large");
+        assert!(num_chains <= 1 << u16::BITS, "{num_chains} chains do not fit in a chain tweak");
+        assert!(chain_len <= 1 << u16::BITS, "chains of length {chain_len} do not fit in a tweak");
+        Self { log_lifetime, num_chains: num_chains as u32, chain_len: chain_len as u32 }
+    }
+
+    const fn num_epochs(self) -> u64 {
+        1 << self.log_lifetime
+    }
+
+    /// The number of tree, chain and message tweaks of the parameter set.
+    const fn num_tweaks(self) -> u128 {
+        let epochs = self.num_epochs() as u128;
+        let tree = 2 * epochs - 1;
+        let chain = epochs * self.num_chains as u128 * self.chain_len as u128;
+        tree + chain + epochs
+    }
+
+    fn all_tweaks(self) -> impl Iterator<Item = PoseidonTweak> {
+        let tree = (0..=self.log_lifetime as u8).flat_map(move |level| {
+            (0..1u64 << (self.log_lifetime - level as usize))
+                .map(move |position| PoseidonTweak::Tree(TreeTweak::new_unchecked(level, position)))
+        });
+        let chain = (0..self.num_epochs()).flat_map(move |epoch| {
+            (0..self.num_chains).flat_map(move |chain_index| {
+                (0..self.chain_len).map(move |position| {
+                    PoseidonTweak::Chain(ChainTweak::new_unchecked(
+                        epoch as u32,
+                        chain_index as u16,
+                        position as u16,
+                    ))
+                })
+            })
+        });
+        let message = (0..self.num_epochs())
+            .map(|epoch| PoseidonTweak::Message(MessageTweak { epoch: epoch as u32 }));
+
+        tree.chain(chain).chain(message)
+    }
+
+    fn sampled_tweaks(self, samples: usize, seed: u64) -> impl Iterator<Item = PoseidonTweak> {
+        let mut rng = SplitMix64(seed);
+        (0..samples).flat_map(move |_| {
+            let level = rng.below(self.log_lifetime as u64 + 1) as u8;
+            let position = rng.below(1 << (self.log_lifetime - level as usize));
+            let tree = TreeTweak::new_unchecked(level, position);
+
+            let chain = ChainTweak::new_unchecked(
+                rng.below(self.num_epochs()) as u32,
+                rng.below(self.num_chains as u64) as u16,
+                rng.below(self.chain_len as u64) as u16,
+            );
+            let message = MessageTweak { epoch: rng.below(self.num_epochs()) as u32 };
+
+            [
+                PoseidonTweak::Tree(tree),
+                PoseidonTweak::Chain(chain),
+                PoseidonTweak::Message(message),
+            ]
+        })
+    }
+}
+
+/// Returns the first pair of distinct tweaks of `tweaks` with the same `encode`-ing.
+fn find_collision(
+    tweaks: impl Iterator<Item = PoseidonTweak>,
+    encode: impl Fn(&PoseidonTweak) -> [BabyBear; TWEAK_LEN],
+) -> Result<(), CollisionReport> {
+    let mut seen = HashMap::new();
+    for tweak in tweaks {
+        let encoding = encode(&tweak);
+        match seen.insert(encoding, tweak) {
+            // Sampling can draw the same tweak twice, which is not a collision
+            Some(first) if first != tweak => {
+                return Err(CollisionReport { first, second: tweak, encoding });
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// A small deterministic generator, so that sampled audits are reproducible.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    const fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a value in `0..bound`, with a negligible bias for the bounds used here.
+    const fn below(&mut self, bound: u64) -> u64 {
+        ((self.next_u64() as u128 * bound as u128) >> 64) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p3_field::{FieldAlgebra, PrimeField32};
+
+    #[test]
+    fn test_num_tweaks() {
+        let params = AuditParams::new(3, 4, 5);
+        assert_eq!(params.num_tweaks(), 15 + 8 * 4 * 5 + 8);
+        assert_eq!(params.all_tweaks().count() as u128, params.num_tweaks());
+    }
+
+    #[test]
+    fn test_exhaustive_audit_passes() {
+        assert_eq!(verify_injectivity(4, 8, 16), Ok(()));
+        assert_eq!(verify_injectivity(0, 1, 1), Ok(()));
+    }
+
+    #[test]
+    fn test_sampled_audit_passes() {
+        let config = AuditConfig { exhaustive_limit: 0, samples: 1 << 12, seed: 7 };
+        assert_eq!(verify_injectivity_with(32, 1 << 16, 1 << 16, &config), Ok(()));
+    }
+
+    #[test]
+    fn test_collision_is_reported() {
+        // Keeping only the low byte of the first limb leaves room for 256 distinct encodings,
+        // fewer than the 1023 tweaks of the parameter set
+        let truncated = |tweak: &PoseidonTweak| {
+            let [low, ..] = tweak.to_field_elements();
+            [BabyBear::new(low.as_canonical_u32() & 0xff), BabyBear::ZERO, BabyBear::ZERO]
+        };
+        let report = find_collision(AuditParams::new(8, 1, 1).all_tweaks(), truncated);
+
+        let report = report.unwrap_err();
+        assert_ne!(report.first, report.second);
+        assert_eq!(truncated(&report.first), truncated(&report.second));
+        assert_eq!(report.encoding, truncated(&report.first));
+    }
+
+    #[test]
+    fn test_collision_across_tweak_types_is_reported() {
+        // Ignoring the separator byte confuses tree, chain and message tweaks
+        let without_separator = |tweak: &PoseidonTweak| {
+            let mut encoding = tweak.to_field_elements();
+            encoding[0] -= BabyBear::new(encoding[0].as_canonical_u32() & 0xff);
+            encoding
+        };
+        let tweaks = [
+            PoseidonTweak::Message(MessageTweak { epoch: 0 }),
+            PoseidonTweak::Tree(TreeTweak::new_unchecked(0, 0)),
+        ];
+
+        assert_eq!(
+            find_collision(tweaks.into_iter(), without_separator),
+            Err(CollisionReport {
+                first: tweaks[0],
+                second: tweaks[1],
+                encoding: [BabyBear::ZERO; TWEAK_LEN]
+            })
+        );
+    }
+}
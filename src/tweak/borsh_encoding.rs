@@ -0,0 +1,96 @@
+//! Borsh support for the tweak types.
+//!
+//! Chain and message tweaks encode their fields in declaration order, as little-endian integers.
+//! [`PoseidonTweak`](super::PoseidonTweak) is a borsh enum: the variant index (`0` tree, `1`
+//! chain, `2` message) followed by the tweak, so the index is not the separator byte.
+//!
+//! Tree tweaks encode their level and position only. Like with serde, only tweaks of the wide
+//! layout are encoded, and decoded tree tweaks go through [`TreeTweak::new`] for the largest
+//! tree a tree tweak can describe.
+
+use super::{TreeTweak, TreeTweakLayout, MAX_TREE_HEIGHT};
+use borsh::{
+    io::{Error, ErrorKind, Read, Result, Write},
+    BorshDeserialize, BorshSerialize,
+};
+
+impl BorshSerialize for TreeTweak {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<()> {
+        if self.layout != TreeTweakLayout::Wide {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "legacy tree tweaks have no borsh encoding",
+            ));
+        }
+        self.level.serialize(writer)?;
+        self.position.serialize(writer)
+    }
+}
+
+impl BorshDeserialize for TreeTweak {
+    fn deserialize_reader<R: Read>(reader: &mut R) -> Result<Self> {
+        let level = u8::deserialize_reader(reader)?;
+        let position = u64::deserialize_reader(reader)?;
+        Self::new::<MAX_TREE_HEIGHT>(level, position)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "tree tweak is out of range"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tweak::{ChainTweak, MessageTweak, PoseidonTweak};
+
+    #[test]
+    fn test_borsh_layout() {
+        let tree = PoseidonTweak::Tree(TreeTweak::new_unchecked(3, 17));
+        assert_eq!(borsh::to_vec(&tree).unwrap(), [0, 3, 17, 0, 0, 0, 0, 0, 0, 0]);
+
+        let chain = PoseidonTweak::Chain(ChainTweak::new_unchecked(9, 4, 2));
+        assert_eq!(borsh::to_vec(&chain).unwrap(), [1, 9, 0, 0, 0, 4, 0, 2, 0]);
+
+        let message = PoseidonTweak::Message(MessageTweak { epoch: 5 });
+        assert_eq!(borsh::to_vec(&message).unwrap(), [2, 5, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_borsh_roundtrip() {
+        let tweaks = [
+            PoseidonTweak::Tree(TreeTweak::new_unchecked(0, 0)),
+            PoseidonTweak::Tree(TreeTweak::new_unchecked(0, u64::MAX)),
+            PoseidonTweak::Chain(ChainTweak::new_unchecked(u32::MAX, u16::MAX, u16::MAX)),
+            PoseidonTweak::Message(MessageTweak { epoch: u32::MAX }),
+        ];
+        for tweak in tweaks {
+            let bytes = borsh::to_vec(&tweak).unwrap();
+            assert_eq!(borsh::from_slice::<PoseidonTweak>(&bytes).unwrap(), tweak);
+        }
+
+        let chain = ChainTweak::new_unchecked(9, 4, 2);
+        assert_eq!(
+            borsh::from_slice::<ChainTweak>(&borsh::to_vec(&chain).unwrap()).unwrap(),
+            chain
+        );
+    }
+
+    #[test]
+    fn test_borsh_rejects_legacy_tree_tweaks() {
+        let legacy = PoseidonTweak::Tree(TreeTweak::new_legacy(3, 17));
+        assert_eq!(borsh::to_vec(&legacy).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_borsh_rejects_invalid_tweaks() {
+        // Level 65 is above the root of the largest tree
+        let err = borsh::from_slice::<TreeTweak>(&[65, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        // Position 2 does not exist at level 63
+        assert!(borsh::from_slice::<TreeTweak>(&[63, 2, 0, 0, 0, 0, 0, 0, 0]).is_err());
+
+        // Unknown variant, truncated and trailing bytes
+        assert!(borsh::from_slice::<PoseidonTweak>(&[3, 5, 0, 0, 0]).is_err());
+        assert!(borsh::from_slice::<PoseidonTweak>(&[2, 5, 0, 0]).is_err());
+        assert!(borsh::from_slice::<PoseidonTweak>(&[2, 5, 0, 0, 0, 0]).is_err());
+    }
+}
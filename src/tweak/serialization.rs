@@ -0,0 +1,198 @@
+//! Serde support for the tweak types.
+//!
+//! Human-readable formats (e.g. JSON) use an internally tagged representation for
+//! [`PoseidonTweak`], such as `{"type":"tree","level":3,"position":17}`. Binary formats (e.g.
+//! bincode) cannot deserialize internally tagged enums, so they use the externally tagged
+//! representation: the variant index followed by the tweak fields.
+//!
+//! Deserialized tree tweaks go through [`TreeTweak::new`] for the largest tree a tree tweak can
+//! describe, so a corrupt file cannot produce a node that exists in no tree. Chain and message
+//! tweaks have no scheme-independent bounds beyond the widths of their fields.
+//!
+//! Only tree tweaks of the [wide layout](TreeTweakLayout::Wide) are serialized. Their level and
+//! position do not tell the layout, so serializing a legacy tweak returns an error instead of
+//! writing a tweak that deserializes to another node.
+
+use super::{
+    ChainTweak, MessageTweak, PoseidonTweak, TreeTweak, TreeTweakLayout, TweakError,
+    MAX_TREE_HEIGHT,
+};
+use serde::{ser::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Unvalidated tree tweak, as read from the serialized form.
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "TreeTweak")]
+pub(super) struct RawTreeTweak {
+    level: u8,
+    position: u64,
+}
+
+impl Serialize for TreeTweak {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.layout != TreeTweakLayout::Wide {
+            return Err(S::Error::custom("legacy tree tweaks have no serialized form"));
+        }
+        RawTreeTweak { level: self.level, position: self.position }.serialize(serializer)
+    }
+}
+
+impl TryFrom<RawTreeTweak> for TreeTweak {
+    type Error = TweakError;
+
+    fn try_from(raw: RawTreeTweak) -> Result<Self, Self::Error> {
+        Self::new::<MAX_TREE_HEIGHT>(raw.level, raw.position)
+    }
+}
+
+/// Representation of [`PoseidonTweak`] for human-readable formats.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum TaggedTweak {
+    Tree(TreeTweak),
+    Chain(ChainTweak),
+    Message(MessageTweak),
+}
+
+/// Representation of [`PoseidonTweak`] for binary formats.
+#[derive(Serialize, Deserialize)]
+enum BinaryTweak {
+    Tree(TreeTweak),
+    Chain(ChainTweak),
+    Message(MessageTweak),
+}
+
+impl From<PoseidonTweak> for TaggedTweak {
+    fn from(tweak: PoseidonTweak) -> Self {
+        match tweak {
+            PoseidonTweak::Tree(tweak) => Self::Tree(tweak),
+            PoseidonTweak::Chain(tweak) => Self::Chain(tweak),
+            PoseidonTweak::Message(tweak) => Self::Message(tweak),
+        }
+    }
+}
+
+impl From<TaggedTweak> for PoseidonTweak {
+    fn from(tweak: TaggedTweak) -> Self {
+        match tweak {
+            TaggedTweak::Tree(tweak) => Self::Tree(tweak),
+            TaggedTweak::Chain(tweak) => Self::Chain(tweak),
+            TaggedTweak::Message(tweak) => Self::Message(tweak),
+        }
+    }
+}
+
+impl From<PoseidonTweak> for BinaryTweak {
+    fn from(tweak: PoseidonTweak) -> Self {
+        match tweak {
+            PoseidonTweak::Tree(tweak) => Self::Tree(tweak),
+            PoseidonTweak::Chain(tweak) => Self::Chain(tweak),
+            PoseidonTweak::Message(tweak) => Self::Message(tweak),
+        }
+    }
+}
+
+impl From<BinaryTweak> for PoseidonTweak {
+    fn from(tweak: BinaryTweak) -> Self {
+        match tweak {
+            BinaryTweak::Tree(tweak) => Self::Tree(tweak),
+            BinaryTweak::Chain(tweak) => Self::Chain(tweak),
+            BinaryTweak::Message(tweak) => Self::Message(tweak),
+        }
+    }
+}
+
+impl Serialize for PoseidonTweak {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            TaggedTweak::from(*self).serialize(serializer)
+        } else {
+            BinaryTweak::from(*self).serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PoseidonTweak {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            TaggedTweak::deserialize(deserializer).map(Into::into)
+        } else {
+            BinaryTweak::deserialize(deserializer).map(Into::into)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tweak::sample_tweaks;
+
+    #[test]
+    fn test_json_representation() {
+        assert_eq!(
+            serde_json::to_string(&PoseidonTweak::Tree(TreeTweak::new_unchecked(3, 17))).unwrap(),
+            r#"{"type":"tree","level":3,"position":17}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&PoseidonTweak::Chain(ChainTweak {
+                epoch: 9,
+                chain_index: 4,
+                position: 2
+            }))
+            .unwrap(),
+            r#"{"type":"chain","epoch":9,"chain_index":4,"position":2}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&PoseidonTweak::Message(MessageTweak { epoch: 5 })).unwrap(),
+            r#"{"type":"message","epoch":5}"#
+        );
+    }
+
+    #[test]
+    fn test_json_roundtrip() {
+        for tweak in sample_tweaks() {
+            let json = serde_json::to_string(&tweak).unwrap();
+            assert_eq!(serde_json::from_str::<PoseidonTweak>(&json).unwrap(), tweak);
+        }
+    }
+
+    #[test]
+    fn test_bincode_roundtrip() {
+        for tweak in sample_tweaks() {
+            let bytes = bincode::serialize(&tweak).unwrap();
+            assert_eq!(bincode::deserialize::<PoseidonTweak>(&bytes).unwrap(), tweak);
+        }
+    }
+
+    #[test]
+    fn test_deserialize_rejects_invalid_tree_tweak() {
+        // No tree of height at most 64 has a level 65
+        assert!(serde_json::from_str::<PoseidonTweak>(
+            r#"{"type":"tree","level":65,"position":0}"#
+        )
+        .is_err());
+        // Level 63 of a height-64 tree only has positions 0 and 1
+        assert!(serde_json::from_str::<TreeTweak>(r#"{"level":63,"position":2}"#).is_err());
+
+        // The same validation applies to binary formats
+        let bytes =
+            bincode::serialize(&PoseidonTweak::Tree(TreeTweak::new_unchecked(63, 2))).unwrap();
+        assert!(bincode::deserialize::<PoseidonTweak>(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_serialize_rejects_legacy_tree_tweaks() {
+        let legacy = PoseidonTweak::Tree(TreeTweak::new_legacy(3, 17));
+        assert!(serde_json::to_string(&legacy).is_err());
+        assert!(bincode::serialize(&legacy).is_err());
+
+        // The wide tweak of the same node keeps its form
+        let wide = PoseidonTweak::Tree(TreeTweak::new_unchecked(3, 17));
+        let json = serde_json::to_string(&wide).unwrap();
+        assert_eq!(serde_json::from_str::<PoseidonTweak>(&json).unwrap(), wide);
+    }
+
+    #[test]
+    fn test_deserialize_rejects_unknown_type() {
+        assert!(serde_json::from_str::<PoseidonTweak>(r#"{"type":"leaf","epoch":0}"#).is_err());
+    }
+}
@@ -0,0 +1,340 @@
+//! Text forms of the tweak types, for logs and debugging tools.
+//!
+//! [`Display`](fmt::Display) prints the components of a tweak, such as `tree(level=3, pos=17)`,
+//! `chain(epoch=9, chain=4, pos=2)` or `message(epoch=5)`. The hex form (`{:x}`, or `{:#x}` with
+//! a `0x` prefix) prints the packed tweak integer, whose separator byte identifies the tweak type.
+//!
+//! [`FromStr`] accepts both forms, the hex form only with its `0x` prefix. As for deserialization,
+//! parsed tree tweaks go through [`TreeTweak::new`] for the largest tree a tree tweak can describe.
+
+use super::{
+    limbs_from_packed, ChainTweak, MessageTweak, PoseidonTweak, TreeTweak, TweakParseError,
+    MAX_TREE_HEIGHT, TREE_TWEAK_BITS, TWEAK_LEN,
+};
+use core::{fmt, str::FromStr};
+use p3_baby_bear::BabyBear;
+
+/// A tweak string, split into its form.
+enum TweakText<'a> {
+    /// The field elements of a `0x`-prefixed packed tweak integer.
+    Packed([BabyBear; TWEAK_LEN]),
+    /// The kind and the fields of `kind(field=value, ...)`.
+    Fields(&'a str, Fields<'a>),
+}
+
+impl<'a> TweakText<'a> {
+    fn parse(s: &'a str) -> Result<Self, TweakParseError> {
+        let s = s.trim();
+        if let Some(hex) = s.strip_prefix("0x") {
+            if hex.is_empty() || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(TweakParseError::InvalidHex);
+            }
+            let packed = u128::from_str_radix(hex, 16).map_err(|_| TweakParseError::InvalidHex)?;
+            // No tweak is wider than a tree tweak, and the limbs cannot hold much wider integers
+            if packed >> TREE_TWEAK_BITS != 0 {
+                return Err(TweakParseError::InvalidHex);
+            }
+            return Ok(Self::Packed(limbs_from_packed(packed)));
+        }
+
+        let (kind, rest) = s.split_once('(').ok_or(TweakParseError::Malformed)?;
+        let body = rest.strip_suffix(')').ok_or(TweakParseError::Malformed)?;
+        Ok(Self::Fields(kind.trim_end(), Fields(body.split(','))))
+    }
+}
+
+/// The comma-separated `field=value` pairs of a tweak string.
+struct Fields<'a>(core::str::Split<'a, char>);
+
+impl Fields<'_> {
+    /// Parses the next field, which must be called `name`.
+    fn next<T: FromStr>(&mut self, name: &'static str) -> Result<T, TweakParseError> {
+        let (key, value) = self
+            .0
+            .next()
+            .and_then(|field| field.split_once('='))
+            .ok_or(TweakParseError::ExpectedField(name))?;
+        if key.trim() != name {
+            return Err(TweakParseError::ExpectedField(name));
+        }
+        value.trim().parse().map_err(|_| TweakParseError::InvalidNumber(name))
+    }
+
+    /// Checks that every field has been parsed.
+    fn finish(mut self) -> Result<(), TweakParseError> {
+        match self.0.next() {
+            None => Ok(()),
+            Some(_) => Err(TweakParseError::TrailingField),
+        }
+    }
+
+    fn tree(mut self) -> Result<TreeTweak, TweakParseError> {
+        let level = self.next("level")?;
+        let position = self.next("pos")?;
+        self.finish()?;
+        Ok(TreeTweak::new::<MAX_TREE_HEIGHT>(level, position)?)
+    }
+
+    fn chain(mut self) -> Result<ChainTweak, TweakParseError> {
+        let epoch = self.next("epoch")?;
+        let chain_index = self.next("chain")?;
+        let position = self.next("pos")?;
+        self.finish()?;
+        Ok(ChainTweak { epoch, chain_index, position })
+    }
+
+    fn message(mut self) -> Result<MessageTweak, TweakParseError> {
+        let epoch = self.next("epoch")?;
+        self.finish()?;
+        Ok(MessageTweak { epoch })
+    }
+}
+
+/// Checks that a decoded tree tweak is a node of the largest tree.
+fn check_tree(tweak: TreeTweak) -> Result<TreeTweak, TweakParseError> {
+    Ok(TreeTweak::new::<MAX_TREE_HEIGHT>(tweak.level, tweak.position)?)
+}
+
+impl fmt::Display for TreeTweak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tree(level={}, pos={})", self.level, self.position)
+    }
+}
+
+impl fmt::Display for ChainTweak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "chain(epoch={}, chain={}, pos={})", self.epoch, self.chain_index, self.position)
+    }
+}
+
+impl fmt::Display for MessageTweak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "message(epoch={})", self.epoch)
+    }
+}
+
+impl fmt::Display for PoseidonTweak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tree(tweak) => fmt::Display::fmt(tweak, f),
+            Self::Chain(tweak) => fmt::Display::fmt(tweak, f),
+            Self::Message(tweak) => fmt::Display::fmt(tweak, f),
+        }
+    }
+}
+
+impl fmt::LowerHex for TreeTweak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.packed(), f)
+    }
+}
+
+impl fmt::LowerHex for ChainTweak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.packed(), f)
+    }
+}
+
+impl fmt::LowerHex for MessageTweak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.packed(), f)
+    }
+}
+
+impl fmt::LowerHex for PoseidonTweak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.packed(), f)
+    }
+}
+
+impl FromStr for TreeTweak {
+    type Err = TweakParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match TweakText::parse(s)? {
+            TweakText::Packed(limbs) => check_tree(Self::from_field_elements(&limbs)?),
+            TweakText::Fields("tree", fields) => fields.tree(),
+            TweakText::Fields(..) => Err(TweakParseError::UnexpectedKind),
+        }
+    }
+}
+
+impl FromStr for ChainTweak {
+    type Err = TweakParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match TweakText::parse(s)? {
+            TweakText::Packed(limbs) => Ok(Self::from_field_elements(&limbs)?),
+            TweakText::Fields("chain", fields) => fields.chain(),
+            TweakText::Fields(..) => Err(TweakParseError::UnexpectedKind),
+        }
+    }
+}
+
+impl FromStr for MessageTweak {
+    type Err = TweakParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match TweakText::parse(s)? {
+            TweakText::Packed(limbs) => Ok(Self::from_field_elements(&limbs)?),
+            TweakText::Fields("message", fields) => fields.message(),
+            TweakText::Fields(..) => Err(TweakParseError::UnexpectedKind),
+        }
+    }
+}
+
+impl FromStr for PoseidonTweak {
+    type Err = TweakParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match TweakText::parse(s)? {
+            TweakText::Packed(limbs) => match Self::from_field_elements(&limbs)? {
+                Self::Tree(tweak) => check_tree(tweak).map(Self::Tree),
+                tweak => Ok(tweak),
+            },
+            TweakText::Fields("tree", fields) => fields.tree().map(Self::Tree),
+            TweakText::Fields("chain", fields) => fields.chain().map(Self::Chain),
+            TweakText::Fields("message", fields) => fields.message().map(Self::Message),
+            TweakText::Fields(..) => Err(TweakParseError::UnexpectedKind),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tweak::{sample_tweaks, TweakDecodeError, TweakDomain, TweakError};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    #[test]
+    fn test_display_representation() {
+        assert_eq!(TreeTweak::new_unchecked(3, 17).to_string(), "tree(level=3, pos=17)");
+        assert_eq!(
+            ChainTweak { epoch: 9, chain_index: 4, position: 2 }.to_string(),
+            "chain(epoch=9, chain=4, pos=2)"
+        );
+        assert_eq!(MessageTweak { epoch: 5 }.to_string(), "message(epoch=5)");
+        assert_eq!(
+            PoseidonTweak::Tree(TreeTweak::new_unchecked(3, 17)).to_string(),
+            "tree(level=3, pos=17)"
+        );
+    }
+
+    #[test]
+    fn test_hex_representation() {
+        let tree = TreeTweak::new_unchecked(3, 17);
+        assert_eq!(format!("{tree:x}"), "3000000000000001101");
+        assert_eq!(format!("{tree:#x}"), "0x3000000000000001101");
+        assert_eq!(format!("{:#x}", PoseidonTweak::Tree(tree)), "0x3000000000000001101");
+
+        let chain = ChainTweak { epoch: 9, chain_index: 4, position: 2 };
+        assert_eq!(format!("{chain:#x}"), "0x90004000200");
+        assert_eq!(format!("{:#x}", MessageTweak { epoch: 5 }), "0x502");
+    }
+
+    #[test]
+    fn test_parse_roundtrip() {
+        for tweak in sample_tweaks() {
+            assert_eq!(tweak.to_string().parse(), Ok(tweak));
+            assert_eq!(format!("{tweak:#x}").parse(), Ok(tweak));
+        }
+
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..100 {
+            let level = rng.gen_range(0..=MAX_TREE_HEIGHT as u8);
+            let position = rng.gen::<u64>().checked_shr(level as u32).unwrap_or(0);
+            let tree = TreeTweak::new_unchecked(level, position);
+            let chain =
+                ChainTweak { epoch: rng.gen(), chain_index: rng.gen(), position: rng.gen() };
+            let message = MessageTweak { epoch: rng.gen() };
+
+            assert_eq!(tree.to_string().parse(), Ok(tree));
+            assert_eq!(format!("{tree:#x}").parse(), Ok(tree));
+            assert_eq!(chain.to_string().parse(), Ok(chain));
+            assert_eq!(format!("{chain:#x}").parse(), Ok(chain));
+            assert_eq!(message.to_string().parse(), Ok(message));
+            assert_eq!(format!("{message:#x}").parse(), Ok(message));
+        }
+    }
+
+    #[test]
+    fn test_parse_accepts_surrounding_whitespace() {
+        assert_eq!(
+            " chain( epoch = 9 ,chain=4,  pos=2 ) ".parse(),
+            Ok(ChainTweak { epoch: 9, chain_index: 4, position: 2 })
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_strings() {
+        assert_eq!("".parse::<PoseidonTweak>(), Err(TweakParseError::Malformed));
+        assert_eq!("tree level=3, pos=17".parse::<TreeTweak>(), Err(TweakParseError::Malformed));
+        assert_eq!("tree(level=3, pos=17".parse::<TreeTweak>(), Err(TweakParseError::Malformed));
+        assert_eq!(
+            "leaf(level=3, pos=17)".parse::<PoseidonTweak>(),
+            Err(TweakParseError::UnexpectedKind)
+        );
+        assert_eq!(
+            "chain(epoch=9, chain=4, pos=2)".parse::<TreeTweak>(),
+            Err(TweakParseError::UnexpectedKind)
+        );
+        assert_eq!(
+            "tree(pos=17, level=3)".parse::<TreeTweak>(),
+            Err(TweakParseError::ExpectedField("level"))
+        );
+        assert_eq!(
+            "message()".parse::<MessageTweak>(),
+            Err(TweakParseError::ExpectedField("epoch"))
+        );
+        assert_eq!(
+            "message(epoch=5, pos=1)".parse::<MessageTweak>(),
+            Err(TweakParseError::TrailingField)
+        );
+        assert_eq!(
+            "chain(epoch=9, chain=65536, pos=2)".parse::<ChainTweak>(),
+            Err(TweakParseError::InvalidNumber("chain"))
+        );
+        assert_eq!(
+            "message(epoch=-1)".parse::<MessageTweak>(),
+            Err(TweakParseError::InvalidNumber("epoch"))
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_hex() {
+        assert_eq!("0x".parse::<PoseidonTweak>(), Err(TweakParseError::InvalidHex));
+        assert_eq!("0x12g".parse::<PoseidonTweak>(), Err(TweakParseError::InvalidHex));
+        assert_eq!("0x+12".parse::<PoseidonTweak>(), Err(TweakParseError::InvalidHex));
+        // Wider than any packed tweak
+        assert_eq!(
+            "0x100000000000000000000".parse::<PoseidonTweak>(),
+            Err(TweakParseError::InvalidHex)
+        );
+        assert_eq!(
+            "0x7".parse::<PoseidonTweak>(),
+            Err(TweakParseError::Decode(TweakDecodeError::UnknownSeparator(0x07)))
+        );
+        // The packed integer of a chain tweak is not a tree tweak
+        assert_eq!(
+            "0x90004000200".parse::<TreeTweak>(),
+            Err(TweakParseError::Decode(TweakDecodeError::WrongSeparator {
+                expected: TweakDomain::Tree.as_u8(),
+                found: 0x00
+            }))
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_tree_tweak() {
+        // Level 63 of a height-64 tree only has positions 0 and 1, in both forms
+        let err = Err(TweakParseError::OutOfRange(TweakError::TreePositionOutOfRange {
+            level: 63,
+            position: 2,
+            log_lifetime: MAX_TREE_HEIGHT,
+        }));
+        assert_eq!("tree(level=63, pos=2)".parse::<TreeTweak>(), err);
+        let hex = format!("{:#x}", TreeTweak::new_unchecked(63, 2));
+        assert_eq!(hex.parse::<TreeTweak>(), err);
+        assert!(hex.parse::<PoseidonTweak>().is_err());
+    }
+}
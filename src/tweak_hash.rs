@@ -1,12 +1,22 @@
 use crate::{
-    poseidon2::{poseidon2_compress, Poseidon2BabyBearShort},
-    poseidon2_config::{poseidon2_instance, poseidon2_instance_short},
-    tweak::{PoseidonTweak, TweakTransfformation},
+    poseidon2::{
+        poseidon2_sponge, try_poseidon2_compress, try_poseidon2_compress_iter,
+        try_poseidon2_compress_len_bound_iter, Poseidon2BabyBearLong, Poseidon2BabyBearValidWidth,
+        Poseidon2Error, DOMAIN_PARAMETERS_LENGTH,
+    },
+    poseidon2_config::{
+        cached_domain_separator, cached_poseidon2_instance, cached_poseidon2_instance_short,
+    },
+    tweak::{
+        min_tweak_len, BasePLimbs, PoseidonTweak, TreeTweak, TweakEncoding, TweakLimbEncoding,
+        KEYED_TWEAK_LEN, TREE_TWEAK_BITS,
+    },
 };
-use p3_baby_bear::{BabyBear, Poseidon2BabyBear};
+use core::{marker::PhantomData, ops::Deref};
+use p3_baby_bear::BabyBear;
 use p3_field::FieldAlgebra;
-
-const DOMAIN_PARAMETERS_LENGTH: usize = 4;
+#[cfg(feature = "transcript-debug")]
+use {crate::transcript::HashCtx, core::panic::Location};
 
 /// A Poseidon tweak hash representation.
 ///
@@ -33,7 +43,9 @@ const DOMAIN_PARAMETERS_LENGTH: usize = 4;
 ///
 /// - `TWEAK_LEN`: Defines the number of field elements used to encode the tweak. The tweak provides
 ///   domain separation between different hash function calls, preventing collision attacks and
-///   ensuring security in hierarchical signature schemes.
+///   ensuring security in hierarchical signature schemes. It is chosen once per instantiation
+///   and must be at least [`TWEAK_LEN`](crate::tweak::TWEAK_LEN), the length of the
+///   encoding; the limbs beyond the encoding are zero.
 ///
 /// - `CAPACITY`: Represents the number of field elements reserved for capacity in the Poseidon
 ///   sponge. Capacity determines resistance to collision attacks by ensuring enough diffusion in
@@ -42,6 +54,43 @@ const DOMAIN_PARAMETERS_LENGTH: usize = 4;
 /// - `NUM_CHUNKS`: Defines the total number of chunks used to process an input message. This
 ///   determines how the message is split for hashing and affects the overall signature scheme
 ///   efficiency.
+///
+/// - `E`: The [`TweakLimbEncoding`] of the tweak, [`BasePLimbs`] by default. Hashes only match
+///   those of another implementation if both encode tweaks the same way:
+///
+/// ```
+/// use openvm_sig_agg::{
+///     tweak::{BytesPerElement, ChainTweak, PoseidonTweak},
+///     tweak_hash::PoseidonTweakHash,
+/// };
+/// use p3_baby_bear::BabyBear;
+/// use p3_field::FieldAlgebra;
+///
+/// type InteropHash = PoseidonTweakHash<3, 2, 2, 4, 4, 3, 8, 2, BytesPerElement>;
+///
+/// let tweak = PoseidonTweak::Chain(ChainTweak::new_unchecked(1, 2, 3));
+/// let hash = InteropHash::new([BabyBear::ONE; 4], tweak, vec![[BabyBear::ZERO; 4]]).apply();
+/// # let default = PoseidonTweakHash::<3, 2, 2, 4, 4, 3, 8, 2>::new(
+/// #     [BabyBear::ONE; 4],
+/// #     tweak,
+/// #     vec![[BabyBear::ZERO; 4]],
+/// # );
+/// # assert_ne!(hash, default.apply());
+/// ```
+///
+/// Signers whose trees share the same node positions can additionally be domain separated with
+/// [`Self::with_key_id`], which folds a key identifier into every tweak.
+///
+/// A `TWEAK_LEN` too short for a tree tweak is rejected at compile time:
+///
+/// ```compile_fail
+/// use openvm_sig_agg::{tweak::{ChainTweak, PoseidonTweak}, tweak_hash::PoseidonTweakHash};
+/// use p3_baby_bear::BabyBear;
+/// use p3_field::FieldAlgebra;
+///
+/// let tweak = PoseidonTweak::Chain(ChainTweak::new_unchecked(1, 2, 3));
+/// let _ = PoseidonTweakHash::<3, 2, 2, 4, 4, 2, 8, 2>::new([BabyBear::ONE; 4], tweak, vec![]);
+/// ```
 #[derive(Debug, Clone)]
 pub struct PoseidonTweakHash<
     const LOG_LIFETIME: usize,
@@ -52,10 +101,13 @@ pub struct PoseidonTweakHash<
     const TWEAK_LEN: usize,
     const CAPACITY: usize,
     const NUM_CHUNKS: usize,
+    E = BasePLimbs,
 > {
     parameter: [BabyBear; PARAMETER_LEN],
     tweak: PoseidonTweak,
     message: Vec<[BabyBear; HASH_LEN]>,
+    key_id: u32,
+    encoding: PhantomData<E>,
 }
 
 impl<
@@ -67,6 +119,7 @@ impl<
         const TWEAK_LEN: usize,
         const CAPACITY: usize,
         const NUM_CHUNKS: usize,
+        E: TweakLimbEncoding,
     >
     PoseidonTweakHash<
         LOG_LIFETIME,
@@ -77,38 +130,109 @@ impl<
         TWEAK_LEN,
         CAPACITY,
         NUM_CHUNKS,
+        E,
     >
 {
+    pub const fn new(
+        parameter: [BabyBear; PARAMETER_LEN],
+        tweak: PoseidonTweak,
+        message: Vec<[BabyBear; HASH_LEN]>,
+    ) -> Self {
+        const {
+            assert!(
+                TWEAK_LEN >= min_tweak_len(TREE_TWEAK_BITS),
+                "TWEAK_LEN limbs are too small to hold a tree tweak"
+            );
+        };
+
+        Self { parameter, tweak, message, key_id: 0, encoding: PhantomData }
+    }
+
+    /// Folds the key identifier `key_id` into the tweak.
+    ///
+    /// A non-zero key id encodes the tweak into
+    /// [`KEYED_TWEAK_LEN`](crate::tweak::KEYED_TWEAK_LEN) field elements, so the same node of
+    /// the trees of two keys is hashed differently even under the same parameter. Key id `0` is
+    /// the legacy encoding into `TWEAK_LEN` field elements.
+    #[must_use]
+    pub const fn with_key_id(mut self, key_id: u32) -> Self {
+        self.key_id = key_id;
+        self
+    }
+
+    /// Encodes the tweak into field elements with the encoding `E`.
+    fn tweak_elements(&self) -> Result<TweakElements<TWEAK_LEN>, Poseidon2Error> {
+        if self.key_id == 0 {
+            let mut elements = [BabyBear::ZERO; TWEAK_LEN];
+            elements[..crate::tweak::TWEAK_LEN].copy_from_slice(&E::encode(self.tweak.packed())?);
+            Ok(TweakElements::Legacy(elements))
+        } else {
+            Ok(TweakElements::Keyed(E::encode_keyed(self.tweak.packed_with_key_id(self.key_id))))
+        }
+    }
+
+    /// Compresses `input` in the compression mode of the key id.
+    ///
+    /// Keyed tweaks are longer than legacy ones, so keyed inputs bind their length into the
+    /// state. Legacy inputs keep the zero-padded compression their circuits implement.
+    fn compress<const WIDTH: usize, I>(
+        &self,
+        instance: &I,
+        input: impl IntoIterator<Item = BabyBear>,
+    ) -> Result<[BabyBear; HASH_LEN], Poseidon2Error>
+    where
+        I: Poseidon2BabyBearValidWidth<WIDTH>,
+    {
+        if self.key_id == 0 {
+            try_poseidon2_compress_iter(instance, input)
+        } else {
+            try_poseidon2_compress_len_bound_iter(instance, input)
+        }
+    }
+
+    /// Evaluates the tweak hash.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the parameter, tweak and message do not fit in the permutation, or the tweak in
+    /// its encoding, see [`Self::try_apply`].
     pub fn apply(&self) -> [BabyBear; HASH_LEN] {
+        self.try_apply().unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Evaluates the tweak hash, or returns an error if the parameter, tweak and message do not
+    /// fit in the permutation, or if the tweak does not fit in its encoding `E`.
+    ///
+    /// The message comes from the signature on the verification side, so verifiers use this
+    /// instead of [`Self::apply`] to reject malformed signatures.
+    pub fn try_apply(&self) -> Result<[BabyBear; HASH_LEN], Poseidon2Error> {
         match self.message.len() {
             1 => {
                 // we compress parameter, tweak, message
-                let tweak_fe: [_; TWEAK_LEN] = self.tweak.to_field_elements();
-                let combined_input: Vec<BabyBear> = self
+                let tweak_fe = self.tweak_elements()?;
+                let combined_input = self
                     .parameter
                     .iter()
                     .chain(tweak_fe.iter())
                     .chain(self.message[0].iter())
-                    .cloned()
-                    .collect();
-                poseidon2_compress::<HASH_LEN, _>(&poseidon2_instance_short(), &combined_input)
+                    .copied();
+                self.compress(cached_poseidon2_instance_short(), combined_input)
             }
             2 => {
-                let tweak_fe: [_; TWEAK_LEN] = self.tweak.to_field_elements();
+                let tweak_fe = self.tweak_elements()?;
 
-                let combined_input: Vec<BabyBear> = self
+                let combined_input = self
                     .parameter
                     .iter()
                     .chain(tweak_fe.iter())
                     .chain(self.message[0].iter())
                     .chain(self.message[1].iter())
-                    .cloned()
-                    .collect();
+                    .copied();
 
-                poseidon2_compress::<HASH_LEN, _>(&poseidon2_instance(), &combined_input)
+                self.compress(cached_poseidon2_instance(), combined_input)
             }
             _ => {
-                let tweak_fe: [_; TWEAK_LEN] = self.tweak.to_field_elements();
+                let tweak_fe = self.tweak_elements()?;
 
                 let combined_input: Vec<BabyBear> = self
                     .parameter
@@ -119,16 +243,476 @@ impl<
                     .collect();
 
                 let lengths: [usize; DOMAIN_PARAMETERS_LENGTH] =
-                    [PARAMETER_LEN, TWEAK_LEN, NUM_CHUNKS, HASH_LEN];
+                    [PARAMETER_LEN, tweak_fe.len(), NUM_CHUNKS, HASH_LEN];
 
-                let poseidon_instance = poseidon2_instance();
+                let safe_input =
+                    cached_domain_separator::<CAPACITY, 24, Poseidon2BabyBearLong>(&lengths)?;
+                Ok(poseidon2_sponge(cached_poseidon2_instance(), safe_input, &combined_input))
+            }
+        }
+    }
+
+    /// Evaluates the tweak hash like [`Self::apply`] and records the call into `ctx`.
+    ///
+    /// The recorded input is the full `parameter || tweak || message` concatenation and the
+    /// call site is the caller of this function.
+    #[cfg(feature = "transcript-debug")]
+    #[track_caller]
+    pub fn apply_with_ctx(&self, ctx: &mut HashCtx) -> [BabyBear; HASH_LEN] {
+        let output = self.apply();
+
+        let tweak_fe = self.tweak_elements().expect("apply encoded the tweak");
+        let input = self
+            .parameter
+            .iter()
+            .chain(tweak_fe.iter())
+            .chain(self.message.iter().flatten())
+            .copied()
+            .collect();
+        ctx.record(self.tweak, input, &output, Location::caller());
+
+        output
+    }
+}
+
+/// A tweak encoded by [`PoseidonTweakHash`], held on the stack whatever its key id.
+#[derive(Debug, Clone, Copy)]
+enum TweakElements<const TWEAK_LEN: usize> {
+    /// The encoding of key id `0`.
+    Legacy([BabyBear; TWEAK_LEN]),
+    /// The encoding of a non-zero key id.
+    Keyed([BabyBear; KEYED_TWEAK_LEN]),
+}
 
-                // let safe_input =
-                //     poseidon_safe_domain_separator::<CAPACITY>(&poseidon_instance, &lengths);
-                // poseidon_sponge(&poseidon_instance, &safe_input, &combined_input)
+impl<const TWEAK_LEN: usize> Deref for TweakElements<TWEAK_LEN> {
+    type Target = [BabyBear];
 
-                [BabyBear::ZERO; HASH_LEN]
+    fn deref(&self) -> &[BabyBear] {
+        match self {
+            Self::Legacy(elements) => elements,
+            Self::Keyed(elements) => elements,
+        }
+    }
+}
+
+/// The number of leaves hashed with one input buffer by [`par_hash_leaves`].
+#[cfg(feature = "rayon")]
+const LEAF_CHUNK: usize = 1024;
+
+/// Checks that every leaf has a tweak and an output, and that all leaves have the same length.
+fn check_leaf_batch(
+    leaf_parts: &[&[BabyBear]],
+    tweaks: usize,
+    outputs: usize,
+) -> Result<(), Poseidon2Error> {
+    let leaves = leaf_parts.len();
+    if leaves != tweaks || leaves != outputs {
+        return Err(Poseidon2Error::BatchLengthMismatch { leaves, tweaks, outputs });
+    }
+    let expected = leaf_parts.first().map_or(0, |part| part.len());
+    match leaf_parts.iter().find(|part| part.len() != expected) {
+        Some(part) => Err(Poseidon2Error::LeafLengthMismatch { expected, found: part.len() }),
+        None => Ok(()),
+    }
+}
+
+/// Hashes the leaves of a chunk with a single input buffer, whose parameter lanes are written
+/// once.
+fn hash_leaf_chunk<const HASH_LEN: usize, const PARAMETER_LEN: usize, const WIDTH: usize, I>(
+    instance: &I,
+    parameter: &[BabyBear; PARAMETER_LEN],
+    tweaks: &[TreeTweak],
+    leaf_parts: &[&[BabyBear]],
+    out: &mut [[BabyBear; HASH_LEN]],
+) -> Result<(), Poseidon2Error>
+where
+    I: Poseidon2BabyBearValidWidth<WIDTH>,
+{
+    use crate::tweak::TWEAK_LEN;
+
+    const {
+        assert!(PARAMETER_LEN + TWEAK_LEN <= WIDTH, "the parameter and tweak must fit in WIDTH");
+    };
+
+    let mut buffer = [BabyBear::ZERO; WIDTH];
+    buffer[..PARAMETER_LEN].copy_from_slice(parameter);
+
+    for ((tweak, part), out) in tweaks.iter().zip(leaf_parts).zip(out) {
+        let len = PARAMETER_LEN + TWEAK_LEN + part.len();
+        if len > WIDTH {
+            return Err(Poseidon2Error::InputTooLong { len, width: WIDTH });
+        }
+        buffer[PARAMETER_LEN..PARAMETER_LEN + TWEAK_LEN]
+            .copy_from_slice(&tweak.to_field_elements());
+        buffer[PARAMETER_LEN + TWEAK_LEN..len].copy_from_slice(part);
+        *out = try_poseidon2_compress(instance, &buffer[..len])?;
+    }
+    Ok(())
+}
+
+/// Hashes every leaf of a Merkle tree into the matching entry of `out`.
+///
+/// Leaf `i` is the compression of `parameter || tweaks[i] || leaf_parts[i]`, with the tweak
+/// encoded by [`TweakEncoding::to_field_elements`]. With `WIDTH = 16`, this is the hash
+/// [`PoseidonTweakHash::apply`] computes for a message of one chunk under the default encoding
+/// and key id. The concatenations are built in one buffer on the stack instead of a `Vec` per
+/// leaf.
+///
+/// The compression zero-pads its input, so all leaf parts must have the same length for the
+/// leaves not to collide through trailing zeros.
+///
+/// Returns [`Poseidon2Error::BatchLengthMismatch`] if `tweaks`, `leaf_parts` and `out` do not
+/// have the same length, [`Poseidon2Error::LeafLengthMismatch`] if the leaf parts do not all
+/// have the same length, and the error of [`try_poseidon2_compress`] if the leaves do not fit
+/// the permutation. Nothing is written to `out` on an error.
+pub fn hash_leaves<const HASH_LEN: usize, const PARAMETER_LEN: usize, const WIDTH: usize, I>(
+    instance: &I,
+    parameter: &[BabyBear; PARAMETER_LEN],
+    tweaks: &[TreeTweak],
+    leaf_parts: &[&[BabyBear]],
+    out: &mut [[BabyBear; HASH_LEN]],
+) -> Result<(), Poseidon2Error>
+where
+    I: Poseidon2BabyBearValidWidth<WIDTH>,
+{
+    check_leaf_batch(leaf_parts, tweaks.len(), out.len())?;
+    hash_leaf_chunk(instance, parameter, tweaks, leaf_parts, out)
+}
+
+/// Hashes every leaf of a Merkle tree into the matching entry of `out` on the rayon thread
+/// pool.
+///
+/// This is [`hash_leaves`] with the leaves split into chunks across threads, each chunk using
+/// its own buffer.
+#[cfg(feature = "rayon")]
+pub fn par_hash_leaves<const HASH_LEN: usize, const PARAMETER_LEN: usize, const WIDTH: usize, I>(
+    instance: &I,
+    parameter: &[BabyBear; PARAMETER_LEN],
+    tweaks: &[TreeTweak],
+    leaf_parts: &[&[BabyBear]],
+    out: &mut [[BabyBear; HASH_LEN]],
+) -> Result<(), Poseidon2Error>
+where
+    I: Poseidon2BabyBearValidWidth<WIDTH> + Sync,
+{
+    use rayon::prelude::*;
+
+    check_leaf_batch(leaf_parts, tweaks.len(), out.len())?;
+    tweaks
+        .par_chunks(LEAF_CHUNK)
+        .zip(leaf_parts.par_chunks(LEAF_CHUNK))
+        .zip(out.par_chunks_mut(LEAF_CHUNK))
+        .try_for_each(|((tweaks, leaf_parts), out)| {
+            hash_leaf_chunk(instance, parameter, tweaks, leaf_parts, out)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        poseidon2::{
+            poseidon2_compress_len_bound, poseidon_safe_domain_separator,
+            try_poseidon2_compress_len_bound,
+        },
+        poseidon2_config::{poseidon2_instance, poseidon2_instance_short},
+        tweak::{BytesPerElement, ChainTweak, TweakError, TWEAK_LEN},
+    };
+
+    type DefaultHash = PoseidonTweakHash<3, 2, 2, 4, 4, 3, 8, 2>;
+    type BasePHash = PoseidonTweakHash<3, 2, 2, 4, 4, 3, 8, 2, BasePLimbs>;
+    type BytesHash = PoseidonTweakHash<3, 2, 2, 4, 4, 3, 8, 2, BytesPerElement>;
+
+    #[test]
+    fn test_default_encoding_is_base_p() {
+        let tweak = PoseidonTweak::Chain(ChainTweak::new_unchecked(1, 2, 3));
+        let message = vec![[BabyBear::new(7); 4]];
+        assert_eq!(
+            DefaultHash::new([BabyBear::ONE; 4], tweak, message.clone()).apply(),
+            BasePHash::new([BabyBear::ONE; 4], tweak, message).apply()
+        );
+    }
+
+    #[test]
+    fn test_longer_tweak_len_pads_the_encoding() {
+        type PaddedHash = PoseidonTweakHash<3, 2, 2, 4, 4, 4, 8, 2>;
+
+        let tweak = PoseidonTweak::Chain(ChainTweak::new_unchecked(1, 2, 3));
+        let message = vec![[BabyBear::new(7); 4]];
+        let input: Vec<BabyBear> = [BabyBear::ONE; 4]
+            .into_iter()
+            .chain(tweak.to_field_elements())
+            .chain([BabyBear::ZERO])
+            .chain(message.iter().flatten().copied())
+            .collect();
+        assert_eq!(
+            PaddedHash::new([BabyBear::ONE; 4], tweak, message.clone()).apply(),
+            try_poseidon2_compress::<4, 16, _>(&poseidon2_instance_short(), &input).unwrap()
+        );
+        assert_ne!(
+            PaddedHash::new([BabyBear::ONE; 4], tweak, message.clone()).apply(),
+            DefaultHash::new([BabyBear::ONE; 4], tweak, message).apply()
+        );
+    }
+
+    #[test]
+    fn test_key_id_zero_is_legacy_encoding() {
+        let tweak = PoseidonTweak::Tree(TreeTweak::new_unchecked(3, 17));
+        let message = vec![[BabyBear::new(7); 4], [BabyBear::new(8); 4]];
+        assert_eq!(
+            DefaultHash::new([BabyBear::ONE; 4], tweak, message.clone()).with_key_id(0).apply(),
+            DefaultHash::new([BabyBear::ONE; 4], tweak, message).apply()
+        );
+    }
+
+    #[test]
+    fn test_key_ids_separate_the_same_node() {
+        let tweak = PoseidonTweak::Tree(TreeTweak::new_unchecked(3, 17));
+        let message = vec![[BabyBear::new(7); 4], [BabyBear::new(8); 4]];
+        let hash = |key_id| {
+            DefaultHash::new([BabyBear::ONE; 4], tweak, message.clone()).with_key_id(key_id).apply()
+        };
+
+        assert_ne!(hash(1), hash(2));
+        assert_ne!(hash(0), hash(1));
+        assert_ne!(hash(1), hash(u32::MAX));
+
+        // Keyed chain tweaks also fit the alternate encoding
+        let tweak = PoseidonTweak::Chain(ChainTweak::new_unchecked(u32::MAX, u16::MAX, u16::MAX));
+        assert_ne!(
+            BytesHash::new([BabyBear::ONE; 4], tweak, vec![[BabyBear::ZERO; 4]])
+                .with_key_id(1)
+                .apply(),
+            BytesHash::new([BabyBear::ONE; 4], tweak, vec![[BabyBear::ZERO; 4]])
+                .with_key_id(2)
+                .apply()
+        );
+    }
+
+    #[test]
+    fn test_keyed_hashes_bind_the_input_length() {
+        let tweak = PoseidonTweak::Chain(ChainTweak::new_unchecked(1, 2, 3));
+        let message = vec![[BabyBear::new(7); 4]];
+        let hash = DefaultHash::new([BabyBear::ONE; 4], tweak, message.clone()).with_key_id(5);
+
+        let input: Vec<BabyBear> = [BabyBear::ONE; 4]
+            .into_iter()
+            .chain(BasePLimbs::encode_keyed(tweak.packed_with_key_id(5)))
+            .chain(message.into_iter().flatten())
+            .collect();
+        assert_eq!(
+            hash.apply(),
+            poseidon2_compress_len_bound::<4, 16, _>(&poseidon2_instance_short(), &input)
+        );
+    }
+
+    #[test]
+    fn test_compressions_match_the_collected_input() {
+        let tweak = PoseidonTweak::Chain(ChainTweak::new_unchecked(1, 2, 3));
+        let parameter = [BabyBear::ONE; 4];
+        for message in
+            [vec![[BabyBear::new(7); 4]], vec![[BabyBear::new(7); 4], [BabyBear::new(8); 4]]]
+        {
+            for key_id in [0, 5] {
+                let hash = DefaultHash::new(parameter, tweak, message.clone()).with_key_id(key_id);
+                let input: Vec<BabyBear> = parameter
+                    .into_iter()
+                    .chain(hash.tweak_elements().unwrap().iter().copied())
+                    .chain(message.iter().flatten().copied())
+                    .collect();
+                let expected = match (message.len(), key_id) {
+                    (1, 0) => try_poseidon2_compress(&poseidon2_instance_short(), &input),
+                    (1, _) => try_poseidon2_compress_len_bound(&poseidon2_instance_short(), &input),
+                    (_, 0) => try_poseidon2_compress(&poseidon2_instance(), &input),
+                    (_, _) => try_poseidon2_compress_len_bound(&poseidon2_instance(), &input),
+                };
+                assert_eq!(hash.try_apply(), expected, "{} {key_id}", message.len());
             }
         }
     }
+
+    #[test]
+    fn test_try_apply_rejects_oversized_inputs() {
+        let tweak = PoseidonTweak::Chain(ChainTweak::new_unchecked(1, 2, 3));
+        let message = vec![[BabyBear::new(7); 4]];
+        assert_eq!(
+            DefaultHash::new([BabyBear::ONE; 4], tweak, message.clone()).try_apply(),
+            Ok(DefaultHash::new([BabyBear::ONE; 4], tweak, message).apply())
+        );
+
+        // A parameter, tweak and message of 8 + 3 + 8 elements overflow the width-16 state,
+        // which is reported at the first extra element
+        let hash = PoseidonTweakHash::<3, 2, 2, 8, 8, 3, 8, 2>::new(
+            [BabyBear::ONE; 8],
+            tweak,
+            vec![[BabyBear::new(7); 8]],
+        );
+        assert_eq!(hash.try_apply(), Err(Poseidon2Error::InputTooLong { len: 17, width: 16 }));
+    }
+
+    #[test]
+    fn test_long_messages_use_the_sponge() {
+        let tweak = PoseidonTweak::Chain(ChainTweak::new_unchecked(1, 2, 3));
+        let message = vec![[BabyBear::new(7); 4], [BabyBear::new(8); 4], [BabyBear::new(9); 4]];
+        let hash = DefaultHash::new([BabyBear::ONE; 4], tweak, message.clone()).apply();
+
+        let instance = poseidon2_instance();
+        let lengths = [4, TWEAK_LEN, 2, 4];
+        let capacity: [BabyBear; 8] =
+            poseidon_safe_domain_separator::<8, 24, _>(&instance, &lengths).unwrap();
+        let input: Vec<BabyBear> = [BabyBear::ONE; 4]
+            .into_iter()
+            .chain(tweak.to_field_elements())
+            .chain(message.into_iter().flatten())
+            .collect();
+        assert_eq!(hash, poseidon2_sponge::<4, 8, 24, _>(&instance, &capacity, &input));
+    }
+
+    #[test]
+    fn test_encodings_give_different_hashes() {
+        // The two encodings of this tree tweak differ, so must the node hashes. Tree tweaks only
+        // fit the byte encoding in the legacy layout.
+        let tweak = PoseidonTweak::Tree(TreeTweak::new_legacy(3, 17));
+        let message = vec![[BabyBear::new(7); 4], [BabyBear::new(8); 4]];
+        assert_ne!(
+            BasePHash::new([BabyBear::ONE; 4], tweak, message.clone()).apply(),
+            BytesHash::new([BabyBear::ONE; 4], tweak, message).apply()
+        );
+    }
+
+    #[test]
+    fn test_try_apply_rejects_wide_tree_tweaks_of_the_byte_encoding() {
+        // Tree tweaks of the default layout need more than TWEAK_LEN elements of three bytes
+        let message = vec![[BabyBear::new(7); 4], [BabyBear::new(8); 4]];
+        for tweak in [
+            TreeTweak::new_unchecked(3, 17),
+            TreeTweak::node(1, 2, 10).unwrap(),
+            TreeTweak::root(32),
+        ] {
+            let hash =
+                BytesHash::new([BabyBear::ONE; 4], PoseidonTweak::Tree(tweak), message.clone());
+            assert_eq!(
+                hash.try_apply(),
+                Err(Poseidon2Error::Tweak(TweakError::Overflow {
+                    required: 4,
+                    provided: TWEAK_LEN
+                }))
+            );
+        }
+
+        // The same node in the legacy layout fits
+        let tweak = PoseidonTweak::Tree(TreeTweak::new_legacy(3, 17));
+        assert!(BytesHash::new([BabyBear::ONE; 4], tweak, message).try_apply().is_ok());
+    }
+
+    fn leaves() -> (Vec<TreeTweak>, Vec<Vec<BabyBear>>) {
+        let tweaks = (0..100).map(TreeTweak::leaf).collect();
+        let parts = (0..100).map(|i| (0..4).map(|j| BabyBear::new(100 * i + j)).collect());
+        (tweaks, parts.collect())
+    }
+
+    #[test]
+    fn test_hash_leaves_matches_per_leaf_compress() {
+        let instance = poseidon2_instance_short();
+        let parameter = [BabyBear::new(3); 4];
+        let (tweaks, parts) = leaves();
+        let parts: Vec<&[BabyBear]> = parts.iter().map(Vec::as_slice).collect();
+
+        let mut out = vec![[BabyBear::ZERO; 4]; parts.len()];
+        hash_leaves::<4, 4, 16, _>(&instance, &parameter, &tweaks, &parts, &mut out).unwrap();
+        for ((tweak, part), out) in tweaks.iter().zip(&parts).zip(&out) {
+            let input: Vec<BabyBear> = parameter
+                .into_iter()
+                .chain(tweak.to_field_elements())
+                .chain(part.iter().copied())
+                .collect();
+            assert_eq!(*out, try_poseidon2_compress::<4, 16, _>(&instance, &input).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_hash_leaves_matches_the_tweak_hash() {
+        let instance = poseidon2_instance_short();
+        let parameter = [BabyBear::new(3); 4];
+        let (tweaks, parts) = leaves();
+        let parts: Vec<&[BabyBear]> = parts.iter().map(Vec::as_slice).collect();
+
+        let mut out = vec![[BabyBear::ZERO; 4]; parts.len()];
+        hash_leaves::<4, 4, 16, _>(&instance, &parameter, &tweaks, &parts, &mut out).unwrap();
+        for ((&tweak, part), out) in tweaks.iter().zip(&parts).zip(&out) {
+            let message = vec![(*part).try_into().unwrap()];
+            let hash = DefaultHash::new(parameter, PoseidonTweak::Tree(tweak), message);
+            assert_eq!(*out, hash.apply(), "{tweak:?}");
+        }
+    }
+
+    #[test]
+    fn test_hash_leaves_rejects_leaves_of_different_lengths() {
+        let instance = poseidon2_instance_short();
+        let parameter = [BabyBear::new(3); 4];
+        let tweaks = [TreeTweak::leaf(7); 2];
+
+        // `[x]` and `[x, 0]` would otherwise compress to the same zero-padded state
+        let x = BabyBear::new(5);
+        let parts: [&[BabyBear]; 2] = [&[x], &[x, BabyBear::ZERO]];
+        let mut out = [[BabyBear::ZERO; 4]; 2];
+        assert_eq!(
+            hash_leaves::<4, 4, 16, _>(&instance, &parameter, &tweaks, &parts, &mut out),
+            Err(Poseidon2Error::LeafLengthMismatch { expected: 1, found: 2 })
+        );
+        assert_eq!(out, [[BabyBear::ZERO; 4]; 2]);
+    }
+
+    #[test]
+    fn test_hash_leaves_rejects_mismatched_lengths() {
+        let instance = poseidon2_instance_short();
+        let parameter = [BabyBear::new(3); 4];
+        let (tweaks, parts) = leaves();
+        let parts: Vec<&[BabyBear]> = parts.iter().map(Vec::as_slice).collect();
+
+        let mut out = vec![[BabyBear::ZERO; 4]; 99];
+        assert_eq!(
+            hash_leaves::<4, 4, 16, _>(&instance, &parameter, &tweaks, &parts, &mut out),
+            Err(Poseidon2Error::BatchLengthMismatch { leaves: 100, tweaks: 100, outputs: 99 })
+        );
+
+        let mut out = vec![[BabyBear::ZERO; 4]; 100];
+        assert_eq!(
+            hash_leaves::<4, 4, 16, _>(&instance, &parameter, &tweaks[1..], &parts, &mut out),
+            Err(Poseidon2Error::BatchLengthMismatch { leaves: 100, tweaks: 99, outputs: 100 })
+        );
+
+        // A part of 10 elements does not fit next to the parameter and tweak
+        let long = [BabyBear::ONE; 10];
+        let mut out = [[BabyBear::ZERO; 4]];
+        assert_eq!(
+            hash_leaves::<4, 4, 16, _>(&instance, &parameter, &tweaks[..1], &[&long], &mut out),
+            Err(Poseidon2Error::InputTooLong { len: 17, width: 16 })
+        );
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_par_hash_leaves_matches_hash_leaves() {
+        let instance = poseidon2_instance();
+        let parameter = [BabyBear::new(3); 4];
+        let tweaks: Vec<TreeTweak> = (0..3000).map(TreeTweak::leaf).collect();
+        let parts: Vec<Vec<BabyBear>> = (0..3000).map(|i| vec![BabyBear::new(i); 8]).collect();
+        let parts: Vec<&[BabyBear]> = parts.iter().map(Vec::as_slice).collect();
+
+        let mut expected = vec![[BabyBear::ZERO; 4]; parts.len()];
+        hash_leaves::<4, 4, 24, _>(&instance, &parameter, &tweaks, &parts, &mut expected).unwrap();
+        let mut out = vec![[BabyBear::ZERO; 4]; parts.len()];
+        par_hash_leaves::<4, 4, 24, _>(&instance, &parameter, &tweaks, &parts, &mut out).unwrap();
+        assert_eq!(out, expected);
+
+        assert!(par_hash_leaves::<4, 4, 24, _>(
+            &instance,
+            &parameter,
+            &tweaks[1..],
+            &parts,
+            &mut out
+        )
+        .is_err());
+    }
 }